@@ -1,9 +1,117 @@
 #version 450
 
-layout(location = 0) in vec3 fragColor;
+layout(location = 0) in vec3 fragPosition;
+layout(location = 1) in vec3 fragNormal;
+layout(location = 2) flat in uint fragIndex;
+layout(location = 3) in vec4 fragTangent;
 
 layout(location = 0) out vec4 outColor;
 
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 viewProj;
+    vec4 eye;
+    // Read by tonemap.frag.glsl
+    vec4 tonemap;
+    mat4 lightViewProj;
+    // x is the depth bias
+    vec4 shadow;
+} camera;
+
+struct Material {
+    vec4 colour;
+    vec4 emissive;
+    float metallic;
+    float roughness;
+};
+
+layout(set = 1, binding = 1) readonly buffer Materials {
+    Material materials[];
+} materials;
+
+struct Light {
+    vec4 position;
+    vec4 radiance;
+};
+
+layout(set = 1, binding = 2) readonly buffer Lights {
+    Light lights[];
+} lights;
+
+layout(set = 2, binding = 0) uniform sampler2DShadow shadowMap;
+
+const float PI = 3.14159265359;
+// Must match `sun_direction` in shadow.rs
+const vec3 LIGHT_DIRECTION = normalize(vec3(1.0));
+const vec3 LIGHT_RADIANCE = vec3(PI);
+const float AMBIENT = 0.5;
+
+// Fraction of the sun reaching position, averaged over a 3x3 kernel to
+// soften the shadow's edge
+float sunlight(vec3 position) {
+    vec4 light = camera.lightViewProj * vec4(position, 1.0);
+    vec3 coords = light.xyz / light.w;
+    if (coords.z > 1.0) {
+        return 1.0;
+    }
+    vec2 uv = coords.xy * 0.5 + 0.5;
+    float depth = coords.z - camera.shadow.x;
+    vec2 texel = 1.0 / vec2(textureSize(shadowMap, 0));
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(shadowMap, vec3(uv + vec2(x, y) * texel, depth));
+        }
+    }
+    return lit / 9.0;
+}
+
+// Cook-Torrance specular plus Lambert diffuse for light arriving along l
+vec3 brdf(vec3 n, vec3 v, vec3 l, vec3 albedo, float metallic, float roughness) {
+    vec3 h = normalize(v + l);
+
+    float ndotl = max(dot(n, l), 0.0);
+    float ndotv = max(dot(n, v), 0.0001);
+    float ndoth = max(dot(n, h), 0.0);
+    float vdoth = max(dot(v, h), 0.0);
+
+    float alpha = roughness * roughness;
+    float a2 = alpha * alpha;
+    float denom = ndoth * ndoth * (a2 - 1.0) + 1.0;
+    float d = a2 / (PI * denom * denom);
+
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    float g = (ndotv / (ndotv * (1.0 - k) + k)) * (ndotl / (ndotl * (1.0 - k) + k));
+
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    vec3 f = f0 + (1.0 - f0) * pow(1.0 - vdoth, 5.0);
+
+    vec3 specular = d * g * f / (4.0 * ndotv * ndotl + 0.0001);
+    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * albedo / PI;
+    return (diffuse + specular) * ndotl;
+}
+
 void main() {
-    outColor = vec4(fragColor, 1.0);
+    Material material = materials.materials[fragIndex];
+    vec3 albedo = material.colour.rgb;
+    float metallic = clamp(material.metallic, 0.0, 1.0);
+    float roughness = clamp(material.roughness, 0.04, 1.0);
+
+    vec3 n = normalize(fragNormal);
+    vec3 v = normalize(camera.eye.xyz - fragPosition);
+
+    vec3 colour = AMBIENT * albedo
+        + brdf(n, v, LIGHT_DIRECTION, albedo, metallic, roughness) * LIGHT_RADIANCE
+            * sunlight(fragPosition)
+        + material.emissive.rgb;
+    for (int i = 0; i < lights.lights.length(); i++) {
+        Light light = lights.lights[i];
+        vec3 offset = light.position.xyz - fragPosition;
+        // The + 1 keeps lights from blowing out surfaces they touch
+        float attenuation = 1.0 / (dot(offset, offset) + 1.0);
+        colour += brdf(n, v, normalize(offset), albedo, metallic, roughness)
+            * light.radiance.rgb * attenuation;
+    }
+    // Left linear for the post pass to tone map. Blended by the transparent
+    // pipeline, opaque materials have an alpha of 1
+    outColor = vec4(colour, material.colour.a);
 }
@@ -2,34 +2,52 @@
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 normal;
+layout(location = 2) in vec4 tangent;
+layout(location = 3) in uvec4 joints;
+layout(location = 4) in vec4 weights;
 
-layout(location = 0) out vec3 fragColor;
+layout(location = 0) out vec3 fragPosition;
+layout(location = 1) out vec3 fragNormal;
+layout(location = 2) flat out uint fragIndex;
+layout(location = 3) out vec4 fragTangent;
 
 layout(set = 0, binding = 0) uniform Camera {
     mat4 viewProj;
+    vec4 eye;
+    vec4 tonemap;
 } camera;
 
 struct Transform {
     mat4 transform;
 };
 
-struct Material {
-    vec4 colour;
-};
-
 layout(set = 1, binding = 0) readonly buffer Transforms {
     Transform transforms[];
 } transforms;
 
-layout(set = 1, binding = 1) readonly buffer Materials {
-    Material materials[];
-} materials;
+// Joint 0 is the identity, which unskinned vertices are bound to
+layout(set = 1, binding = 3) readonly buffer Joints {
+    mat4 joints[];
+} skeleton;
+
+mat4 skin() {
+    return weights.x * skeleton.joints[joints.x]
+        + weights.y * skeleton.joints[joints.y]
+        + weights.z * skeleton.joints[joints.z]
+        + weights.w * skeleton.joints[joints.w];
+}
 
 void main() {
-    uint index = gl_DrawID;
+    // Each draw's firstInstance is its object index, which works for both
+    // indirect and per-object draws without needing shaderDrawParameters
+    uint index = gl_InstanceIndex;
     Transform transform = transforms.transforms[index];
-    Material material = materials.materials[index];
-    
-    gl_Position = camera.viewProj * transform.transform * vec4(position, 1.0);
-    fragColor = material.colour.rgb * (0.5 + 0.5 * max(dot(normal, vec3(1.0)), 0.0));
+    transform.transform *= skin();
+
+    vec4 world = transform.transform * vec4(position, 1.0);
+    gl_Position = camera.viewProj * world;
+    fragPosition = world.xyz;
+    fragNormal = transpose(inverse(mat3(transform.transform))) * normal;
+    fragIndex = index;
+    fragTangent = vec4(mat3(transform.transform) * tangent.xyz, tangent.w);
 }
@@ -0,0 +1,38 @@
+#version 460
+
+layout(location = 0) in vec3 position;
+layout(location = 3) in uvec4 joints;
+layout(location = 4) in vec4 weights;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 viewProj;
+    vec4 eye;
+    vec4 tonemap;
+    mat4 lightViewProj;
+    vec4 shadow;
+} camera;
+
+struct Transform {
+    mat4 transform;
+};
+
+layout(set = 1, binding = 0) readonly buffer Transforms {
+    Transform transforms[];
+} transforms;
+
+// Joint 0 is the identity, which unskinned vertices are bound to
+layout(set = 1, binding = 3) readonly buffer Joints {
+    mat4 joints[];
+} skeleton;
+
+mat4 skin() {
+    return weights.x * skeleton.joints[joints.x]
+        + weights.y * skeleton.joints[joints.y]
+        + weights.z * skeleton.joints[joints.z]
+        + weights.w * skeleton.joints[joints.w];
+}
+
+void main() {
+    Transform transform = transforms.transforms[gl_InstanceIndex];
+    gl_Position = camera.lightViewProj * transform.transform * skin() * vec4(position, 1.0);
+}
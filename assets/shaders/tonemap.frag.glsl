@@ -0,0 +1,31 @@
+#version 450
+
+layout(location = 0) out vec4 outColor;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 viewProj;
+    vec4 eye;
+    // x is the exposure, y enables tone mapping, z picks Reinhard over ACES
+    vec4 tonemap;
+} camera;
+
+// The scene's resolved HDR target, one texel per pixel
+layout(set = 1, binding = 0) uniform sampler2D hdr;
+
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 x) {
+    return x / (x + 1.0);
+}
+
+void main() {
+    vec3 colour = texelFetch(hdr, ivec2(gl_FragCoord.xy), 0).rgb;
+    if (camera.tonemap.y > 0.5) {
+        vec3 exposed = colour * camera.tonemap.x;
+        colour = camera.tonemap.z > 0.5 ? reinhard(exposed) : aces(exposed);
+    }
+    // Without tone mapping anything over 1 clips when written
+    outColor = vec4(colour, 1.0);
+}
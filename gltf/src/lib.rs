@@ -1,11 +1,51 @@
 #![feature(exact_size_is_empty)]
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Deserialize_repr, Serialize_repr, Debug)]
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    /// The binary container around the JSON and buffer is broken
+    Malformed(&'static str),
+    /// An index refers to a missing accessor, buffer view or animation
+    /// sampler, or an accessor reaches past the end of the buffer
+    OutOfBounds,
+    /// An accessor's components can't be read as floats
+    NotFloat(ComponentType),
+    /// Indices and joints must be unsigned integers
+    NotIndices(ComponentType),
+    /// An animation sampler without keyframes, or too few values for them
+    Keyframes,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "Invalid glTF JSON: {e}"),
+            Self::Malformed(what) => write!(f, "Malformed GLB: {what}"),
+            Self::OutOfBounds => write!(f, "Index or accessor is out of bounds"),
+            Self::NotFloat(ty) => write!(f, "{ty:?} accessor can't be read as floats"),
+            Self::NotIndices(ty) => write!(f, "{ty:?} accessor can't be read as indices"),
+            Self::Keyframes => write!(f, "Animation sampler is missing keyframes"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+#[derive(Deserialize_repr, Serialize_repr, Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u16)]
 pub enum ComponentType {
     I8 = 5120,
@@ -47,9 +87,12 @@ pub struct Accessor {
 }
 
 impl Accessor {
-    pub fn get_data(&self, glb: &Glb) -> Vec<u8> {
-        let buffer_view = glb.gltf.buffer_views.get(self.buffer_view).unwrap();
-        let _buffer = glb.gltf.buffers.get(buffer_view.buffer).unwrap();
+    pub fn get_data(&self, glb: &Glb) -> Result<Vec<u8>, Error> {
+        let buffer_view = glb
+            .gltf
+            .buffer_views
+            .get(self.buffer_view)
+            .ok_or(Error::OutOfBounds)?;
 
         let offset = self.byte_offset + buffer_view.byte_offset;
 
@@ -60,16 +103,174 @@ impl Accessor {
             "VEC4" | "MAT2" => 4,
             "MAT3" => 9,
             "MAT4" => 16,
-            _ => panic!("Invalid element type"),
+            _ => return Err(Error::Malformed("invalid accessor element type")),
         };
         let size = self.component_type.size_of() * element_size * self.count;
 
-        glb.buffer[offset..(offset + size)].to_vec()
+        glb.buffer
+            .get(offset..offset + size)
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// Reads float components, or normalized integer ones scaled as the
+    /// glTF spec describes.
+    pub fn get_f32s(&self, glb: &Glb) -> Result<Vec<f32>, Error> {
+        let data = self.get_data(glb)?;
+        let ty = self.component_type;
+        if ty != ComponentType::F32 && !self.normalized {
+            return Err(Error::NotFloat(ty));
+        }
+        Ok(match ty {
+            ComponentType::F32 => data
+                .chunks(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+            ComponentType::I8 => data
+                .iter()
+                .map(|x| (*x as i8 as f32 / 127.0).max(-1.0))
+                .collect(),
+            ComponentType::U8 => data.iter().map(|x| *x as f32 / 255.0).collect(),
+            ComponentType::I16 => data
+                .chunks(2)
+                .map(|bytes| i16::from_le_bytes(bytes.try_into().unwrap()))
+                .map(|x| (x as f32 / 32767.0).max(-1.0))
+                .collect(),
+            ComponentType::U16 => data
+                .chunks(2)
+                .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+                .map(|x| x as f32 / 65535.0)
+                .collect(),
+            ComponentType::U32 => return Err(Error::NotFloat(ty)),
+        })
+    }
+
+    /// Reads unsigned integer components, such as indices or joints
+    pub fn get_u32s(&self, glb: &Glb) -> Result<Vec<u32>, Error> {
+        let data = self.get_data(glb)?;
+        Ok(match self.component_type {
+            ComponentType::U8 => data.into_iter().map(u32::from).collect(),
+            ComponentType::U16 => data
+                .chunks(2)
+                .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()) as u32)
+                .collect(),
+            ComponentType::U32 => data
+                .chunks(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+            ty => return Err(Error::NotIndices(ty)),
+        })
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Animation {}
+pub struct AnimationTarget {
+    #[serde(default)]
+    pub node: Option<usize>,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnimationChannel {
+    pub sampler: usize,
+    pub target: AnimationTarget,
+}
+
+fn default_interpolation() -> String {
+    String::from("LINEAR")
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnimationSampler {
+    pub input: usize,
+    pub output: usize,
+    #[serde(default = "default_interpolation")]
+    pub interpolation: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Property {
+    Translation([f32; 3]),
+    Rotation([f32; 4]),
+    Scale([f32; 3]),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Animation {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+    pub samplers: Vec<AnimationSampler>,
+}
+
+impl Animation {
+    pub fn duration(&self, glb: &Glb) -> Result<f32, Error> {
+        self.samplers.iter().try_fold(0.0, |duration: f32, sampler| {
+            let times = glb.accessor(sampler.input)?.get_f32s(glb)?;
+            Ok(duration.max(times.last().copied().unwrap_or_default()))
+        })
+    }
+
+    pub fn sample(&self, glb: &Glb, time: f32) -> Result<Vec<(usize, Property)>, Error> {
+        let mut properties = Vec::new();
+        for channel in &self.channels {
+            let Some(node) = channel.target.node else {
+                continue;
+            };
+            let sampler = self.samplers.get(channel.sampler).ok_or(Error::OutOfBounds)?;
+            let width = match channel.target.path.as_str() {
+                "translation" | "scale" => 3,
+                "rotation" => 4,
+                _ => continue,
+            };
+            let times = glb.accessor(sampler.input)?.get_f32s(glb)?;
+            let values = glb.accessor(sampler.output)?.get_f32s(glb)?;
+            if times.is_empty() || values.len() < times.len() * width {
+                return Err(Error::Keyframes);
+            }
+
+            let next = times.iter().position(|t| *t > time).unwrap_or(times.len());
+            let value = if next == 0 || next == times.len() || sampler.interpolation == "STEP" {
+                let index = next.saturating_sub(1).min(times.len() - 1);
+                values[index * width..(index + 1) * width].to_vec()
+            } else {
+                let (a, b) = (next - 1, next);
+                let t = (time - times[a]) / (times[b] - times[a]);
+                let from = &values[a * width..(a + 1) * width];
+                let to = &values[b * width..(b + 1) * width];
+                let sign = if width == 4
+                    && from.iter().zip(to).map(|(x, y)| x * y).sum::<f32>() < 0.0
+                {
+                    -1.0
+                } else {
+                    1.0
+                };
+                let mut value = from
+                    .iter()
+                    .zip(to)
+                    .map(|(x, y)| x * (1.0 - t) + y * sign * t)
+                    .collect::<Vec<f32>>();
+                if width == 4 {
+                    let length = value.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    value.iter_mut().for_each(|x| *x /= length);
+                }
+                value
+            };
+
+            properties.push((
+                node,
+                match width {
+                    4 => Property::Rotation([value[0], value[1], value[2], value[3]]),
+                    _ if channel.target.path == "scale" => {
+                        Property::Scale([value[0], value[1], value[2]])
+                    }
+                    _ => Property::Translation([value[0], value[1], value[2]]),
+                },
+            ));
+        }
+        Ok(properties)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Asset {
@@ -195,7 +396,7 @@ pub struct Material {
     #[serde(rename = "emissiveTexture")]
     pub emissive_texture: Option<TextureInfo>,
     #[serde(default)]
-    #[serde(rename = "emissiveFavtor")]
+    #[serde(rename = "emissiveFactor")]
     pub emissive_factor: Option<[f32; 3]>,
     #[serde(default)]
     #[serde(rename = "alphaMode")]
@@ -218,26 +419,40 @@ pub struct MeshPrimitive {
 }
 
 impl MeshPrimitive {
-    pub fn get_attribute_data(&self, glb: &Glb, attribute: &str) -> Option<Vec<u8>> {
-        glb.gltf
-            .accessors
-            .get(*self.attributes.get(attribute)?)
-            .map(|accessor| accessor.get_data(glb))
+    /// `None` if the primitive doesn't have `attribute`
+    pub fn get_attribute_data(&self, glb: &Glb, attribute: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.attributes
+            .get(attribute)
+            .map(|index| glb.accessor(*index)?.get_data(glb))
+            .transpose()
     }
 
-    pub fn get_indices_data(&self, glb: &Glb) -> Option<Vec<u32>> {
-        glb.gltf.accessors.get(self.indices?).map(|accessor| {
-            let data = accessor.get_data(glb);
-            match accessor.component_type {
-                ComponentType::U16 => bytemuck::cast_slice::<u8, u16>(&data)
-                    .iter()
-                    .copied()
-                    .map(|short| short as u32)
-                    .collect(),
-                ComponentType::U32 => bytemuck::cast_slice::<u8, u32>(&data).to_vec(),
-                _ => panic!("Invalid index component type"),
-            }
-        })
+    pub fn get_attribute_f32s(
+        &self,
+        glb: &Glb,
+        attribute: &str,
+    ) -> Result<Option<Vec<f32>>, Error> {
+        self.attributes
+            .get(attribute)
+            .map(|index| glb.accessor(*index)?.get_f32s(glb))
+            .transpose()
+    }
+
+    pub fn get_attribute_u32s(
+        &self,
+        glb: &Glb,
+        attribute: &str,
+    ) -> Result<Option<Vec<u32>>, Error> {
+        self.attributes
+            .get(attribute)
+            .map(|index| glb.accessor(*index)?.get_u32s(glb))
+            .transpose()
+    }
+
+    pub fn get_indices_data(&self, glb: &Glb) -> Result<Option<Vec<u32>>, Error> {
+        self.indices
+            .map(|index| glb.accessor(index)?.get_u32s(glb))
+            .transpose()
     }
 }
 
@@ -324,7 +539,32 @@ pub struct Scene {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Skin {}
+pub struct Skin {
+    #[serde(default)]
+    #[serde(rename = "inverseBindMatrices")]
+    pub inverse_bind_matrices: Option<usize>,
+    #[serde(default)]
+    pub skeleton: Option<usize>,
+    pub joints: Vec<usize>,
+}
+
+impl Skin {
+    pub fn get_inverse_bind_matrices(&self, glb: &Glb) -> Result<Vec<[f32; 16]>, Error> {
+        Ok(match self.inverse_bind_matrices {
+            Some(accessor) => glb
+                .accessor(accessor)?
+                .get_f32s(glb)?
+                .chunks_exact(16)
+                .map(|matrix| matrix.try_into().unwrap())
+                .collect(),
+            None => {
+                let mut identity = [0.0; 16];
+                [0, 5, 10, 15].into_iter().for_each(|i| identity[i] = 1.0);
+                vec![identity; self.joints.len()]
+            }
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Texture {
@@ -375,8 +615,8 @@ pub struct Gltf {
 }
 
 impl Gltf {
-    fn load(bytes: &[u8]) -> serde_json::Result<Self> {
-        serde_json::from_slice(bytes)
+    fn load(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(bytes)?)
     }
 }
 
@@ -386,49 +626,193 @@ pub struct Glb {
 }
 
 impl Glb {
-    fn get_u32(bytes: &mut impl Iterator<Item = u8>) -> u32 {
-        *bytemuck::from_bytes::<u32>(&bytes.take(4).collect::<Vec<u8>>())
+    pub fn accessor(&self, index: usize) -> Result<&Accessor, Error> {
+        self.gltf.accessors.get(index).ok_or(Error::OutOfBounds)
     }
 
-    fn get(bytes: &mut impl Iterator<Item = u8>, length: usize) -> Vec<u8> {
-        bytes.take(length).collect()
+    fn get_u32(bytes: &mut impl Iterator<Item = u8>) -> Result<u32, Error> {
+        let word = bytes.take(4).collect::<Vec<u8>>();
+        let word = word.try_into().map_err(|_| Error::Malformed("truncated"))?;
+        Ok(u32::from_le_bytes(word))
     }
 
-    pub fn load(bytes: &[u8]) -> serde_json::Result<Self> {
+    fn get(bytes: &mut impl Iterator<Item = u8>, length: usize) -> Result<Vec<u8>, Error> {
+        let data = bytes.take(length).collect::<Vec<u8>>();
+        if data.len() != length {
+            return Err(Error::Malformed("truncated"));
+        }
+        Ok(data)
+    }
+
+    pub fn load(bytes: &[u8]) -> Result<Self, Error> {
         let mut bytes = bytes.iter().copied();
 
-        let magic = Self::get_u32(&mut bytes);
+        let magic = Self::get_u32(&mut bytes)?;
         if magic != 0x46546C67 {
-            panic!("Malformed GLB");
+            return Err(Error::Malformed("wrong magic number"));
         }
 
-        let version = Self::get_u32(&mut bytes);
+        let version = Self::get_u32(&mut bytes)?;
         if version != 2 {
-            panic!("Aetheria only supports glTF 2.0");
+            return Err(Error::Malformed("only glTF 2.0 is supported"));
         }
 
-        let _length = Self::get_u32(&mut bytes);
+        let _length = Self::get_u32(&mut bytes)?;
 
-        let gltf_length = Self::get_u32(&mut bytes);
-        let gltf_type = Self::get_u32(&mut bytes);
+        let gltf_length = Self::get_u32(&mut bytes)?;
+        let gltf_type = Self::get_u32(&mut bytes)?;
         if gltf_type != 0x4E4F534A {
-            panic!("Malformed GLB");
+            return Err(Error::Malformed("first chunk isn't JSON"));
         }
 
-        let gltf_bytes: Vec<u8> = Self::get(&mut bytes, gltf_length as usize);
+        let gltf_bytes: Vec<u8> = Self::get(&mut bytes, gltf_length as usize)?;
         let gltf = Gltf::load(&gltf_bytes)?;
 
         let mut buffer = Vec::new();
         if !bytes.is_empty() {
-            let buffer_length = Self::get_u32(&mut bytes);
-            let buffer_type = Self::get_u32(&mut bytes);
+            let buffer_length = Self::get_u32(&mut bytes)?;
+            let buffer_type = Self::get_u32(&mut bytes)?;
             if buffer_type != 0x004E4942 {
-                panic!("Malformed GLB");
+                return Err(Error::Malformed("second chunk isn't binary"));
             }
 
-            buffer = bytes.take(buffer_length as usize).collect();
+            buffer = Self::get(&mut bytes, buffer_length as usize)?;
         }
 
         Ok(Self { gltf, buffer })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rigged() -> Glb {
+        let times: [f32; 2] = [0.0, 1.0];
+        let translations: [f32; 6] = [0.0, 0.0, 0.0, 2.0, 4.0, 0.0];
+        let mut buffer = bytemuck::cast_slice::<f32, u8>(&times).to_vec();
+        buffer.extend_from_slice(bytemuck::cast_slice::<f32, u8>(&translations));
+
+        let gltf = r#"{
+            "asset": { "version": "2.0" },
+            "buffers": [{ "byteLength": 32 }],
+            "bufferViews": [
+                { "buffer": 0, "byteOffset": 0, "byteLength": 8 },
+                { "buffer": 0, "byteOffset": 8, "byteLength": 24 }
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": 2, "type": "SCALAR" },
+                { "bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC3" }
+            ],
+            "nodes": [{ "children": [1] }, {}],
+            "skins": [{ "joints": [0, 1] }],
+            "animations": [{
+                "channels": [{ "sampler": 0, "target": { "node": 1, "path": "translation" } }],
+                "samplers": [{ "input": 0, "output": 1 }]
+            }]
+        }"#;
+
+        Glb {
+            gltf: Gltf::load(gltf.as_bytes()).unwrap(),
+            buffer,
+        }
+    }
+
+    #[test]
+    pub fn test_sample_animation() {
+        let glb = rigged();
+        let animation = &glb.gltf.animations[0];
+        assert_eq!(animation.duration(&glb).unwrap(), 1.0);
+        assert_eq!(
+            animation.sample(&glb, 0.0).unwrap(),
+            [(1, Property::Translation([0.0, 0.0, 0.0]))]
+        );
+        assert_eq!(
+            animation.sample(&glb, 0.5).unwrap(),
+            [(1, Property::Translation([1.0, 2.0, 0.0]))]
+        );
+        assert_eq!(
+            animation.sample(&glb, 2.0).unwrap(),
+            [(1, Property::Translation([2.0, 4.0, 0.0]))]
+        );
+        assert_eq!(glb.gltf.skins[0].get_inverse_bind_matrices(&glb).unwrap().len(), 2);
+    }
+
+    #[test]
+    pub fn test_malformed_glb() {
+        assert!(matches!(Glb::load(&[]), Err(Error::Malformed(_))));
+        assert!(matches!(Glb::load(b"glTF\x01"), Err(Error::Malformed(_))));
+
+        let json = b"{ not json";
+        let mut glb = Vec::new();
+        for word in [0x46546C67, 2, 0, json.len() as u32, 0x4E4F534A] {
+            glb.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        glb.extend_from_slice(json);
+        assert!(matches!(Glb::load(&glb), Err(Error::Json(_))));
+
+        let mut rigged = rigged();
+        rigged.buffer.truncate(16);
+        assert!(matches!(
+            rigged.gltf.animations[0].sample(&rigged, 0.0),
+            Err(Error::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    pub fn test_out_of_range_indices() {
+        let mut glb = rigged();
+        glb.gltf.animations[0].channels[0].sampler = 1;
+        assert!(matches!(
+            glb.gltf.animations[0].sample(&glb, 0.0),
+            Err(Error::OutOfBounds)
+        ));
+
+        let mut glb = rigged();
+        glb.gltf.animations[0].samplers[0].output = 2;
+        assert!(matches!(
+            glb.gltf.animations[0].sample(&glb, 0.0),
+            Err(Error::OutOfBounds)
+        ));
+        glb.gltf.animations[0].samplers[0].input = 2;
+        assert!(matches!(
+            glb.gltf.animations[0].duration(&glb),
+            Err(Error::OutOfBounds)
+        ));
+
+        glb.gltf.skins[0].inverse_bind_matrices = Some(2);
+        assert!(matches!(
+            glb.gltf.skins[0].get_inverse_bind_matrices(&glb),
+            Err(Error::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    pub fn test_bad_accessors() {
+        let mut glb = rigged();
+        glb.gltf.accessors[0].count = 0;
+        assert!(matches!(
+            glb.gltf.animations[0].sample(&glb, 0.0),
+            Err(Error::Keyframes)
+        ));
+
+        // Normalized weights are read as floats, other integers aren't
+        glb.buffer = vec![0, 255, 0, 0];
+        let mut weights = Accessor {
+            buffer_view: 0,
+            byte_offset: 0,
+            component_type: ComponentType::U8,
+            normalized: true,
+            count: 2,
+            element_type: String::from("SCALAR"),
+            max: None,
+            min: None,
+        };
+        assert_eq!(weights.get_f32s(&glb).unwrap(), [0.0, 1.0]);
+        weights.normalized = false;
+        assert!(matches!(
+            weights.get_f32s(&glb),
+            Err(Error::NotFloat(ComponentType::U8))
+        ));
+    }
+}
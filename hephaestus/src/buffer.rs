@@ -4,13 +4,13 @@ use std::{ffi::c_void, rc::Rc};
 use ash::{
     prelude::VkResult,
     vk::{
-        self, BufferCreateInfo, BufferUsageFlags, MemoryAllocateInfo, MemoryMapFlags,
-        MemoryPropertyFlags, MemoryRequirements, SharingMode,
+        self, AccessFlags, BufferCreateInfo, BufferUsageFlags, MemoryAllocateInfo,
+        MemoryMapFlags, MemoryPropertyFlags, MemoryRequirements, PipelineStageFlags, SharingMode,
     },
 };
 
 use crate::{
-    command::Region,
+    command::{BufferBarrier, Region},
     task::{Fence, SubmitInfo, Task},
     Context, Device,
 };
@@ -102,6 +102,17 @@ impl Dynamic {
 
         Ok(())
     }
+
+    pub fn read(&self) -> VkResult<Vec<u8>> {
+        let memory: *mut c_void = unsafe {
+            self.device.map_memory(self.memory, 0, self.size as u64, MemoryMapFlags::default())?
+        };
+        let memory: *const u8 = memory.cast();
+        let data = unsafe { slice::from_raw_parts(memory, self.size).to_vec() };
+        unsafe { self.device.unmap_memory(self.memory) };
+
+        Ok(data)
+    }
 }
 
 impl Drop for Dynamic {
@@ -130,6 +141,7 @@ pub struct Static {
     pub handle: vk::Buffer,
     pub memory: vk::DeviceMemory,
     pub size: usize,
+    usage: BufferUsageFlags,
 }
 
 impl Static {
@@ -159,6 +171,7 @@ impl Static {
             handle,
             memory,
             size,
+            usage,
         };
 
         let cmd = ctx
@@ -190,6 +203,56 @@ impl Static {
 
         Ok(Rc::new(buffer))
     }
+
+    /// Starts copying the buffer back to the host. Only buffers made with
+    /// `TRANSFER_SRC` usage can be read back.
+    pub fn read(self: &Rc<Self>, ctx: &Context) -> VkResult<Readback> {
+        assert!(
+            self.usage.contains(BufferUsageFlags::TRANSFER_SRC),
+            "Reading back a buffer without TRANSFER_SRC usage"
+        );
+        let staging = Dynamic::new(ctx, self.size, BufferUsageFlags::TRANSFER_DST)?;
+
+        let cmd = ctx
+            .command_pool
+            .alloc()?
+            .begin()?
+            .copy_buffer(
+                self,
+                &staging,
+                Region {
+                    from_offset: 0,
+                    to_offset: 0,
+                    size: self.size,
+                },
+            )
+            .buffer_barrier(
+                &staging,
+                BufferBarrier {
+                    before: (AccessFlags::TRANSFER_WRITE, PipelineStageFlags::TRANSFER),
+                    after: (AccessFlags::HOST_READ, PipelineStageFlags::HOST),
+                },
+            )
+            .end()?;
+
+        let mut task = Task::new();
+        let fence = Fence::new(&ctx.device)?;
+        task.submit(SubmitInfo {
+            cmd: &cmd,
+            fence: fence.clone(),
+            device: &ctx.device,
+            queue: &ctx.device.queues.graphics,
+            wait: &[],
+            signal: &[],
+        })?;
+
+        Ok(Readback {
+            _task: task,
+            _source: self.clone(),
+            fence,
+            staging,
+        })
+    }
 }
 
 impl Drop for Static {
@@ -212,3 +275,56 @@ impl Buffer for Static {
         self.size
     }
 }
+
+/// A copy of a `Static` buffer back to the host, which may still be running
+pub struct Readback {
+    _task: Task,
+    _source: Rc<Static>,
+    fence: Rc<Fence>,
+    staging: Rc<Dynamic>,
+}
+
+impl Readback {
+    /// The buffer's contents if the copy has finished, without blocking
+    pub fn poll(&self) -> VkResult<Option<Vec<u8>>> {
+        if !self.fence.signalled()? {
+            return Ok(None);
+        }
+        self.staging.read().map(Some)
+    }
+
+    pub fn wait(self) -> VkResult<Vec<u8>> {
+        self.fence.wait()?;
+        self.staging.read()
+    }
+}
+
+impl Drop for Readback {
+    fn drop(&mut self) {
+        // The copy still uses both buffers until it finishes
+        let _ = self.fence.wait();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use winit::{
+        event_loop::EventLoopBuilder, platform::x11::EventLoopBuilderExtX11,
+        window::WindowBuilder,
+    };
+
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a display and a Vulkan device"]
+    pub fn test_read_round_trip() {
+        let event_loop = EventLoopBuilder::new().with_any_thread(true).build().unwrap();
+        let window = WindowBuilder::new().with_visible(false).build(&event_loop).unwrap();
+        let ctx = Context::builder("test").build(&window, (64, 64)).unwrap();
+
+        let data = (0..=255_u8).cycle().take(1000).collect::<Vec<_>>();
+        let usage = BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_SRC;
+        let buffer = Static::new(&ctx, &data, usage).unwrap();
+        assert_eq!(buffer.read(&ctx).unwrap().wait().unwrap(), data);
+    }
+}
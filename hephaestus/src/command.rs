@@ -3,12 +3,12 @@ use std::{any::Any, rc::Rc};
 use ash::{
     prelude::VkResult,
     vk::{
-        self, AccessFlags, BufferCopy, BufferImageCopy, ClearValue, CommandBufferAllocateInfo,
-        CommandBufferBeginInfo, CommandBufferLevel, CommandPoolCreateInfo, DependencyFlags,
-        Extent2D, Extent3D, ImageAspectFlags, ImageLayout, ImageMemoryBarrier,
-        ImageSubresourceLayers, ImageSubresourceRange, IndexType, Offset2D, Offset3D,
-        PipelineBindPoint, PipelineLayout, PipelineStageFlags, Rect2D, RenderPassBeginInfo,
-        SubpassContents, Viewport,
+        self, AccessFlags, BufferCopy, BufferImageCopy, BufferMemoryBarrier, ClearValue,
+        CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel,
+        CommandPoolCreateInfo, DependencyFlags, Extent2D, Extent3D, ImageAspectFlags,
+        ImageLayout, ImageMemoryBarrier, ImageSubresourceLayers, ImageSubresourceRange,
+        IndexType, Offset2D, Offset3D, PipelineBindPoint, PipelineLayout, PipelineStageFlags,
+        Rect2D, RenderPassBeginInfo, SubpassContents, Viewport,
     },
 };
 
@@ -16,6 +16,7 @@ use crate::{
     buffer, descriptor,
     image::Image,
     pipeline::{Framebuffer, Graphics, RenderPass},
+    query::TimestampPool,
     Device, Queue,
 };
 
@@ -38,6 +39,11 @@ pub struct TransitionLayout {
     pub after: (AccessFlags, PipelineStageFlags),
 }
 
+pub struct BufferBarrier {
+    pub before: (AccessFlags, PipelineStageFlags),
+    pub after: (AccessFlags, PipelineStageFlags),
+}
+
 pub struct Buffer {
     device: Rc<Device>,
     pool: Rc<Pool>,
@@ -358,6 +364,57 @@ impl<'a> Recorder<'a> {
         self
     }
 
+    pub fn buffer_barrier<A: buffer::Buffer>(self, buffer: &A, info: BufferBarrier) -> Self {
+        let barrier = BufferMemoryBarrier::builder()
+            .buffer(buffer.buffer())
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .src_access_mask(info.before.0)
+            .dst_access_mask(info.after.0)
+            .build();
+
+        unsafe {
+            self.buffer.device.cmd_pipeline_barrier(
+                self.buffer.handle,
+                info.before.1,
+                info.after.1,
+                DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            )
+        }
+
+        self
+    }
+
+    pub fn reset_timestamps(mut self, pool: &Rc<TimestampPool>) -> Self {
+        unsafe {
+            self.buffer
+                .device
+                .cmd_reset_query_pool(self.buffer.handle, pool.handle, 0, pool.count)
+        }
+        self.buffer.resources.push(pool.clone());
+        self
+    }
+
+    pub fn write_timestamp(
+        mut self,
+        pool: &Rc<TimestampPool>,
+        stage: PipelineStageFlags,
+        index: u32,
+    ) -> Self {
+        unsafe {
+            self.buffer
+                .device
+                .cmd_write_timestamp(self.buffer.handle, stage, pool.handle, index)
+        }
+        self.buffer.resources.push(pool.clone());
+        self
+    }
+
     pub fn next_subpass(self) -> Self {
         unsafe {
             self.buffer
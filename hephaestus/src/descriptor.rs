@@ -1,4 +1,4 @@
-use std::{any::Any, rc::Rc};
+use std::{any::Any, fmt, rc::Rc};
 
 use ash::{
     prelude::VkResult,
@@ -21,6 +21,80 @@ pub struct Set {
     layout: Rc<Layout>,
     pub handle: vk::DescriptorSet,
     resources: Vec<Rc<dyn Any>>,
+    written: Vec<bool>,
+    errors: Vec<BindingError>,
+}
+
+/// A write that doesn't match the set's layout
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindingError {
+    OutOfRange { binding: usize, bindings: usize },
+    Mismatch { binding: usize, expected: DescriptorType },
+    Unwritten(usize),
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange { binding, bindings } => {
+                write!(f, "binding {binding} out of range, layout has {bindings} bindings")
+            }
+            Self::Mismatch { binding, expected } => {
+                write!(f, "binding {binding} expects {expected:?}")
+            }
+            Self::Unwritten(binding) => write!(f, "binding {binding} was never written"),
+        }
+    }
+}
+
+/// Every problem found with a set when it was finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error(pub Vec<BindingError>);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid descriptor set: ")?;
+        self.0.iter().enumerate().try_for_each(|(i, e)| match i {
+            0 => write!(f, "{e}"),
+            _ => write!(f, "; {e}"),
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn is_buffer(ty: DescriptorType) -> bool {
+    matches!(
+        ty,
+        DescriptorType::UNIFORM_BUFFER
+            | DescriptorType::STORAGE_BUFFER
+            | DescriptorType::UNIFORM_BUFFER_DYNAMIC
+            | DescriptorType::STORAGE_BUFFER_DYNAMIC
+    )
+}
+
+/// Checks a write to `binding` against a layout's `bindings`, `buffer` being
+/// whether a buffer or an image is written
+fn check_write(
+    bindings: &[DescriptorType],
+    binding: usize,
+    buffer: bool,
+) -> Result<(), BindingError> {
+    let Some(ty) = bindings.get(binding) else {
+        return Err(BindingError::OutOfRange { binding, bindings: bindings.len() });
+    };
+    if is_buffer(*ty) != buffer {
+        return Err(BindingError::Mismatch { binding, expected: *ty });
+    }
+    Ok(())
+}
+
+fn unwritten(written: &[bool]) -> impl Iterator<Item = BindingError> + '_ {
+    written
+        .iter()
+        .enumerate()
+        .filter(|(_, written)| !**written)
+        .map(|(binding, _)| BindingError::Unwritten(binding))
 }
 
 impl Layout {
@@ -77,6 +151,8 @@ impl Layout {
             handle,
             layout: self.clone(),
             resources: Vec::new(),
+            written: vec![false; self.bindings.len()],
+            errors: Vec::new(),
         })
     }
 }
@@ -89,7 +165,21 @@ impl Drop for Layout {
 }
 
 impl Set {
+    fn check(&mut self, binding: usize, buffer: bool) -> bool {
+        if let Err(e) = check_write(&self.layout.bindings, binding, buffer) {
+            self.errors.push(e);
+            return false;
+        }
+
+        self.written[binding] = true;
+        true
+    }
+
     pub fn write_buffer<T: buffer::Buffer + 'static>(mut self, binding: usize, buffer: &Rc<T>) -> Self {
+        if !self.check(binding, true) {
+            return self;
+        }
+
         let buffer_info = DescriptorBufferInfo {
             buffer: buffer.buffer(),
             offset: 0,
@@ -114,6 +204,10 @@ impl Set {
     }
 
     pub fn write_image(mut self, binding: usize, view: &Rc<ImageView>, sampler: &Rc<Sampler>, layout: ImageLayout) -> Self {
+        if !self.check(binding, false) {
+            return self;
+        }
+
         let image_info = DescriptorImageInfo {
             image_layout: layout,
             image_view: view.handle,
@@ -139,8 +233,18 @@ impl Set {
         self
     }
 
-    pub fn finish(self) -> Rc<Self> {
-        Rc::new(self)
+    pub fn finish(self) -> Result<Rc<Self>, Error> {
+        let errors = self
+            .errors
+            .iter()
+            .cloned()
+            .chain(unwritten(&self.written))
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(Error(errors));
+        }
+
+        Ok(Rc::new(self))
     }
 }
 
@@ -154,3 +258,51 @@ impl Drop for Set {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_error_display() {
+        let error = Error(vec![
+            BindingError::Mismatch { binding: 0, expected: DescriptorType::UNIFORM_BUFFER },
+            BindingError::Unwritten(1),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "invalid descriptor set: binding 0 expects UNIFORM_BUFFER; binding 1 was never written"
+        );
+    }
+
+    #[test]
+    pub fn test_check_write() {
+        let bindings = [DescriptorType::UNIFORM_BUFFER, DescriptorType::COMBINED_IMAGE_SAMPLER];
+        assert_eq!(check_write(&bindings, 0, true), Ok(()));
+        assert_eq!(check_write(&bindings, 1, false), Ok(()));
+        assert_eq!(
+            check_write(&bindings, 2, true),
+            Err(BindingError::OutOfRange { binding: 2, bindings: 2 })
+        );
+        assert_eq!(
+            check_write(&bindings, 0, false),
+            Err(BindingError::Mismatch { binding: 0, expected: DescriptorType::UNIFORM_BUFFER })
+        );
+        assert_eq!(
+            check_write(&bindings, 1, true),
+            Err(BindingError::Mismatch {
+                binding: 1,
+                expected: DescriptorType::COMBINED_IMAGE_SAMPLER
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_unwritten() {
+        assert_eq!(
+            unwritten(&[true, false, true, false]).collect::<Vec<_>>(),
+            [BindingError::Unwritten(1), BindingError::Unwritten(3)]
+        );
+        assert_eq!(unwritten(&[true, true]).count(), 0);
+    }
+}
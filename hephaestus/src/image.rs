@@ -168,6 +168,33 @@ impl Sampler {
             handle,
         }))
     }
+
+    /// Compares against a depth texture with normalised coordinates, giving
+    /// the filtered fraction of samples that pass. Lookups outside the
+    /// texture always pass.
+    pub fn shadow(device: &Rc<Device>) -> VkResult<Rc<Self>> {
+        let create_info = SamplerCreateInfo::builder()
+            .mag_filter(Filter::LINEAR)
+            .min_filter(Filter::LINEAR)
+            .address_mode_u(SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_v(SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_w(SamplerAddressMode::CLAMP_TO_BORDER)
+            .anisotropy_enable(false)
+            .border_color(BorderColor::FLOAT_OPAQUE_WHITE)
+            .unnormalized_coordinates(false)
+            .compare_enable(true)
+            .compare_op(CompareOp::LESS_OR_EQUAL)
+            .mipmap_mode(SamplerMipmapMode::NEAREST)
+            .mip_lod_bias(0.0)
+            .min_lod(0.0)
+            .max_lod(0.0);
+
+        let handle = unsafe { device.create_sampler(&create_info, None)? };
+        Ok(Rc::new(Self {
+            device: device.clone(),
+            handle,
+        }))
+    }
 }
 
 impl Drop for Sampler {
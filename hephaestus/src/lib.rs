@@ -3,6 +3,7 @@ pub mod command;
 pub mod descriptor;
 pub mod image;
 pub mod pipeline;
+pub mod query;
 pub mod task;
 pub mod vertex;
 
@@ -17,19 +18,28 @@ pub use ash::prelude::VkResult;
 pub use ash::vk::{
     AccessFlags, AttachmentLoadOp, AttachmentStoreOp, BufferUsageFlags, ClearColorValue,
     ClearValue, DescriptorType, Extent2D, Extent3D, Format, ImageAspectFlags, ImageUsageFlags,
-    MemoryPropertyFlags, Offset2D, Offset3D, PipelineStageFlags, SampleCountFlags,
+    MemoryPropertyFlags, Offset2D, Offset3D, PipelineStageFlags, PresentModeKHR,
+    SampleCountFlags,
 };
+
 use ash::{
     vk::{
-        self, ApplicationInfo, ColorSpaceKHR, CompositeAlphaFlagsKHR, DeviceCreateInfo, DeviceQueueCreateInfo, Image, InstanceCreateInfo, PhysicalDeviceFeatures, PhysicalDeviceProperties, PhysicalDeviceVulkan11Features, PresentModeKHR, QueueFamilyProperties, QueueFlags, SharingMode, SurfaceCapabilitiesKHR, SurfaceFormatKHR, SwapchainCreateInfoKHR, SwapchainKHR
+        self, ApplicationInfo, ColorSpaceKHR, CompositeAlphaFlagsKHR, DeviceCreateInfo, DeviceQueueCreateInfo, Image, InstanceCreateInfo, PhysicalDeviceFeatures, PhysicalDeviceProperties, QueueFamilyProperties, QueueFlags, SharingMode, SurfaceCapabilitiesKHR, SurfaceFormatKHR, SwapchainCreateInfoKHR, SwapchainKHR
     },
     Entry,
 };
 
 use image::ImageView;
-use log::{error, warn};
+use log::{debug, error, info, warn};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 
+/// Returned by acquiring or presenting once the window's surface is gone, see
+/// `Context::recreate_surface`
+pub const SURFACE_LOST: vk::Result = vk::Result::ERROR_SURFACE_LOST_KHR;
+/// Returned once the surface has changed under the swapchain, such as by a
+/// resize landing part way through recreating it
+pub const OUT_OF_DATE: vk::Result = vk::Result::ERROR_OUT_OF_DATE_KHR;
+
 pub struct InstanceExtensions {
     pub surface: ash::extensions::khr::Surface,
 }
@@ -68,6 +78,74 @@ impl PhysicalDevice {
             & self.properties.limits.framebuffer_depth_sample_counts;
         SampleCountFlags::from_raw(1 << (31 - samples.as_raw().leading_zeros()))
     }
+
+    pub fn supports_timestamps(&self) -> bool {
+        self.properties.limits.timestamp_compute_and_graphics == vk::TRUE
+    }
+
+    pub fn info(&self) -> DeviceInfo {
+        let properties = &self.properties;
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+        let version = properties.api_version;
+        DeviceInfo {
+            name: name.to_string_lossy().into_owned(),
+            device_type: properties.device_type,
+            api_version: (
+                vk::api_version_major(version),
+                vk::api_version_minor(version),
+                vk::api_version_patch(version),
+            ),
+            driver_version: properties.driver_version,
+            vendor_id: properties.vendor_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: (u32, u32, u32),
+    pub driver_version: u32,
+    pub vendor_id: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    MultiDrawIndirect,
+    FillModeNonSolid,
+    WideLines,
+    SamplerAnisotropy,
+    DrawIndirectFirstInstance,
+    Timestamps,
+}
+
+impl Feature {
+    pub fn supported(self, physical: &PhysicalDevice) -> bool {
+        let features = &physical.features;
+        match self {
+            Self::MultiDrawIndirect => features.multi_draw_indirect == vk::TRUE,
+            Self::FillModeNonSolid => features.fill_mode_non_solid == vk::TRUE,
+            Self::WideLines => features.wide_lines == vk::TRUE,
+            Self::SamplerAnisotropy => features.sampler_anisotropy == vk::TRUE,
+            Self::DrawIndirectFirstInstance => {
+                features.draw_indirect_first_instance == vk::TRUE
+            }
+            Self::Timestamps => physical.supports_timestamps(),
+        }
+    }
+
+    fn enable(self, features: &mut PhysicalDeviceFeatures) {
+        match self {
+            Self::MultiDrawIndirect => features.multi_draw_indirect = vk::TRUE,
+            Self::FillModeNonSolid => features.fill_mode_non_solid = vk::TRUE,
+            Self::WideLines => features.wide_lines = vk::TRUE,
+            Self::SamplerAnisotropy => features.sampler_anisotropy = vk::TRUE,
+            Self::DrawIndirectFirstInstance => features.draw_indirect_first_instance = vk::TRUE,
+            // Timestamps are a limit rather than a device feature
+            Self::Timestamps => (),
+        }
+    }
 }
 
 pub struct Surface {
@@ -77,6 +155,8 @@ pub struct Surface {
     pub capabilities: SurfaceCapabilitiesKHR,
     pub formats: Vec<SurfaceFormatKHR>,
     pub present_modes: Vec<PresentModeKHR>,
+    /// Used by the swapchain if supported, otherwise FIFO
+    pub present_mode: PresentModeKHR,
 }
 
 impl Surface {
@@ -115,6 +195,7 @@ impl Surface {
                 capabilities,
                 formats,
                 present_modes,
+                present_mode: PresentModeKHR::MAILBOX,
                 extent: Extent2D {
                     width: extent.0,
                     height: extent.1,
@@ -166,8 +247,8 @@ impl Instance {
         let available = entry.enumerate_instance_extension_properties(None)?;
         let presentation_extensions =
             ash_window::enumerate_required_extensions(window.raw_display_handle())?;
-        println!(
-            "{:?}",
+        debug!(
+            "Available instance extensions: {:?}",
             available
                 .iter()
                 .map(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) })
@@ -245,6 +326,7 @@ pub struct Device {
     pub extensions: DeviceExtensions,
     pub physical: PhysicalDevice,
     pub queues: Queues,
+    pub enabled: HashSet<Feature>,
 }
 
 impl Deref for Device {
@@ -255,6 +337,19 @@ impl Deref for Device {
     }
 }
 
+/// `preferred` if the surface supports it, otherwise FIFO which every surface
+/// must support.
+pub fn pick_present_mode(
+    supported: &[PresentModeKHR],
+    preferred: PresentModeKHR,
+) -> PresentModeKHR {
+    if supported.contains(&preferred) {
+        return preferred;
+    }
+    warn!("Present mode {preferred:?} isn't supported, falling back to FIFO");
+    PresentModeKHR::FIFO
+}
+
 pub struct Swapchain {
     device: Rc<Device>,
     pub handle: SwapchainKHR,
@@ -275,12 +370,7 @@ impl Swapchain {
             })
             .unwrap_or_else(|| surface.formats.first().unwrap());
 
-        let present_mode = surface
-            .present_modes
-            .iter()
-            .copied()
-            .find(|mode| *mode == PresentModeKHR::MAILBOX)
-            .unwrap_or(PresentModeKHR::FIFO);
+        let present_mode = pick_present_mode(&surface.present_modes, surface.present_mode);
 
         let extent = if surface.capabilities.current_extent.width == u32::MAX {
             Extent2D {
@@ -371,7 +461,12 @@ impl Drop for Swapchain {
 impl Device {
     const EXTENSIONS: &'static [&'static CStr] = &[ash::extensions::khr::Swapchain::name()];
 
-    pub fn new(instance: &Instance, physical: PhysicalDevice, surface: &Surface) -> VkResult<Self> {
+    pub fn new(
+        instance: &Instance,
+        physical: PhysicalDevice,
+        surface: &Surface,
+        enabled: HashSet<Feature>,
+    ) -> VkResult<Self> {
         let priorities = &[1.0];
 
         let graphics_index = physical
@@ -418,13 +513,15 @@ impl Device {
             .map(|name| name.as_ptr() as *const c_char)
             .collect::<Vec<_>>();
 
-        let features = PhysicalDeviceFeatures::builder().multi_draw_indirect(true);
-        let mut features11 = PhysicalDeviceVulkan11Features::builder().shader_draw_parameters(true);
+        let mut features = PhysicalDeviceFeatures::default();
+        enabled
+            .iter()
+            .for_each(|feature| feature.enable(&mut features));
 
         let create_info = DeviceCreateInfo::builder()
             .enabled_extension_names(&extensions)
             .queue_create_infos(&queue_create_infos)
-            .enabled_features(&features).push_next(&mut features11);
+            .enabled_features(&features);
 
         let inner = unsafe { instance.create_device(physical.handle, &create_info, None)? };
 
@@ -441,8 +538,13 @@ impl Device {
             extensions,
             physical,
             queues,
+            enabled,
         })
     }
+
+    pub fn enabled(&self, feature: Feature) -> bool {
+        self.enabled.contains(&feature)
+    }
 }
 
 impl Drop for Device {
@@ -460,25 +562,70 @@ pub struct Context {
     pub entry: Entry,
 }
 
-impl Context {
-    pub fn new<T: HasRawWindowHandle + HasRawDisplayHandle>(
-        name: &str,
+pub struct ContextBuilder<'a> {
+    name: &'a str,
+    required: HashSet<Feature>,
+    optional: HashSet<Feature>,
+}
+
+impl<'a> ContextBuilder<'a> {
+    /// Fails to build if `feature` isn't supported by the device.
+    pub fn require(mut self, feature: Feature) -> Self {
+        self.required.insert(feature);
+        self
+    }
+
+    /// Enabled only if the device supports it, check with `Device::enabled`.
+    pub fn optional(mut self, feature: Feature) -> Self {
+        self.optional.insert(feature);
+        self
+    }
+
+    pub fn build<T: HasRawWindowHandle + HasRawDisplayHandle>(
+        self,
         window: T,
         extent: (u32, u32),
-    ) -> VkResult<Self> {
+    ) -> VkResult<Context> {
         let entry = unsafe { Entry::load() }.unwrap_or_else(|_| {
-            println!("Failed to load vulkan dll, using linked vulkan");
+            warn!("Failed to load vulkan dll, using linked vulkan");
             Entry::linked()
         });
-        let name = CString::new(name).unwrap();
+        let name = CString::new(self.name).unwrap();
         let instance = Rc::new(Instance::new(&entry, &name, &window)?);
         let physical = unsafe { instance.get_physical_device()? };
+
+        let unsupported = self
+            .required
+            .iter()
+            .filter(|feature| !feature.supported(&physical))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            error!("Unsupported features: {unsupported:?}");
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        }
+        let enabled = self
+            .optional
+            .into_iter()
+            .filter(|feature| feature.supported(&physical))
+            .chain(self.required)
+            .collect::<HashSet<_>>();
+
         let surface = Surface::new(&entry, &instance, &physical, window, extent)?;
-        let device = Rc::new(Device::new(&instance, physical, &surface)?);
+        let device = Rc::new(Device::new(&instance, physical, &surface, enabled)?);
         let swapchain = Some(Swapchain::new(&device, &surface)?);
         let command_pool = command::Pool::new(&device, &device.queues.graphics)?;
 
-        Ok(Self {
+        let info = device.physical.info();
+        let (major, minor, patch) = info.api_version;
+        info!(
+            "Using {} ({:?}), Vulkan {major}.{minor}.{patch}, driver {:#x}",
+            info.name, info.device_type, info.driver_version
+        );
+        debug!("Enabled features: {:?}", device.enabled);
+        debug!("Features: {:?}", device.physical.features);
+        debug!("Limits: {:?}", device.physical.properties.limits);
+
+        Ok(Context {
             entry,
             instance,
             surface,
@@ -487,6 +634,26 @@ impl Context {
             command_pool,
         })
     }
+}
+
+impl Context {
+    pub fn builder(name: &str) -> ContextBuilder {
+        ContextBuilder {
+            name,
+            required: HashSet::new(),
+            optional: HashSet::new(),
+        }
+    }
+
+    pub fn new<T: HasRawWindowHandle + HasRawDisplayHandle>(
+        name: &str,
+        window: T,
+        extent: (u32, u32),
+    ) -> VkResult<Self> {
+        Self::builder(name)
+            .require(Feature::MultiDrawIndirect)
+            .build(window, extent)
+    }
 
     fn refresh_surface(&mut self) -> VkResult<()> {
         unsafe {
@@ -518,10 +685,64 @@ impl Context {
         }
     }
 
+    /// Takes effect the next time the swapchain is recreated
+    pub fn set_present_mode(&mut self, present_mode: PresentModeKHR) {
+        self.surface.present_mode = present_mode;
+    }
+
+    /// Replaces a surface that was lost with `SURFACE_LOST`. The swapchain
+    /// goes with it, so follow up with `recreate_swapchain`.
+    pub fn recreate_surface<T: HasRawWindowHandle + HasRawDisplayHandle>(
+        &mut self,
+        window: T,
+        extent: (u32, u32),
+    ) -> VkResult<()> {
+        drop(self.swapchain.take());
+        let present_mode = self.surface.present_mode;
+        self.surface =
+            Surface::new(&self.entry, &self.instance, &self.device.physical, window, extent)?;
+        self.surface.present_mode = present_mode;
+        Ok(())
+    }
+
     pub fn recreate_swapchain(&mut self) -> VkResult<()> {
         self.refresh_surface()?;
         drop(self.swapchain.take());
         self.swapchain = Some(Swapchain::new(&self.device, &self.surface)?);
         Ok(())
     }
+
+    pub fn info(&self) -> DeviceInfo {
+        self.device.physical.info()
+    }
+
+    pub fn features(&self) -> &PhysicalDeviceFeatures {
+        &self.device.physical.features
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.device.physical.properties.limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_pick_present_mode() {
+        let supported = [PresentModeKHR::FIFO, PresentModeKHR::MAILBOX];
+        assert_eq!(
+            pick_present_mode(&supported, PresentModeKHR::MAILBOX),
+            PresentModeKHR::MAILBOX
+        );
+        assert_eq!(
+            pick_present_mode(&supported, PresentModeKHR::IMMEDIATE),
+            PresentModeKHR::FIFO
+        );
+        assert_eq!(
+            pick_present_mode(&[PresentModeKHR::FIFO], PresentModeKHR::FIFO_RELAXED),
+            PresentModeKHR::FIFO
+        );
+    }
 }
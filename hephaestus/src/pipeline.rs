@@ -167,6 +167,8 @@ impl Subpass {
 pub struct RenderPassBuilder {
     attachments: Vec<AttachmentDescription>,
     subpasses: Vec<Subpass>,
+    sampled_depth: bool,
+    sampled_colour: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -205,6 +207,18 @@ impl RenderPassBuilder {
         self.subpasses.push(subpass);
     }
 
+    /// Orders the depth writes of this pass around fragment shaders sampling
+    /// them in other passes, as with a shadow map
+    pub fn sampled_depth(&mut self) {
+        self.sampled_depth = true;
+    }
+
+    /// Orders the colour writes of this pass, resolves included, around
+    /// fragment shaders sampling them in other passes, as with an HDR target
+    pub fn sampled_colour(&mut self) {
+        self.sampled_colour = true;
+    }
+
     pub fn build(self, device: &Rc<Device>) -> VkResult<RenderPass> {
         let subpasses = self
             .subpasses
@@ -222,7 +236,7 @@ impl RenderPassBuilder {
             })
             .collect::<Vec<_>>();
 
-        let dependencies = (0..subpasses.len() - 1)
+        let mut dependencies = (0..subpasses.len() - 1)
             .map(|n| SubpassDependency {
                 dependency_flags: DependencyFlags::empty(),
                 src_subpass: n as u32,
@@ -234,6 +248,50 @@ impl RenderPassBuilder {
                     | AccessFlags::COLOR_ATTACHMENT_WRITE,
             })
             .collect::<Vec<_>>();
+        if self.sampled_depth {
+            let depth_tests =
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS;
+            // Wait for the last frame's reads before writing, then make the
+            // writes visible to whoever samples them next
+            dependencies.push(SubpassDependency {
+                dependency_flags: DependencyFlags::empty(),
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                dst_stage_mask: depth_tests,
+                src_access_mask: AccessFlags::SHADER_READ,
+                dst_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            });
+            dependencies.push(SubpassDependency {
+                dependency_flags: DependencyFlags::empty(),
+                src_subpass: subpasses.len() as u32 - 1,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: depth_tests,
+                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+            });
+        }
+        if self.sampled_colour {
+            dependencies.push(SubpassDependency {
+                dependency_flags: DependencyFlags::empty(),
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                src_access_mask: AccessFlags::SHADER_READ,
+                dst_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+            });
+            dependencies.push(SubpassDependency {
+                dependency_flags: DependencyFlags::empty(),
+                src_subpass: subpasses.len() as u32 - 1,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: AccessFlags::SHADER_READ,
+            });
+        }
 
         let create_info = RenderPassCreateInfo::builder()
             .attachments(&self.attachments)
@@ -282,6 +340,9 @@ pub struct GraphicsBuilder<'a> {
     vertex_info: Option<vertex::Info>,
     layouts: Vec<&'a descriptor::Layout>,
     depth: bool,
+    depth_read_only: bool,
+    depth_only: bool,
+    wireframe: bool,
     multisampled: Option<SampleCountFlags>
 }
 
@@ -326,6 +387,27 @@ impl<'a> GraphicsBuilder<'a> {
         self
     }
 
+    /// Depth tested but not written, for blended geometry
+    pub fn depth_read_only(mut self) -> Self {
+        self.depth = true;
+        self.depth_read_only = true;
+        self
+    }
+
+    /// Depth written with no colour attachments, the fragment shader is
+    /// optional
+    pub fn depth_only(mut self) -> Self {
+        self.depth = true;
+        self.depth_only = true;
+        self
+    }
+
+    /// Draws triangle edges only, needs `Feature::FillModeNonSolid`
+    pub fn wireframe(mut self) -> Self {
+        self.wireframe = true;
+        self
+    }
+
     pub fn multisampled(mut self, samples: SampleCountFlags) -> Self {
         self.multisampled = Some(samples);
         self
@@ -337,12 +419,20 @@ impl<'a> GraphicsBuilder<'a> {
             .module(self.vertex.expect("Missing vertex shader").handle)
             .name(c"main")
             .build();
-        let fragment_stage = PipelineShaderStageCreateInfo::builder()
-            .stage(ShaderStageFlags::FRAGMENT)
-            .module(self.fragment.expect("Missing fragment shader").handle)
-            .name(c"main")
-            .build();
-        let stages = [vertex_stage, fragment_stage];
+        let fragment = match self.fragment {
+            Some(fragment) => Some(fragment),
+            None if self.depth_only => None,
+            None => panic!("Missing fragment shader"),
+        };
+        let stages = std::iter::once(vertex_stage)
+            .chain(fragment.map(|fragment| {
+                PipelineShaderStageCreateInfo::builder()
+                    .stage(ShaderStageFlags::FRAGMENT)
+                    .module(fragment.handle)
+                    .name(c"main")
+                    .build()
+            }))
+            .collect::<Vec<_>>();
 
         let viewport = self.viewport.expect("Missing viewport");
         let mut dynamic_states = Vec::new();
@@ -406,7 +496,11 @@ impl<'a> GraphicsBuilder<'a> {
         let raster = PipelineRasterizationStateCreateInfo::builder()
             .depth_clamp_enable(false)
             .rasterizer_discard_enable(false)
-            .polygon_mode(PolygonMode::FILL)
+            .polygon_mode(if self.wireframe {
+                PolygonMode::LINE
+            } else {
+                PolygonMode::FILL
+            })
             .line_width(1.0)
             .cull_mode(CullModeFlags::FRONT)
             .front_face(FrontFace::COUNTER_CLOCKWISE)
@@ -419,7 +513,7 @@ impl<'a> GraphicsBuilder<'a> {
         let depth_stencil = if self.depth {
             PipelineDepthStencilStateCreateInfo::builder()
                 .depth_test_enable(true)
-                .depth_write_enable(true)
+                .depth_write_enable(!self.depth_read_only)
                 .depth_compare_op(CompareOp::LESS)
                 .depth_bounds_test_enable(false)
                 .stencil_test_enable(false)
@@ -438,7 +532,7 @@ impl<'a> GraphicsBuilder<'a> {
             .dst_alpha_blend_factor(BlendFactor::ZERO)
             .alpha_blend_op(BlendOp::ADD)
             .build();
-        let attachments = [attachment];
+        let attachments = if self.depth_only { vec![] } else { vec![attachment] };
 
         let blending = PipelineColorBlendStateCreateInfo::builder()
             .logic_op_enable(false)
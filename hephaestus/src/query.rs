@@ -0,0 +1,67 @@
+use std::{rc::Rc, time::Duration};
+
+use ash::{
+    prelude::VkResult,
+    vk::{self, QueryPoolCreateInfo, QueryResultFlags, QueryType},
+};
+
+use crate::{Device, Feature};
+
+pub struct TimestampPool {
+    device: Rc<Device>,
+    pub handle: vk::QueryPool,
+    pub count: u32,
+    period: f32,
+}
+
+impl TimestampPool {
+    pub fn new(device: &Rc<Device>, count: u32) -> VkResult<Option<Rc<Self>>> {
+        if !device.enabled(Feature::Timestamps) {
+            return Ok(None);
+        }
+
+        let create_info = QueryPoolCreateInfo::builder()
+            .query_type(QueryType::TIMESTAMP)
+            .query_count(count);
+        let handle = unsafe { device.create_query_pool(&create_info, None)? };
+        Ok(Some(Rc::new(Self {
+            device: device.clone(),
+            handle,
+            count,
+            period: device.physical.properties.limits.timestamp_period,
+        })))
+    }
+
+    pub fn get(&self) -> VkResult<Option<Vec<Duration>>> {
+        let mut timestamps = vec![0_u64; self.count as usize];
+        match unsafe {
+            self.device.get_query_pool_results(
+                self.handle,
+                0,
+                self.count,
+                &mut timestamps,
+                QueryResultFlags::TYPE_64,
+            )
+        } {
+            Ok(()) => (),
+            Err(vk::Result::NOT_READY) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(
+            timestamps
+                .windows(2)
+                .map(|pair| {
+                    let ticks = pair[1].saturating_sub(pair[0]);
+                    Duration::from_nanos((ticks as f64 * self.period as f64) as u64)
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Drop for TimestampPool {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_query_pool(self.handle, None) }
+    }
+}
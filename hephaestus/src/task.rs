@@ -7,6 +7,9 @@ use ash::{
 
 use crate::{command, Device, Queue, Swapchain};
 
+/// Nanoseconds to wait for a swapchain image before giving up on the frame
+const ACQUIRE_TIMEOUT: u64 = 1_000_000_000;
+
 #[derive(Clone)]
 pub struct Fence {
     device: Rc<Device>,
@@ -25,6 +28,11 @@ impl Fence {
         unsafe { self.device.wait_for_fences(&fences, true, u64::MAX) }
     }
 
+    /// Whether the fence has been signalled, without blocking
+    pub fn signalled(&self) -> VkResult<bool> {
+        unsafe { self.device.get_fence_status(self.handle) }
+    }
+
     pub fn reset(&self) -> VkResult<()> {
         let fences = [self.handle];
         unsafe { self.device.reset_fences(&fences) }
@@ -98,19 +106,20 @@ impl Task {
         device: &Device,
         swapchain: &Swapchain,
         signal: Rc<Semaphore>,
-    ) -> VkResult<(u32, bool)> {
+    ) -> VkResult<Option<(u32, bool)>> {
         let result = unsafe {
             device.extensions.swapchain.acquire_next_image(
                 swapchain.handle,
-                u64::MAX,
+                ACQUIRE_TIMEOUT,
                 signal.handle,
                 vk::Fence::null(),
             )
         };
         self.semaphores.push(signal);
         match result {
-            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok((0, true)),
-            x => x,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(Some((0, true))),
+            Err(vk::Result::TIMEOUT | vk::Result::NOT_READY) => Ok(None),
+            x => x.map(Some),
         }
     }
 
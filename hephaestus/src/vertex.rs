@@ -4,6 +4,7 @@ pub enum AttributeType {
     Vec2,
     Vec3,
     Vec4,
+    UVec4,
 }
 
 impl AttributeType {
@@ -12,6 +13,7 @@ impl AttributeType {
             Self::Vec2 => Format::R32G32_SFLOAT,
             Self::Vec3 => Format::R32G32B32_SFLOAT,
             Self::Vec4 => Format::R32G32B32A32_SFLOAT,
+            Self::UVec4 => Format::R32G32B32A32_UINT,
         }
     }
 }
@@ -0,0 +1,90 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+};
+
+use glam::Vec3;
+
+/// Side length of an interest cell, in world units
+pub const INTEREST_CELL: f32 = 32.0;
+/// Players within this many cells of each other, on both axes, see each other
+pub const INTEREST_RADIUS: i32 = 2;
+
+type CellId = (i32, i32);
+
+/// Which clients are in which cell, so finding who is near a position only
+/// looks at the surrounding cells rather than at every client.
+#[derive(Default)]
+pub struct Grid {
+    cells: HashMap<CellId, BTreeSet<SocketAddr>>,
+    clients: HashMap<SocketAddr, CellId>,
+}
+
+impl Grid {
+    fn cell(position: Vec3) -> CellId {
+        (
+            (position.x / INTEREST_CELL).floor() as i32,
+            (position.z / INTEREST_CELL).floor() as i32,
+        )
+    }
+
+    /// Puts `addr` in the cell for `position`, taking it out of its old one.
+    pub fn place(&mut self, addr: SocketAddr, position: Vec3) {
+        let cell = Self::cell(position);
+        match self.clients.insert(addr, cell) {
+            Some(old) if old == cell => return,
+            Some(old) => self.remove_from(old, addr),
+            None => (),
+        }
+        self.cells.entry(cell).or_default().insert(addr);
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        if let Some(cell) = self.clients.remove(&addr) {
+            self.remove_from(cell, addr);
+        }
+    }
+
+    fn remove_from(&mut self, cell: CellId, addr: SocketAddr) {
+        if let Some(addrs) = self.cells.get_mut(&cell) {
+            addrs.remove(&addr);
+            if addrs.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    /// Everyone within `INTEREST_RADIUS` cells of `position`.
+    pub fn nearby(&self, position: Vec3) -> BTreeSet<SocketAddr> {
+        let (x, z) = Self::cell(position);
+        (-INTEREST_RADIUS..=INTEREST_RADIUS)
+            .flat_map(|dx| (-INTEREST_RADIUS..=INTEREST_RADIUS).map(move |dz| (x + dx, z + dz)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_nearby() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut grid = Grid::default();
+        grid.place(a, Vec3::ZERO);
+        grid.place(b, Vec3::X * INTEREST_CELL);
+        assert_eq!(grid.nearby(Vec3::ZERO), BTreeSet::from([a, b]));
+
+        let far = Vec3::X * INTEREST_CELL * (INTEREST_RADIUS + 2) as f32;
+        grid.place(b, far);
+        assert_eq!(grid.nearby(Vec3::ZERO), BTreeSet::from([a]));
+        assert_eq!(grid.nearby(far), BTreeSet::from([b]));
+
+        grid.remove(b);
+        assert!(grid.nearby(far).is_empty());
+    }
+}
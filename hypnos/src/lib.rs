@@ -0,0 +1,7 @@
+pub mod interest;
+pub mod limit;
+pub mod net;
+pub mod record;
+pub mod server;
+pub mod stats;
+pub mod supervise;
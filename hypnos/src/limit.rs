@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+/// Messages per second a client may send before the rest are dropped
+pub const DEFAULT_RATE: f32 = 60.0;
+/// Clients sending more than this many times their rate are flooding
+const CEILING: f32 = 4.0;
+/// Whole seconds of flooding before a client is kicked
+const KICK_AFTER: u32 = 3;
+/// How long everything from a kicked client is dropped, so it can't flood
+/// straight back in with a fresh bucket
+const BAN: Duration = Duration::from_secs(30);
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Kick,
+}
+
+/// A token bucket holding a second's worth of messages, plus a per-second
+/// count to catch clients that keep flooding well past the rate.
+pub struct Limiter {
+    rate: f32,
+    tokens: f32,
+    last: Instant,
+    window_start: Instant,
+    window_count: u32,
+    flooded: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(rate: f32, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+            window_start: now,
+            window_count: 0,
+            flooded: 0,
+            banned_until: None,
+        }
+    }
+
+    /// Drops everything from now until the ban runs out
+    pub fn ban(&mut self, now: Instant) {
+        self.banned_until = Some(now + BAN);
+    }
+
+    pub fn banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+
+    pub fn take(&mut self, now: Instant) -> Verdict {
+        if self.banned(now) {
+            return Verdict::Drop;
+        }
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            // A quiet gap of a whole window means the flood is over
+            if elapsed < WINDOW * 2 && self.window_count as f32 > self.rate * CEILING {
+                self.flooded += 1;
+            } else {
+                self.flooded = 0;
+            }
+            self.window_start = now;
+            self.window_count = 0;
+        }
+        self.window_count += 1;
+        if self.flooded >= KICK_AFTER {
+            self.flooded = 0;
+            self.ban(now);
+            return Verdict::Kick;
+        }
+
+        let refill = now.duration_since(self.last).as_secs_f32() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return Verdict::Drop;
+        }
+        self.tokens -= 1.0;
+        Verdict::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_limiter() {
+        let start = Instant::now();
+        let mut limiter = Limiter::new(10.0, start);
+        let burst = (0..20)
+            .map(|_| limiter.take(start))
+            .filter(|verdict| *verdict == Verdict::Allow)
+            .count();
+        assert_eq!(burst, 10);
+        assert_eq!(
+            limiter.take(start + Duration::from_millis(100)),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.take(start + Duration::from_millis(100)),
+            Verdict::Drop
+        );
+
+        // 100 messages a second, well past the ceiling, for long enough
+        let kicked = (0..500)
+            .map(|i| limiter.take(start + Duration::from_millis(200 + i * 10)))
+            .position(|verdict| verdict == Verdict::Kick);
+        assert!(kicked.is_some_and(|i| i >= 200));
+    }
+
+    #[test]
+    pub fn test_kick_ban() {
+        let start = Instant::now();
+        let mut limiter = Limiter::new(10.0, start);
+        let kicked = (0..1000)
+            .map(|i| start + Duration::from_millis(i * 5))
+            .find(|now| limiter.take(*now) == Verdict::Kick)
+            .unwrap();
+
+        // Nothing gets through until the ban is up, however slowly it's sent
+        assert!(limiter.banned(kicked));
+        assert_eq!(limiter.take(kicked + BAN / 2), Verdict::Drop);
+        assert_eq!(limiter.take(kicked + BAN - Duration::from_millis(1)), Verdict::Drop);
+        assert!(!limiter.banned(kicked + BAN));
+        assert_eq!(limiter.take(kicked + BAN), Verdict::Allow);
+    }
+}
@@ -1,310 +1,208 @@
 use std::{
-    cell::{Cell, RefCell},
-    collections::{HashMap, VecDeque},
-    io::ErrorKind,
-    net::{SocketAddr, UdpSocket},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter},
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use glam::Vec3;
-use nyx::{
-    data,
-    equipment::{Equipment, EquipmentId, EquipmentInventory, Passive},
-    item::{Inventory, Item, ItemStack, LootTable, RecipeOutput, RARITIES},
-    protocol::{ClientId, Clientbound, ClientboundBundle, Serverbound, Tick, TPS},
-    task::Proficiencies,
+use crossbeam_channel::{unbounded, Sender};
+use hypnos::{
+    limit::DEFAULT_RATE,
+    net::{handle_networking, Peers, DEFAULT_TIMEOUT},
+    record::{Record, Recorder, Replay},
+    server::{coalesce, Admin, Server},
+    supervise::{supervise, RestartPolicy},
 };
+use log::{info, warn};
+use nyx::protocol::{ClientId, Tick, TPS};
 
-const FORCED_LATENCY: Duration = Duration::from_millis(0);
+const MAX_CATCH_UP: u32 = 5;
 
-pub struct Client {
-    id: ClientId,
-    position: Cell<Vec3>,
-    inventory: RefCell<Inventory>,
-    equipment: RefCell<EquipmentInventory>,
-    proficiencies: RefCell<Proficiencies>,
-}
+/// Set by SIGINT or the `shutdown` command to stop after the current tick
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
-fn handle_networking(
-    socket: UdpSocket,
-    clientbound_rx: Receiver<(SocketAddr, Clientbound)>,
-    flush_rx: Receiver<Tick>,
-    serverbound_tx: Sender<(SocketAddr, Serverbound)>,
-) {
-    let mut buf = [0; 4096];
-    println!("Listening");
-    let mut messages: HashMap<SocketAddr, Vec<Clientbound>> = HashMap::new();
-    let mut to_receive = VecDeque::new();
-    let mut last_seen: HashMap<SocketAddr, Instant> = HashMap::new();
+#[cfg(unix)]
+fn handle_sigint() {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+    unsafe { libc::signal(libc::SIGINT, on_sigint as libc::sighandler_t) };
+}
 
-    loop {
-        if let Ok((addr, message)) = clientbound_rx.try_recv() {
-            match messages.get_mut(&addr) {
-                Some(messages) => messages.push(message),
-                None => {
-                    messages.insert(addr, vec![message]);
+#[cfg(not(unix))]
+fn handle_sigint() {}
+
+/// Reads admin commands from stdin: `pause`, `resume`, `shutdown` and
+/// `damage <id> <amount>`.
+fn handle_admin(paused: Arc<AtomicBool>, admin_tx: Sender<Admin>) {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["pause"] => paused.store(true, Ordering::Relaxed),
+            ["resume"] => paused.store(false, Ordering::Relaxed),
+            ["shutdown"] => SHUTDOWN.store(true, Ordering::Relaxed),
+            ["damage", id, amount] => match (id.parse(), amount.parse()) {
+                (Ok(id), Ok(amount)) => {
+                    // The server has stopped, nothing is left to command
+                    if admin_tx.send(Admin::Damage(ClientId(id), amount)).is_err() {
+                        break;
+                    }
                 }
-            }
+                _ => warn!("Usage: damage <id> <amount>"),
+            },
+            [] => (),
+            _ => warn!("Unknown command {line:?}"),
         }
+    }
+}
 
-        if let Ok(tick) = flush_rx.try_recv() {
-            messages.iter_mut().for_each(|(addr, messages)| {
-                let bundle = ClientboundBundle {
-                    tick,
-                    messages: messages.to_vec(),
-                };
-                *messages = Vec::new();
-                let buffer = bincode::serialize(&bundle).unwrap();
-                socket.send_to(&buffer, addr).unwrap();
-            })
-        }
+fn arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
 
-        let (n, addr) = match socket.recv_from(&mut buf) {
-            Ok((n, addr)) => (n, addr),
-            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
-            Err(e) => panic!("{e:?}"),
-        };
-        let Ok(message) = bincode::deserialize::<Serverbound>(&buf[0..n]) else {
-            continue;
-        };
-        println!("{n} from {addr:?}");
-        last_seen.insert(addr, Instant::now());
+fn seed() -> u64 {
+    arg("--seed")
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random)
+}
 
-        last_seen.clone().iter().for_each(|(addr, seen)| {
-            if seen.elapsed() > Duration::from_secs(10) {
-                serverbound_tx
-                    .send((*addr, Serverbound::Disconnect))
-                    .unwrap();
-                last_seen.remove(addr);
-            }
-        });
+fn replay(path: &str) -> Result<()> {
+    let replay = Replay::new(BufReader::new(File::open(path)?))?;
+    info!("Replaying {path} with seed {}", replay.seed);
+    let (tx, rx) = unbounded();
+    let mut server = Server::new(replay.seed, tx);
 
-        to_receive.push_back((Instant::now(), (addr, message)));
-        while let Some((time, _)) = to_receive.get(0) {
-            if *time + FORCED_LATENCY < Instant::now() {
-                serverbound_tx
-                    .send(to_receive.pop_front().unwrap().1)
-                    .unwrap()
-            } else {
-                break;
-            }
-        }
+    let mut tick = Tick(0);
+    let mut received = 0;
+    let mut sent = 0;
+    for record in replay {
+        let (at, record) = record?;
+        tick = at;
+        server.set_tick(tick);
+        record.apply(&mut server)?;
+        received += 1;
+        sent += rx.try_iter().count();
     }
-}
-
-fn add_client(
-    clients: &mut HashMap<SocketAddr, Client>,
-    tx: &Sender<(SocketAddr, Clientbound)>,
-    id: ClientId,
-    addr: SocketAddr,
-) -> Result<()> {
-    tx.send((addr, Clientbound::AuthSuccess(id)))?;
-    clients
-        .iter()
-        .map(|(other_addr, other)| {
-            tx.send((*other_addr, Clientbound::Spawn(id, Vec3::ZERO)))?;
-            tx.send((addr, Clientbound::Spawn(other.id, other.position.get())))?;
-            Ok(())
-        })
-        .collect::<Result<Vec<_>>>()?;
-    clients.insert(
-        addr,
-        Client {
-            id,
-            position: Cell::new(Vec3::ZERO),
-            inventory: RefCell::new(Inventory::default()),
-            equipment: RefCell::new(EquipmentInventory(Vec::new())),
-            proficiencies: RefCell::new(Proficiencies::default()),
-        },
-    );
 
+    info!("Replayed {received} messages up to {tick:?}, {sent} sent");
     Ok(())
 }
 
 fn main() -> Result<()> {
+    pretty_env_logger::init();
+
+    if let Some(path) = arg("--replay") {
+        return replay(&path);
+    }
+
     let socket = UdpSocket::bind("0.0.0.0:8080").unwrap();
     socket.set_nonblocking(true).unwrap();
-    let mut clients: HashMap<SocketAddr, Client> = HashMap::new();
     let (serverbound_tx, serverbound_rx) = unbounded();
     let (clientbound_tx, clientbound_rx) = unbounded();
     let (flush_tx, flush_rx) = unbounded();
 
-    std::thread::spawn(|| handle_networking(socket, clientbound_rx, flush_rx, serverbound_tx));
+    let timeout = arg("--timeout")
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let rate = arg("--rate")
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(DEFAULT_RATE);
+    // A panic here would otherwise leave the server ticking with nobody able
+    // to reach it
+    let networking = std::thread::spawn(move || {
+        // Kept across restarts, so a failure doesn't let banned clients back
+        let mut peers = Peers::default();
+        supervise("Networking", RestartPolicy::Always, || {
+            let socket = socket.try_clone()?;
+            let (clientbound_rx, flush_rx) = (clientbound_rx.clone(), flush_rx.clone());
+            let serverbound_tx = serverbound_tx.clone();
+            handle_networking(
+                socket,
+                &mut peers,
+                clientbound_rx,
+                flush_rx,
+                serverbound_tx,
+                timeout,
+                rate,
+            )
+        })
+    });
+    handle_sigint();
+
+    let paused = Arc::new(AtomicBool::new(false));
+    let (admin_tx, admin_rx) = unbounded();
+    {
+        let paused = paused.clone();
+        std::thread::spawn(move || handle_admin(paused, admin_tx));
+    }
 
-    let mut next = 0;
-    let mut next_equipment = 0;
     let mut tick = Tick(0);
     let rx = serverbound_rx;
-    let tx = clientbound_tx;
-
-    let recipes = data::recipes();
-    let nodes = data::nodes::get();
 
-    loop {
-        let start = Instant::now();
-
-        while let Ok((addr, message)) = rx.try_recv() {
-            if let Serverbound::AuthRequest = message {
-                let id = ClientId(next);
-                add_client(&mut clients, &tx, id, addr).unwrap();
-                next += 1;
+    let seed = seed();
+    info!("Seed: {seed}");
+    let mut server = Server::new(seed, clientbound_tx);
+
+    let mut recorder = match arg("--record") {
+        Some(path) => Some(Recorder::new(BufWriter::new(File::create(path)?), seed)?),
+        None => None,
+    };
+
+    let step = Duration::from_secs_f32(1.0 / TPS);
+    let mut deadline = Instant::now();
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let paused = paused.load(Ordering::Relaxed);
+        server.set_paused(paused);
+        server.set_tick(tick);
+        for (addr, message) in coalesce(rx.try_iter()) {
+            if let Some(recorder) = recorder.as_mut().filter(|_| server.accepts(&message)) {
+                recorder.write(tick, &Record::Message(addr, message.clone()))?;
             }
+            server.handle(addr, message).unwrap();
+        }
 
-            let Some(client) = clients.get(&addr) else {
-                continue;
-            };
-            match message {
-                Serverbound::Move(position, tick) => {
-                    let changed = client.position.get() != position;
-                    client.position.set(position);
-                    clients.keys().for_each(|other_addr| {
-                        if *other_addr != addr && !changed {
-                            return;
-                        }
-                        tx.send((*other_addr, Clientbound::Move(client.id, position, tick)))
-                            .unwrap();
-                    })
-                }
-                Serverbound::Gather(index) => {
-                    let Some(node) = nodes.get(index) else {
-                        continue;
-                    };
-                    let mut inventory = client.inventory.borrow_mut();
-                    node.pick().iter().for_each(|stack| {
-                        inventory.add(*stack);
-                        tx.send((
-                            addr,
-                            Clientbound::SetStack(ItemStack {
-                                item: stack.item,
-                                quantity: inventory.get(stack.item).unwrap_or_default(),
-                            }),
-                        ))
-                        .unwrap();
-                    })
-                }
-                Serverbound::Craft(index, rarities) => {
-                    let Some(recipe) = recipes.get(index) else {
-                        continue;
-                    };
-                    let mut inventory = client.inventory.borrow_mut();
-                    let mut equipment = client.equipment.borrow_mut();
-                    if !recipe.craftable(&inventory.items().collect::<Vec<_>>(), &rarities) {
-                        continue;
-                    }
-                    recipe
-                        .inputs
-                        .iter()
-                        .cloned()
-                        .zip(rarities.clone())
-                        .for_each(|((kind, quantity), rarity)| {
-                            let item = Item { kind, rarity };
-                            inventory.remove(ItemStack { item, quantity });
-                            tx.send((
-                                addr,
-                                Clientbound::SetStack(ItemStack {
-                                    item,
-                                    quantity: inventory.get(item).unwrap_or_default(),
-                                }),
-                            ))
-                            .unwrap();
-                        });
-
-                    let tags = recipe.output.tags();
-                    let rank_up = client.proficiencies.borrow().rank_up.get(&tags);
-                    let chances = recipe.rarity_chances(&rarities, rank_up);
-                    let rarity = *RARITIES
-                        .into_iter()
-                        .zip(chances)
-                        .fold(LootTable::default(), |picker, (rarity, chance)| {
-                            picker.add(chance, rarity)
-                        })
-                        .pick();
-
-                    match recipe.output {
-                        RecipeOutput::Item(kind) => {
-                            let item = Item { kind, rarity };
-                            inventory.add(ItemStack { item, quantity: 1 });
-                            tx.send((
-                                addr,
-                                Clientbound::SetStack(ItemStack {
-                                    item,
-                                    quantity: inventory.get(item).unwrap_or_default(),
-                                }),
-                            ))
-                            .unwrap();
-                        }
-                        RecipeOutput::Equipment(kind) => {
-                            let piece = Equipment {
-                                id: EquipmentId(next_equipment),
-                                kind,
-                                rarity,
-                                durability: 10,
-                                passives: vec![Passive::Empty; rarity.index() + 1],
-                            };
-                            next_equipment += 1;
-                            equipment.0.push(piece.clone());
-                            tx.send((addr, Clientbound::AddEquipment(piece))).unwrap();
-                        }
-                    }
-                }
-                Serverbound::Refine(id, reagent) => {
-                    let Some(quantity) = client.inventory.borrow().get(reagent) else {
-                        continue;
-                    };
-
-                    let Some(replacement) = reagent.passive() else {
-                        continue;
-                    };
-                    let mut equipment = client.equipment.borrow_mut();
-
-                    let Some(equipment) =
-                        equipment.0.iter_mut().find(|equipment| equipment.id == id)
-                    else {
-                        continue;
-                    };
+        // Admin commands use the RNG too, so replays need them as well
+        while let Ok(command) = admin_rx.try_recv() {
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.write(tick, &Record::Admin(command.clone()))?;
+            }
+            server.admin(command)?;
+        }
 
-                    {
-                        let Some(passive) = equipment
-                            .passives
-                            .iter_mut()
-                            .find(|passive| **passive == Passive::Empty)
-                        else {
-                            continue;
-                        };
-                        *passive = replacement;
-                    }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.flush()?;
+        }
 
-                    let stack = ItemStack {
-                        item: reagent,
-                        quantity: quantity - 1,
-                    };
-                    client.inventory.borrow_mut().set(stack);
-                    tx.send((addr, Clientbound::SetStack(stack))).unwrap();
-                    tx.send((
-                        addr,
-                        Clientbound::SetPassives(id, equipment.passives.clone()),
-                    ))
-                    .unwrap();
-                }
-                Serverbound::Disconnect => {
-                    clients
-                        .iter()
-                        .filter(|(other_addr, _)| **other_addr != addr)
-                        .for_each(|(other_addr, _)| {
-                            tx.send((*other_addr, Clientbound::Despawn(client.id)))
-                                .unwrap();
-                        });
-                    clients.remove(&addr);
-                }
+        // Still flush while paused so clients keep hearing from the server
+        if !paused {
+            tick.0 += 1;
+        }
+        flush_tx.send(tick).unwrap();
 
-                Serverbound::AuthRequest => (),
-            }
+        deadline += step;
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        } else if now - deadline > step * MAX_CATCH_UP {
+            warn!("Running {:?} behind at {tick:?}, skipping ahead", now - deadline);
+            deadline = now;
         }
+    }
 
-        tick.0 += 1;
-        flush_tx.send(tick).unwrap();
-        std::thread::sleep(Duration::from_secs_f32(1.0 / TPS) - start.elapsed())
+    info!("Shutting down at {tick:?}");
+    server.shutdown()?;
+    // Hanging up the flush channel has the networking thread send what's left
+    drop(flush_tx);
+    networking.join().unwrap()?;
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.flush()?;
     }
+    Ok(())
 }
@@ -0,0 +1,322 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use log::{info, trace, warn};
+use nyx::protocol::{
+    self, Clientbound, ClientboundBundle, Serverbound, Tick, MAX_MESSAGE_LEN,
+};
+
+use crate::{
+    limit::{Limiter, Verdict},
+    stats::Bandwidth,
+};
+
+const FORCED_LATENCY: Duration = Duration::from_millis(0);
+/// How long a client can go without sending anything before it's dropped.
+/// Clients ping every second, so only dead connections get this far.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Splits a tick's messages into bundles that each fit in `MAX_MESSAGE_LEN`,
+/// always sending at least one so clients still see the tick.
+fn bundles(tick: Tick, messages: Vec<Clientbound>) -> Vec<ClientboundBundle> {
+    let empty = |tick| ClientboundBundle {
+        tick,
+        messages: Vec::new(),
+    };
+    let header = bincode::serialized_size(&empty(tick)).unwrap();
+    let mut bundles = vec![empty(tick)];
+    let mut size = header;
+    for message in messages {
+        let message_size = bincode::serialized_size(&message).unwrap();
+        let full = size + message_size > MAX_MESSAGE_LEN as u64;
+        if full && !bundles.last().unwrap().messages.is_empty() {
+            bundles.push(empty(tick));
+            size = header;
+        }
+        bundles.last_mut().unwrap().messages.push(message);
+        size += message_size;
+    }
+    bundles
+}
+
+type Outgoing = HashMap<SocketAddr, Vec<Clientbound>>;
+
+fn queue(clientbound_rx: &Receiver<(SocketAddr, Clientbound)>, messages: &mut Outgoing) {
+    clientbound_rx.try_iter().for_each(|(addr, message)| {
+        messages.entry(addr).or_default().push(message);
+    });
+}
+
+/// Errors that only lose one datagram, such as a reset surfacing on the next
+/// receive after an ICMP port unreachable from a client that's gone
+fn transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Sends every queued message, a failed send only costs that client its
+/// bundle
+fn flush(socket: &UdpSocket, messages: &mut Outgoing, tick: Tick, bandwidth: &mut Bandwidth) {
+    messages.iter_mut().for_each(|(addr, messages)| {
+        messages.iter().for_each(|message| {
+            let size = bincode::serialized_size(message).unwrap_or_default();
+            bandwidth.sent(message.kind(), size);
+        });
+        bundles(tick, std::mem::take(messages))
+            .iter()
+            .for_each(|bundle| {
+                let buffer = bincode::serialize(bundle).unwrap();
+                if let Err(e) = socket.send_to(&buffer, addr) {
+                    warn!("Failed to send {} bytes to {addr:?}: {e}", buffer.len());
+                }
+            });
+    });
+    bandwidth.report();
+}
+
+/// Forgets clients that have been silent for longer than `timeout`,
+/// returning their addresses.
+fn reap(
+    last_seen: &mut HashMap<SocketAddr, Instant>,
+    now: Instant,
+    timeout: Duration,
+) -> Vec<SocketAddr> {
+    let silent = last_seen
+        .iter()
+        .filter(|(_, seen)| now.duration_since(**seen) > timeout)
+        .map(|(addr, _)| *addr)
+        .collect::<Vec<_>>();
+    silent.iter().for_each(|addr| {
+        last_seen.remove(addr);
+    });
+    silent
+}
+
+/// What the networking loop knows about clients. It's kept by the caller so
+/// that restarting the loop after a failure doesn't lift bans, refill rate
+/// limits or lose queued messages.
+#[derive(Default)]
+pub struct Peers {
+    messages: Outgoing,
+    to_receive: VecDeque<(Instant, (SocketAddr, Serverbound))>,
+    last_seen: HashMap<SocketAddr, Instant>,
+    limiters: HashMap<SocketAddr, Limiter>,
+}
+
+pub fn handle_networking(
+    socket: UdpSocket,
+    peers: &mut Peers,
+    clientbound_rx: Receiver<(SocketAddr, Clientbound)>,
+    flush_rx: Receiver<Tick>,
+    serverbound_tx: Sender<(SocketAddr, Serverbound)>,
+    timeout: Duration,
+    rate: f32,
+) -> Result<()> {
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    info!("Listening");
+    let Peers {
+        messages,
+        to_receive,
+        last_seen,
+        limiters,
+    } = peers;
+    let mut bandwidth = Bandwidth::new();
+    let mut last_tick = Tick(0);
+
+    loop {
+        queue(&clientbound_rx, messages);
+        match flush_rx.try_recv() {
+            Ok(tick) => {
+                last_tick = tick;
+                flush(&socket, messages, tick, &mut bandwidth);
+            }
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => {
+                // The server has stopped, send off whatever it said last
+                queue(&clientbound_rx, messages);
+                flush(&socket, messages, last_tick, &mut bandwidth);
+                info!("Stopped listening");
+                return Ok(());
+            }
+        }
+
+        // Checked every loop, a silent client won't trigger it by receiving
+        let now = Instant::now();
+        reap(last_seen, now, timeout)
+            .into_iter()
+            .try_for_each(|addr| {
+                info!("{addr:?} timed out");
+                serverbound_tx.send((addr, Serverbound::Disconnect))
+            })?;
+        // Kicked addresses are remembered until their ban runs out
+        limiters.retain(|addr, limiter| last_seen.contains_key(addr) || limiter.banned(now));
+
+        let (n, addr) = match socket.recv_from(&mut buf) {
+            Ok((n, addr)) => (n, addr),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) if transient(&e) => {
+                warn!("Failed to receive: {e}");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let message = match protocol::decode::<Serverbound>(&buf[0..n]) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Dropping {n} bytes from {addr:?}: {e}");
+                continue;
+            }
+        };
+        trace!("{n} bytes from {addr:?}");
+        bandwidth.received(message.kind(), n as u64);
+        let now = Instant::now();
+        let limiter = limiters
+            .entry(addr)
+            .or_insert_with(|| Limiter::new(rate, now));
+        match (&message, limiter.take(now)) {
+            (Serverbound::Disconnect, _) => {
+                last_seen.remove(&addr);
+            }
+            (_, Verdict::Allow) => {
+                last_seen.insert(addr, now);
+            }
+            (_, Verdict::Drop) => {
+                trace!("Rate limited {addr:?}");
+                continue;
+            }
+            (_, Verdict::Kick) => {
+                warn!("Kicking {addr:?} for flooding");
+                last_seen.remove(&addr);
+                serverbound_tx.send((addr, Serverbound::Disconnect))?;
+                continue;
+            }
+        }
+
+        to_receive.push_back((Instant::now(), (addr, message)));
+        while let Some((time, _)) = to_receive.get(0) {
+            if *time + FORCED_LATENCY < Instant::now() {
+                serverbound_tx.send(to_receive.pop_front().unwrap().1)?;
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use nyx::protocol::ClientId;
+
+    use crate::limit::DEFAULT_RATE;
+
+    use super::*;
+
+    #[test]
+    pub fn test_bundles_fit() {
+        let messages = (0..1000)
+            .map(|i| Clientbound::SetName(ClientId(i), "x".repeat(32)))
+            .collect::<Vec<_>>();
+        let split = bundles(Tick(1), messages);
+        assert!(split.len() > 1);
+        assert!(split
+            .iter()
+            .all(|bundle| bincode::serialized_size(bundle).unwrap() <= MAX_MESSAGE_LEN as u64));
+        assert_eq!(
+            split.iter().map(|bundle| bundle.messages.len()).sum::<usize>(),
+            1000
+        );
+        assert_eq!(bundles(Tick(1), Vec::new()).len(), 1);
+    }
+
+    #[test]
+    pub fn test_reap() {
+        let quiet: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let chatty: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let start = Instant::now();
+        let mut last_seen = HashMap::from([(quiet, start), (chatty, start)]);
+
+        let timeout = Duration::from_secs(30);
+        assert!(reap(&mut last_seen, start + timeout / 2, timeout).is_empty());
+        last_seen.insert(chatty, start + timeout);
+        assert_eq!(reap(&mut last_seen, start + timeout * 2, timeout), vec![quiet]);
+        assert_eq!(last_seen.len(), 1);
+        assert!(reap(&mut last_seen, start + timeout * 2, timeout).is_empty());
+    }
+
+    #[test]
+    pub fn test_ban_survives_restart() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_nonblocking(true).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ping = bincode::serialize(&Serverbound::Ping(0)).unwrap();
+
+        // Runs the loop until the client has pinged, as the supervisor would
+        // after a restart, returning whether the ping got through
+        let run = |peers: &mut Peers| {
+            let (_clientbound_tx, clientbound_rx) = unbounded();
+            let (flush_tx, flush_rx) = unbounded();
+            let (serverbound_tx, serverbound_rx) = unbounded();
+            let socket = server.try_clone().unwrap();
+            std::thread::scope(|scope| {
+                let networking = scope.spawn(|| {
+                    handle_networking(
+                        socket,
+                        peers,
+                        clientbound_rx,
+                        flush_rx,
+                        serverbound_tx,
+                        DEFAULT_TIMEOUT,
+                        DEFAULT_RATE,
+                    )
+                });
+                client.send_to(&ping, server.local_addr().unwrap()).unwrap();
+                let received = serverbound_rx.recv_timeout(Duration::from_millis(500)).is_ok();
+                drop(flush_tx);
+                networking.join().unwrap().unwrap();
+                received
+            })
+        };
+
+        let mut peers = Peers::default();
+        let now = Instant::now();
+        let mut limiter = Limiter::new(DEFAULT_RATE, now);
+        limiter.ban(now);
+        peers.limiters.insert(client.local_addr().unwrap(), limiter);
+        assert!(!run(&mut peers));
+        assert!(!run(&mut peers));
+        assert!(run(&mut Peers::default()));
+    }
+
+    #[test]
+    pub fn test_flush_send_error() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        // An IPv4 socket can't send to an IPv6 address, which used to panic
+        // and take everyone else's bundles with it
+        let unreachable: SocketAddr = "[::1]:1".parse().unwrap();
+        let mut messages = Outgoing::from([
+            (unreachable, vec![Clientbound::Pong(1)]),
+            (client.local_addr().unwrap(), vec![Clientbound::Pong(2)]),
+        ]);
+        flush(&server, &mut messages, Tick(1), &mut Bandwidth::new());
+
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let n = client.recv(&mut buf).unwrap();
+        let bundle = protocol::decode::<ClientboundBundle>(&buf[..n]).unwrap();
+        assert!(matches!(bundle.messages.as_slice(), [Clientbound::Pong(2)]));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fmt,
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+};
+
+use anyhow::Result;
+use nyx::protocol::{Serverbound, Tick};
+use serde::{Deserialize, Serialize};
+
+use crate::server::{Admin, Server};
+
+/// CRC-32 (IEEE), enough to notice truncated or bit-rotted recordings.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// A record whose checksum didn't match, counting from 0 after the seed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Corrupt {
+    pub record: usize,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Replay record {} is corrupt", self.record)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+/// Anything that changes the server's state, which is everything a replay
+/// needs to reach the same state
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Record {
+    Message(SocketAddr, Serverbound),
+    Admin(Admin),
+}
+
+impl Record {
+    pub fn apply(self, server: &mut Server) -> Result<()> {
+        match self {
+            Self::Message(addr, message) => server.handle(addr, message),
+            Self::Admin(command) => server.admin(command),
+        }
+    }
+}
+
+pub struct Recorder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W, seed: u64) -> Result<Self> {
+        bincode::serialize_into(&mut writer, &seed)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, tick: Tick, record: &Record) -> Result<()> {
+        let payload = bincode::serialize(&(tick, record))?;
+        bincode::serialize_into(&mut self.writer, &(&payload, crc32(&payload)))?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Replay<R: Read> {
+    reader: R,
+    pub seed: u64,
+    record: usize,
+}
+
+impl<R: Read> Replay<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let seed = bincode::deserialize_from(&mut reader)?;
+        Ok(Self {
+            reader,
+            seed,
+            record: 0,
+        })
+    }
+
+    fn read(&mut self) -> Result<(Tick, Record)> {
+        let record = self.record;
+        self.record += 1;
+        let (payload, crc): (Vec<u8>, u32) = bincode::deserialize_from(&mut self.reader)?;
+        if crc32(&payload) != crc {
+            return Err(Corrupt { record }.into());
+        }
+        Ok(bincode::deserialize(&payload)?)
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = Result<(Tick, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read() {
+            Ok(record) => Some(Ok(record)),
+            Err(e) => match e.downcast_ref::<bincode::ErrorKind>() {
+                Some(bincode::ErrorKind::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => None,
+                _ => Some(Err(e)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use glam::Vec3;
+    use nyx::{
+        item::Rarity,
+        protocol::{ClientId, PLAYER_HEALTH, PROTOCOL_VERSION},
+    };
+
+    use super::*;
+
+    fn run(replay: Replay<&[u8]>) -> String {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(replay.seed, tx);
+        replay.for_each(|record| {
+            let (tick, record) = record.unwrap();
+            server.set_tick(tick);
+            record.apply(&mut server).unwrap();
+        });
+        format!("{:?}", rx.try_iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    pub fn test_replay_deterministic() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut buffer = Vec::new();
+        let mut recorder = Recorder::new(&mut buffer, 42).unwrap();
+        let mut tick = Tick(0);
+        let message = |message| Record::Message(addr, message);
+        recorder.write(tick, &message(Serverbound::AuthRequest(PROTOCOL_VERSION))).unwrap();
+        (0..50).for_each(|_| {
+            tick.inc();
+            recorder.write(tick, &message(Serverbound::Gather(0))).unwrap();
+        });
+        recorder
+            .write(tick, &message(Serverbound::Move(Vec3::ONE, tick)))
+            .unwrap();
+        recorder
+            .write(tick, &message(Serverbound::Craft(0, vec![Rarity::Common])))
+            .unwrap();
+        // Dying drops the inventory and respawns at a random point
+        recorder
+            .write(tick, &Record::Admin(Admin::Damage(ClientId(0), PLAYER_HEALTH)))
+            .unwrap();
+        recorder.flush().unwrap();
+
+        let first = run(Replay::new(buffer.as_slice()).unwrap());
+        let second = run(Replay::new(buffer.as_slice()).unwrap());
+        assert!(!first.is_empty());
+        assert!(first.contains("SpawnDrop"));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    pub fn test_replay_corrupt() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut buffer = Vec::new();
+        let mut recorder = Recorder::new(&mut buffer, 42).unwrap();
+        (0..5).for_each(|i| {
+            let record = Record::Message(addr, Serverbound::Move(Vec3::ONE, Tick(i)));
+            recorder.write(Tick(i), &record).unwrap()
+        });
+        recorder.flush().unwrap();
+
+        // Flip a byte in the last record's payload, just before its checksum
+        let n = buffer.len();
+        buffer[n - 5] ^= 0xff;
+        let records = Replay::new(buffer.as_slice()).unwrap().collect::<Vec<_>>();
+        assert_eq!(records.len(), 5);
+        assert!(records[..4].iter().all(|record| record.is_ok()));
+        let error = records[4].as_ref().unwrap_err();
+        assert_eq!(error.downcast_ref::<Corrupt>(), Some(&Corrupt { record: 4 }));
+    }
+}
@@ -0,0 +1,1099 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use glam::Vec3;
+use log::{debug, info, warn};
+use nyx::{
+    data,
+    equipment::{Equipment, EquipmentId, EquipmentInventory, Passive},
+    item::{Inventory, Item, ItemStack, LootTable, Recipe, RecipeOutput, RARITIES},
+    movement::integrate,
+    protocol::{
+        ClientId, Clientbound, DropId, Serverbound, Tick, MAX_CHAT_LEN, MAX_MESSAGE_LEN,
+        MAX_NAME_LEN, PICKUP_RANGE, PLAYER_HEALTH, PLAYER_SPEED, PROTOCOL_VERSION, TPS,
+    },
+    task::Proficiencies,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::interest::Grid;
+
+const SPAWN_POINTS: usize = 8;
+const SPAWN_RADIUS: f32 = 5.0;
+const SPAWN_JITTER: f32 = 0.5;
+/// Slack on top of the distance a player could legitimately cover, to absorb
+/// frame timing differences between the client and the server.
+const MOVE_MARGIN: f32 = 1.5;
+/// Players further than this from the origin on any axis are respawned.
+const WORLD_BOUNDS: f32 = 1000.0;
+/// Chat messages allowed per client per second
+const CHAT_RATE: usize = 3;
+/// Minimum ticks between syncs for one client, as each resends everything it
+/// can see
+const SYNC_INTERVAL: u64 = TPS as u64;
+/// Room left for the bundle around a `Synced` message
+const SYNCED_MAX_LEN: u64 = MAX_MESSAGE_LEN as u64 - 64;
+
+/// Commands from whoever runs the server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Admin {
+    Damage(ClientId, f32),
+}
+
+pub struct Client {
+    id: ClientId,
+    position: Cell<Vec3>,
+    last_move: Cell<Option<Tick>>,
+    /// The server's tick when the last move was accepted
+    moved_at: Cell<Option<Tick>>,
+    speed: Cell<f32>,
+    health: Cell<f32>,
+    name: RefCell<String>,
+    chat: RefCell<VecDeque<Tick>>,
+    synced_at: Cell<Option<Tick>>,
+    /// Other clients close enough that this one has been told about them
+    visible: RefCell<BTreeSet<SocketAddr>>,
+    inventory: RefCell<Inventory>,
+    equipment: RefCell<EquipmentInventory>,
+    proficiencies: RefCell<Proficiencies>,
+}
+
+impl Client {
+    /// Limits a move to `position` received at `now` to what the client's
+    /// speed allows since its last move. Only server ticks are counted, a
+    /// client could claim any amount of time has passed.
+    fn clamp_move(&self, position: Vec3, now: Tick) -> Vec3 {
+        let ticks = match self.moved_at.get() {
+            Some(last) => now.0.saturating_sub(last.0).max(1),
+            None => 1,
+        };
+        let dt = ticks as f32 / TPS;
+        let speed = self.speed.get() * MOVE_MARGIN;
+        let current = self.position.get();
+        if current.distance(position) <= speed * dt {
+            return position;
+        }
+        // Too far: step towards the claim the way the client would have
+        integrate(current, position - current, speed, dt)
+    }
+
+    /// Whether another chat message at `now` fits in the last second's
+    /// `CHAT_RATE`, counting it if so.
+    fn chat_allowed(&self, now: Tick) -> bool {
+        let mut sent = self.chat.borrow_mut();
+        while sent
+            .front()
+            .is_some_and(|tick| now.0.saturating_sub(tick.0) >= TPS as u64)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= CHAT_RATE {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+/// Keeps only the latest `Move` from each client among a tick's messages, so
+/// a client sending several between ticks is only moved once.
+pub fn coalesce(
+    messages: impl IntoIterator<Item = (SocketAddr, Serverbound)>,
+) -> Vec<(SocketAddr, Serverbound)> {
+    let mut moved = HashSet::new();
+    let mut messages = messages.into_iter().collect::<Vec<_>>();
+    messages.reverse();
+    messages.retain(|(addr, message)| {
+        !matches!(message, Serverbound::Move(..)) || moved.insert(*addr)
+    });
+    messages.reverse();
+    messages
+}
+
+/// Strips control characters and surrounding whitespace from player text,
+/// refusing it if empty or longer than `max_len` bytes.
+fn sanitize(text: &str, max_len: usize) -> Option<String> {
+    if text.len() > max_len {
+        return None;
+    }
+    let text = text.chars().filter(|c| !c.is_control()).collect::<String>();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+pub struct Server {
+    clients: HashMap<SocketAddr, Client>,
+    tx: Sender<(SocketAddr, Clientbound)>,
+    rng: StdRng,
+    recipes: Vec<Recipe>,
+    nodes: Vec<LootTable<Vec<ItemStack>>>,
+    next: u64,
+    next_equipment: u64,
+    drops: BTreeMap<DropId, (Vec3, ItemStack)>,
+    next_drop: u64,
+    grid: Grid,
+    tick: Tick,
+    paused: bool,
+}
+
+impl Server {
+    pub fn new(seed: u64, tx: Sender<(SocketAddr, Clientbound)>) -> Self {
+        Self {
+            clients: HashMap::new(),
+            tx,
+            rng: StdRng::seed_from_u64(seed),
+            recipes: data::recipes(),
+            nodes: data::nodes::get(),
+            next: 0,
+            next_equipment: 0,
+            drops: BTreeMap::new(),
+            next_drop: 0,
+            grid: Grid::default(),
+            tick: Tick(0),
+            paused: false,
+        }
+    }
+
+    /// The tick messages are being handled at, for anything rate limited.
+    pub fn set_tick(&mut self, tick: Tick) {
+        self.tick = tick;
+    }
+
+    /// While paused clients can still join, leave and ping, but everything
+    /// else is dropped and moves are answered with the unchanged position.
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused != paused {
+            info!("{}", if paused { "Paused" } else { "Resumed" });
+        }
+        self.paused = paused;
+    }
+
+    pub fn accepts(&self, message: &Serverbound) -> bool {
+        !self.paused
+            || matches!(
+                message,
+                Serverbound::AuthRequest(_) | Serverbound::Disconnect | Serverbound::Ping(_)
+            )
+    }
+
+    /// Tells every client the server is stopping.
+    pub fn shutdown(&self) -> Result<()> {
+        info!("Disconnecting {} clients", self.clients.len());
+        self.broadcast(Clientbound::Shutdown)
+    }
+
+    /// Picks a position on a ring around the origin, cycling through
+    /// `SPAWN_POINTS` slots and jittering so later laps don't stack players.
+    fn spawn_point(rng: &mut StdRng, index: u64) -> Vec3 {
+        let slot = (index % SPAWN_POINTS as u64) as f32;
+        let angle = slot * std::f32::consts::TAU / SPAWN_POINTS as f32;
+        let jitter = Vec3::new(
+            rng.gen_range(-SPAWN_JITTER..SPAWN_JITTER),
+            0.0,
+            rng.gen_range(-SPAWN_JITTER..SPAWN_JITTER),
+        );
+        Vec3::new(angle.cos(), 0.0, angle.sin()) * SPAWN_RADIUS + jitter
+    }
+
+    fn add_client(&mut self, id: ClientId, addr: SocketAddr) -> Result<()> {
+        let position = Self::spawn_point(&mut self.rng, id.0);
+        let client = Client {
+            id,
+            position: Cell::new(position),
+            last_move: Cell::new(None),
+            moved_at: Cell::new(None),
+            speed: Cell::new(PLAYER_SPEED),
+            health: Cell::new(PLAYER_HEALTH),
+            name: RefCell::new(String::new()),
+            chat: RefCell::new(VecDeque::new()),
+            synced_at: Cell::new(None),
+            visible: RefCell::new(BTreeSet::new()),
+            inventory: RefCell::new(Inventory::default()),
+            equipment: RefCell::new(EquipmentInventory(Vec::new())),
+            proficiencies: RefCell::new(Proficiencies::default()),
+        };
+
+        let tx = &self.tx;
+        tx.send((addr, Clientbound::AuthSuccess(id)))?;
+        tx.send((addr, Clientbound::SetSpeed(client.speed.get())))?;
+        tx.send((addr, Clientbound::Spawn(id, position)))?;
+        tx.send((addr, Clientbound::SetHealth(id, client.health.get())))?;
+        self.clients.insert(addr, client);
+        self.grid.place(addr, position);
+        self.update_interest(addr)?;
+        self.send_drops(addr)?;
+        info!("{addr} connected as {id:?}");
+
+        Ok(())
+    }
+
+    /// Sends `to` everything it needs to show `about`.
+    fn introduce(&self, to: SocketAddr, about: &Client) -> Result<()> {
+        self.tx
+            .send((to, Clientbound::Spawn(about.id, about.position.get())))?;
+        self.tx
+            .send((to, Clientbound::SetHealth(about.id, about.health.get())))?;
+        let name = about.name.borrow();
+        if !name.is_empty() {
+            self.tx
+                .send((to, Clientbound::SetName(about.id, name.clone())))?;
+        }
+        Ok(())
+    }
+
+    fn send_drops(&self, addr: SocketAddr) -> Result<()> {
+        self.drops.iter().try_for_each(|(drop, (position, stack))| {
+            self.tx
+                .send((addr, Clientbound::SpawnDrop(*drop, *position, *stack)))
+        })?;
+        Ok(())
+    }
+
+    /// Resends `addr` every player it can see and every drop, as on joining,
+    /// after telling it which ones exist so it can forget the rest.
+    fn sync(&self, addr: SocketAddr) -> Result<()> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        let visible = client.visible.borrow();
+        let synced = Clientbound::Synced(
+            visible.iter().map(|other| self.clients[other].id).collect(),
+            self.drops.keys().copied().collect(),
+        );
+        // Past one datagram the stale state is left to time out instead
+        if bincode::serialized_size(&synced)? <= SYNCED_MAX_LEN {
+            self.tx.send((addr, synced))?;
+        } else {
+            debug!("Too much to reconcile for {addr}, only resending");
+        }
+        visible
+            .iter()
+            .try_for_each(|other| self.introduce(addr, &self.clients[other]))?;
+        self.send_drops(addr)
+    }
+
+    /// After `addr` has moved, introduces it to players who came into range
+    /// and despawns it for those who left, and the other way around.
+    fn update_interest(&self, addr: SocketAddr) -> Result<()> {
+        let client = &self.clients[&addr];
+        let mut nearby = self.grid.nearby(client.position.get());
+        nearby.remove(&addr);
+        let visible = client.visible.replace(nearby.clone());
+        for other_addr in nearby.difference(&visible) {
+            let other = &self.clients[other_addr];
+            self.introduce(addr, other)?;
+            self.introduce(*other_addr, client)?;
+            other.visible.borrow_mut().insert(addr);
+        }
+        for other_addr in visible.difference(&nearby) {
+            let other = &self.clients[other_addr];
+            self.tx.send((addr, Clientbound::Despawn(other.id)))?;
+            self.tx.send((*other_addr, Clientbound::Despawn(client.id)))?;
+            other.visible.borrow_mut().remove(&addr);
+        }
+        Ok(())
+    }
+
+    /// Sends `message` to `addr` and everyone who can see it.
+    fn broadcast_near(&self, addr: SocketAddr, message: Clientbound) -> Result<()> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        self.tx.send((addr, message.clone()))?;
+        client
+            .visible
+            .borrow()
+            .iter()
+            .try_for_each(|other| self.tx.send((*other, message.clone())))?;
+        Ok(())
+    }
+
+    fn broadcast(&self, message: Clientbound) -> Result<()> {
+        self.clients
+            .keys()
+            .try_for_each(|addr| self.tx.send((*addr, message.clone())))?;
+        Ok(())
+    }
+
+    /// Moves a player to a spawn point at full health, telling everyone
+    /// around to snap to the new position.
+    fn respawn(&mut self, addr: SocketAddr) -> Result<()> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        let position = Self::spawn_point(&mut self.rng, client.id.0);
+        client.position.set(position);
+        client.health.set(PLAYER_HEALTH);
+        let id = client.id;
+        self.grid.place(addr, position);
+        self.update_interest(addr)?;
+        self.broadcast_near(addr, Clientbound::Teleport(id, position))?;
+        self.broadcast_near(addr, Clientbound::SetHealth(id, PLAYER_HEALTH))
+    }
+
+    /// Leaves everything a player was carrying on the ground where they stand.
+    fn drop_inventory(&mut self, addr: SocketAddr) -> Result<()> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        let position = client.position.get();
+        let stacks = std::mem::take(&mut *client.inventory.borrow_mut())
+            .items()
+            .collect::<Vec<_>>();
+        for stack in stacks {
+            self.tx.send((addr, Clientbound::SetStack(ItemStack { quantity: 0, ..stack })))?;
+            let id = DropId(self.next_drop);
+            self.next_drop += 1;
+            self.drops.insert(id, (position, stack));
+            self.broadcast(Clientbound::SpawnDrop(id, position, stack))?;
+        }
+        Ok(())
+    }
+
+    /// Moves any drops within `PICKUP_RANGE` of a player into their inventory.
+    fn pickup(&mut self, addr: SocketAddr) -> Result<()> {
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+        let position = client.position.get();
+        let picked = self
+            .drops
+            .iter()
+            .filter(|(_, (at, _))| at.distance(position) <= PICKUP_RANGE)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let mut inventory = client.inventory.borrow_mut();
+        for id in picked {
+            let (_, stack) = self.drops.remove(&id).unwrap();
+            inventory.add(stack);
+            self.tx.send((
+                addr,
+                Clientbound::SetStack(ItemStack {
+                    item: stack.item,
+                    quantity: inventory.get(stack.item).unwrap_or_default(),
+                }),
+            ))?;
+            self.broadcast(Clientbound::DespawnDrop(id))?;
+        }
+        Ok(())
+    }
+
+    pub fn damage(&mut self, id: ClientId, amount: f32) -> Result<()> {
+        let Some((addr, client)) = self.clients.iter().find(|(_, client)| client.id == id) else {
+            return Ok(());
+        };
+        let addr = *addr;
+        let health = client.health.get() - amount;
+        client.health.set(health);
+        if health <= 0.0 {
+            info!("{addr} ({id:?}) died");
+            self.drop_inventory(addr)?;
+            return self.respawn(addr);
+        }
+        self.broadcast_near(addr, Clientbound::SetHealth(id, health))
+    }
+
+    pub fn admin(&mut self, command: Admin) -> Result<()> {
+        match command {
+            Admin::Damage(id, amount) => self.damage(id, amount),
+        }
+    }
+
+    pub fn handle(&mut self, addr: SocketAddr, message: Serverbound) -> Result<()> {
+        if let Serverbound::AuthRequest(version) = message {
+            if version != PROTOCOL_VERSION {
+                warn!("{addr} uses protocol {version}, rejecting");
+                let reason = format!("Server uses protocol {PROTOCOL_VERSION}, not {version}");
+                self.tx.send((addr, Clientbound::Reject(reason)))?;
+                return Ok(());
+            }
+            let id = ClientId(self.next);
+            self.add_client(id, addr)?;
+            self.next += 1;
+        }
+
+        let tx = &self.tx;
+        let Some(client) = self.clients.get(&addr) else {
+            return Ok(());
+        };
+
+        if !self.accepts(&message) {
+            if let Serverbound::Move(_, tick) = message {
+                tx.send((addr, Clientbound::Move(client.id, client.position.get(), tick)))?;
+            }
+            return Ok(());
+        }
+
+        match message {
+            Serverbound::Move(position, tick) => {
+                // Clients stamp moves with the last tick they were sent, a
+                // later one is made up and would make honest moves look stale
+                if tick > self.tick {
+                    warn!("{addr} ({:?}) sent a move from {tick:?} at {:?}", client.id, self.tick);
+                    return Ok(());
+                }
+                // Datagrams can be reordered, and an older move would drag
+                // the player backwards
+                if client.last_move.get().is_some_and(|last| tick < last) {
+                    debug!("{addr} ({:?}) sent a stale move for {tick:?}", client.id);
+                    return Ok(());
+                }
+                // Everyone, including the mover, is told the clamped position
+                // so the client rubber-bands back to it
+                let clamped = client.clamp_move(position, self.tick);
+                if clamped != position {
+                    warn!(
+                        "{addr} ({:?}) moved {} at {tick:?}, clamping",
+                        client.id,
+                        position.distance(client.position.get())
+                    );
+                }
+                let position = clamped;
+                client.last_move.set(Some(tick));
+                client.moved_at.set(Some(self.tick));
+                if position.abs().max_element() > WORLD_BOUNDS {
+                    info!("{addr} ({:?}) left the world, respawning", client.id);
+                    return self.respawn(addr);
+                }
+                client.position.set(position);
+                self.grid.place(addr, position);
+                self.update_interest(addr)?;
+                // Unchanged positions are still sent so watchers don't time the
+                // player out, the interest grid keeps this to nearby players
+                self.broadcast_near(addr, Clientbound::Move(client.id, position, tick))?;
+                return self.pickup(addr);
+            }
+            Serverbound::Gather(index) => {
+                let Some(node) = self.nodes.get(index) else {
+                    return Ok(());
+                };
+                let mut inventory = client.inventory.borrow_mut();
+                node.pick(&mut self.rng).iter().for_each(|stack| {
+                    inventory.add(*stack);
+                    tx.send((
+                        addr,
+                        Clientbound::SetStack(ItemStack {
+                            item: stack.item,
+                            quantity: inventory.get(stack.item).unwrap_or_default(),
+                        }),
+                    ))
+                    .unwrap();
+                })
+            }
+            Serverbound::Craft(index, rarities) => {
+                let Some(recipe) = self.recipes.get(index) else {
+                    return Ok(());
+                };
+                let mut inventory = client.inventory.borrow_mut();
+                let mut equipment = client.equipment.borrow_mut();
+                if !recipe.craftable(&inventory.items().collect::<Vec<_>>(), &rarities) {
+                    return Ok(());
+                }
+                recipe
+                    .inputs
+                    .iter()
+                    .cloned()
+                    .zip(rarities.clone())
+                    .for_each(|((kind, quantity), rarity)| {
+                        let item = Item { kind, rarity };
+                        inventory.remove(ItemStack { item, quantity });
+                        tx.send((
+                            addr,
+                            Clientbound::SetStack(ItemStack {
+                                item,
+                                quantity: inventory.get(item).unwrap_or_default(),
+                            }),
+                        ))
+                        .unwrap();
+                    });
+
+                let tags = recipe.output.tags();
+                let rank_up = client.proficiencies.borrow().rank_up.get(&tags);
+                let chances = recipe.rarity_chances(&rarities, rank_up);
+                let rarity = *RARITIES
+                    .into_iter()
+                    .zip(chances)
+                    .fold(LootTable::default(), |picker, (rarity, chance)| {
+                        picker.add(chance, rarity)
+                    })
+                    .pick(&mut self.rng);
+
+                match recipe.output {
+                    RecipeOutput::Item(kind) => {
+                        let item = Item { kind, rarity };
+                        inventory.add(ItemStack { item, quantity: 1 });
+                        tx.send((
+                            addr,
+                            Clientbound::SetStack(ItemStack {
+                                item,
+                                quantity: inventory.get(item).unwrap_or_default(),
+                            }),
+                        ))?;
+                    }
+                    RecipeOutput::Equipment(kind) => {
+                        let piece = Equipment {
+                            id: EquipmentId(self.next_equipment),
+                            kind,
+                            rarity,
+                            durability: 10,
+                            passives: vec![Passive::Empty; rarity.index() + 1],
+                        };
+                        self.next_equipment += 1;
+                        equipment.0.push(piece.clone());
+                        tx.send((addr, Clientbound::AddEquipment(piece)))?;
+                    }
+                }
+            }
+            Serverbound::Refine(id, reagent) => {
+                let Some(quantity) = client.inventory.borrow().get(reagent) else {
+                    return Ok(());
+                };
+
+                let Some(replacement) = reagent.passive() else {
+                    return Ok(());
+                };
+                let mut equipment = client.equipment.borrow_mut();
+
+                let Some(equipment) = equipment.0.iter_mut().find(|equipment| equipment.id == id)
+                else {
+                    return Ok(());
+                };
+
+                {
+                    let Some(passive) = equipment
+                        .passives
+                        .iter_mut()
+                        .find(|passive| **passive == Passive::Empty)
+                    else {
+                        return Ok(());
+                    };
+                    *passive = replacement;
+                }
+
+                let stack = ItemStack {
+                    item: reagent,
+                    quantity: quantity - 1,
+                };
+                client.inventory.borrow_mut().set(stack);
+                tx.send((addr, Clientbound::SetStack(stack)))?;
+                tx.send((
+                    addr,
+                    Clientbound::SetPassives(id, equipment.passives.clone()),
+                ))?;
+            }
+            Serverbound::SetName(name) => {
+                let Some(name) = sanitize(&name, MAX_NAME_LEN) else {
+                    warn!("{addr} ({:?}) sent an invalid name {name:?}", client.id);
+                    return Ok(());
+                };
+                info!("{addr} ({:?}) is now {name:?}", client.id);
+                *client.name.borrow_mut() = name.clone();
+                self.broadcast_near(addr, Clientbound::SetName(client.id, name))?;
+            }
+            Serverbound::Chat(text) => {
+                let Some(text) = sanitize(&text, MAX_CHAT_LEN) else {
+                    warn!("{addr} ({:?}) sent an invalid chat message", client.id);
+                    return Ok(());
+                };
+                if !client.chat_allowed(self.tick) {
+                    warn!("{addr} ({:?}) is chatting too fast, dropping", client.id);
+                    return Ok(());
+                }
+                let id = client.id;
+                self.broadcast(Clientbound::Chat(id, text))?;
+            }
+            Serverbound::Ping(nonce) => tx.send((addr, Clientbound::Pong(nonce)))?,
+            Serverbound::RequestSync => {
+                let since = client
+                    .synced_at
+                    .get()
+                    .map(|last| self.tick.0.saturating_sub(last.0));
+                if since.is_some_and(|since| since < SYNC_INTERVAL) {
+                    warn!("{addr} ({:?}) is requesting syncs too fast, dropping", client.id);
+                    return Ok(());
+                }
+                client.synced_at.set(Some(self.tick));
+                info!("{addr} ({:?}) requested a sync", client.id);
+                self.sync(addr)?;
+            }
+            Serverbound::Disconnect => {
+                client.visible.borrow().iter().try_for_each(|other_addr| {
+                    self.clients[other_addr].visible.borrow_mut().remove(&addr);
+                    tx.send((*other_addr, Clientbound::Despawn(client.id)))
+                })?;
+                info!("{addr} ({:?}) disconnected", client.id);
+                self.grid.remove(addr);
+                self.clients.remove(&addr);
+            }
+
+            Serverbound::AuthRequest(_) => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use nyx::item::{ItemKind, Rarity};
+
+    use super::*;
+    use crate::interest::{INTEREST_CELL, INTEREST_RADIUS};
+
+    #[test]
+    pub fn test_speed_on_join() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        assert!(rx.try_iter().any(|(to, message)| {
+            to == addr && matches!(message, Clientbound::SetSpeed(speed) if speed == PLAYER_SPEED)
+        }));
+    }
+
+    #[test]
+    pub fn test_clamp_teleport() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let spawn = server.clients[&addr].position.get();
+        rx.try_iter().for_each(drop);
+
+        let step = spawn + Vec3::X * PLAYER_SPEED / TPS;
+        server.set_tick(Tick(1));
+        server.handle(addr, Serverbound::Move(step, Tick(1))).unwrap();
+        assert_eq!(server.clients[&addr].position.get(), step);
+
+        let teleport = step + Vec3::X * 100.0;
+        server.set_tick(Tick(2));
+        server.handle(addr, Serverbound::Move(teleport, Tick(2))).unwrap();
+        let clamped = step + Vec3::X * PLAYER_SPEED / TPS * MOVE_MARGIN;
+        let position = server.clients[&addr].position.get();
+        assert!(position.distance(clamped) < 1e-4);
+        assert!(rx.try_iter().any(|(to, message)| {
+            to == addr && matches!(message, Clientbound::Move(_, at, Tick(2)) if at == position)
+        }));
+    }
+
+    #[test]
+    pub fn test_clamp_skipped_ticks() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let spawn = server.clients[&addr].position.get();
+        rx.try_iter().for_each(drop);
+
+        // Claiming a hundred ticks have passed only buys the one that has
+        server.set_tick(Tick(99));
+        server.handle(addr, Serverbound::Move(spawn, Tick(0))).unwrap();
+        server.set_tick(Tick(100));
+        let far = spawn + Vec3::X * PLAYER_SPEED;
+        server.handle(addr, Serverbound::Move(far, Tick(100))).unwrap();
+        let moved = server.clients[&addr].position.get().distance(spawn);
+        assert!(moved <= PLAYER_SPEED / TPS * MOVE_MARGIN + 1e-4);
+    }
+
+    #[test]
+    pub fn test_deterministic() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let run = || {
+            let (tx, _rx) = unbounded();
+            let mut server = Server::new(7, tx);
+            server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+            let mut claim = server.clients[&addr].position.get();
+            (1..500).for_each(|tick| {
+                // Mix honest steps with overshoots so the clamp's step is exercised
+                let direction = match tick % 5 {
+                    0 => Vec3::X * 3.0,
+                    1 | 2 => Vec3::Z,
+                    3 => Vec3::new(-1.0, 0.0, 1.0),
+                    _ => Vec3::NEG_X * 0.5,
+                };
+                claim += direction * PLAYER_SPEED / TPS;
+                server.set_tick(Tick(tick));
+                server.handle(addr, Serverbound::Move(claim, Tick(tick))).unwrap();
+                claim = server.clients[&addr].position.get();
+            });
+            server.clients[&addr].position.get()
+        };
+        let (a, b) = (run(), run());
+        assert_eq!(a.to_array().map(f32::to_bits), b.to_array().map(f32::to_bits));
+    }
+
+    #[test]
+    pub fn test_paused() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let spawn = server.clients[&first].position.get();
+
+        server.set_tick(Tick(1));
+        server.set_paused(true);
+        let step = spawn + Vec3::X * PLAYER_SPEED / TPS;
+        server.handle(first, Serverbound::Move(step, Tick(1))).unwrap();
+        assert_eq!(server.clients[&first].position.get(), spawn);
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        assert_eq!(server.clients.len(), 2);
+        rx.try_iter().for_each(drop);
+
+        server.set_paused(false);
+        server.handle(first, Serverbound::Move(step, Tick(1))).unwrap();
+        assert_eq!(server.clients[&first].position.get(), step);
+    }
+
+    #[test]
+    pub fn test_respawn_out_of_bounds() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        rx.try_iter().for_each(drop);
+
+        let edge = Vec3::X * (WORLD_BOUNDS - 0.1);
+        server.clients[&addr].position.set(edge);
+        server.set_tick(Tick(1));
+        server
+            .handle(addr, Serverbound::Move(edge + Vec3::X * 0.2, Tick(1)))
+            .unwrap();
+
+        let position = server.clients[&addr].position.get();
+        assert!(position.length() < SPAWN_RADIUS + SPAWN_JITTER * 2.0);
+        assert!(rx.try_iter().any(|(to, message)| {
+            to == addr && matches!(message, Clientbound::Teleport(ClientId(0), at) if at == position)
+        }));
+    }
+
+    #[test]
+    pub fn test_damage() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.clients[&addr].position.set(Vec3::X * 100.0);
+        rx.try_iter().for_each(drop);
+
+        server.damage(ClientId(0), 30.0).unwrap();
+        assert_eq!(server.clients[&addr].health.get(), PLAYER_HEALTH - 30.0);
+        assert!(rx
+            .try_iter()
+            .any(|(_, message)| matches!(message, Clientbound::SetHealth(ClientId(0), health) if health == 70.0)));
+
+        server.damage(ClientId(0), PLAYER_HEALTH).unwrap();
+        assert_eq!(server.clients[&addr].health.get(), PLAYER_HEALTH);
+        assert!(server.clients[&addr].position.get().length() < SPAWN_RADIUS + SPAWN_JITTER * 2.0);
+        assert!(rx
+            .try_iter()
+            .any(|(_, message)| matches!(message, Clientbound::Teleport(ClientId(0), _))));
+    }
+
+    #[test]
+    pub fn test_drops() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let stack = ItemStack {
+            item: Item {
+                kind: ItemKind::CopperOre,
+                rarity: Rarity::Common,
+            },
+            quantity: 3,
+        };
+        server.clients[&first].inventory.borrow_mut().add(stack);
+        let position = server.clients[&first].position.get();
+        server.damage(ClientId(0), PLAYER_HEALTH).unwrap();
+        assert!(server.clients[&first].inventory.borrow().get(stack.item).is_none());
+        rx.try_iter().for_each(drop);
+
+        // Late joiners see both the players and what's lying around
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let messages = rx
+            .try_iter()
+            .filter(|(to, _)| *to == second)
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        assert!(messages
+            .iter()
+            .any(|message| matches!(message, Clientbound::Spawn(ClientId(0), _))));
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            Clientbound::SpawnDrop(_, at, dropped) if *at == position && dropped.quantity == 3
+        )));
+
+        server.clients[&second].position.set(position);
+        server.set_tick(Tick(1));
+        server.handle(second, Serverbound::Move(position, Tick(1))).unwrap();
+        assert_eq!(server.clients[&second].inventory.borrow().get(stack.item), Some(3));
+        assert!(server.drops.is_empty());
+        assert!(rx
+            .try_iter()
+            .any(|(to, message)| to == first && matches!(message, Clientbound::DespawnDrop(_))));
+    }
+
+    #[test]
+    pub fn test_sync() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        // Whatever the second client was told on joining never arrived
+        rx.try_iter().for_each(drop);
+
+        server.handle(second, Serverbound::RequestSync).unwrap();
+        let messages = rx.try_iter().collect::<Vec<_>>();
+        assert!(messages.iter().all(|(to, _)| *to == second));
+        assert!(messages
+            .iter()
+            .any(|(_, message)| matches!(message, Clientbound::Spawn(ClientId(0), _))));
+        assert!(!messages
+            .iter()
+            .any(|(_, message)| matches!(message, Clientbound::Spawn(ClientId(1), _))));
+        assert!(matches!(
+            messages.first(),
+            Some((_, Clientbound::Synced(players, _))) if players == &[ClientId(0)]
+        ));
+
+        // Asking again straight away is ignored, a second later it isn't
+        server.handle(second, Serverbound::RequestSync).unwrap();
+        assert_eq!(rx.try_iter().count(), 0);
+        server.set_tick(Tick(SYNC_INTERVAL));
+        server.handle(second, Serverbound::RequestSync).unwrap();
+        assert!(rx.try_iter().count() > 0);
+    }
+
+    #[test]
+    pub fn test_reject_version() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server
+            .handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION + 1))
+            .unwrap();
+        let messages = rx.try_iter().collect::<Vec<_>>();
+        assert!(matches!(messages.as_slice(), [(_, Clientbound::Reject(_))]));
+        assert!(server.clients.is_empty());
+    }
+
+    #[test]
+    pub fn test_set_name() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server
+            .handle(first, Serverbound::SetName(String::from(" al\u{7}ice\n")))
+            .unwrap();
+        assert_eq!(*server.clients[&first].name.borrow(), "alice");
+        server
+            .handle(first, Serverbound::SetName("x".repeat(MAX_NAME_LEN + 1)))
+            .unwrap();
+        assert_eq!(*server.clients[&first].name.borrow(), "alice");
+        rx.try_iter().for_each(drop);
+
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        assert!(rx.try_iter().any(|(to, message)| {
+            to == second
+                && matches!(message, Clientbound::SetName(ClientId(0), name) if name == "alice")
+        }));
+    }
+
+    #[test]
+    pub fn test_chat() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        rx.try_iter().for_each(drop);
+
+        let chat = |server: &mut Server| {
+            server
+                .handle(first, Serverbound::Chat(String::from("hello")))
+                .unwrap();
+            rx.try_iter().any(|(to, message)| {
+                to == second
+                    && matches!(message, Clientbound::Chat(ClientId(0), text) if text == "hello")
+            })
+        };
+        (0..CHAT_RATE).for_each(|_| assert!(chat(&mut server)));
+        assert!(!chat(&mut server));
+        server.set_tick(Tick(TPS as u64));
+        assert!(chat(&mut server));
+    }
+
+    #[test]
+    pub fn test_ping() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.set_paused(true);
+        rx.try_iter().for_each(drop);
+
+        server.handle(addr, Serverbound::Ping(0xdeadbeef)).unwrap();
+        let messages = rx.try_iter().collect::<Vec<_>>();
+        assert!(matches!(messages.as_slice(), [(to, Clientbound::Pong(0xdeadbeef))] if *to == addr));
+    }
+
+    #[test]
+    pub fn test_interest() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        rx.try_iter().for_each(drop);
+
+        let far = Vec3::X * INTEREST_CELL * (INTEREST_RADIUS + 2) as f32;
+        let teleport = |server: &mut Server, position: Vec3, tick: u64| {
+            server.clients[&second].position.set(position);
+            server.set_tick(Tick(tick));
+            server
+                .handle(second, Serverbound::Move(position, Tick(tick)))
+                .unwrap();
+            rx.try_iter()
+                .filter(|(to, _)| *to == first)
+                .map(|(_, message)| message)
+                .collect::<Vec<_>>()
+        };
+
+        let seen = teleport(&mut server, far, 1);
+        assert!(matches!(seen.as_slice(), [Clientbound::Despawn(ClientId(1))]));
+        assert!(server.clients[&first].visible.borrow().is_empty());
+        assert!(teleport(&mut server, far + Vec3::X, 2).is_empty());
+
+        let seen = teleport(&mut server, Vec3::ZERO, 3);
+        assert!(matches!(seen.first(), Some(Clientbound::Spawn(ClientId(1), _))));
+        assert!(seen
+            .iter()
+            .any(|message| matches!(message, Clientbound::Move(ClientId(1), _, Tick(3)))));
+    }
+
+    #[test]
+    pub fn test_move_near_only() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addrs = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        addrs.iter().for_each(|addr| {
+            server.handle(*addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap()
+        });
+        let far = Vec3::X * INTEREST_CELL * (INTEREST_RADIUS + 2) as f32;
+        server.clients[&addrs[2]].position.set(far);
+        server.set_tick(Tick(1));
+        server.handle(addrs[2], Serverbound::Move(far, Tick(1))).unwrap();
+        rx.try_iter().for_each(drop);
+
+        // Standing still still reaches the neighbour, but not the far player
+        let position = server.clients[&addrs[0]].position.get();
+        server.set_tick(Tick(2));
+        server.handle(addrs[0], Serverbound::Move(position, Tick(2))).unwrap();
+        let moves = rx
+            .try_iter()
+            .filter(|(_, message)| matches!(message, Clientbound::Move(..)))
+            .map(|(to, _)| to)
+            .collect::<HashSet<_>>();
+        assert_eq!(moves, HashSet::from([addrs[0], addrs[1]]));
+    }
+
+    #[test]
+    pub fn test_stale_move() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let spawn = server.clients[&addr].position.get();
+        rx.try_iter().for_each(drop);
+
+        let step = Vec3::X * PLAYER_SPEED / TPS;
+        server.set_tick(Tick(2));
+        server
+            .handle(addr, Serverbound::Move(spawn + step, Tick(2)))
+            .unwrap();
+        server.handle(addr, Serverbound::Move(spawn, Tick(1))).unwrap();
+        assert_eq!(server.clients[&addr].position.get(), spawn + step);
+        assert!(!rx
+            .try_iter()
+            .any(|(_, message)| matches!(message, Clientbound::Move(_, _, Tick(1)))));
+    }
+
+    #[test]
+    pub fn test_future_move() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        server.handle(addr, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        let spawn = server.clients[&addr].position.get();
+        rx.try_iter().for_each(drop);
+
+        let step = spawn + Vec3::X * PLAYER_SPEED / TPS;
+        server.set_tick(Tick(1));
+        server.handle(addr, Serverbound::Move(step, Tick(50))).unwrap();
+        assert_eq!(server.clients[&addr].position.get(), spawn);
+        assert!(server.clients[&addr].last_move.get().is_none());
+
+        server.handle(addr, Serverbound::Move(step, Tick(1))).unwrap();
+        assert_eq!(server.clients[&addr].position.get(), step);
+    }
+
+    #[test]
+    pub fn test_coalesce() {
+        let flooder: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut messages = (0..100)
+            .map(|i| (flooder, Serverbound::Move(Vec3::X * i as f32, Tick(1))))
+            .collect::<Vec<_>>();
+        messages.insert(50, (other, Serverbound::Move(Vec3::Z, Tick(1))));
+        messages.insert(20, (flooder, Serverbound::Gather(0)));
+
+        let coalesced = coalesce(messages)
+            .into_iter()
+            .map(|(addr, message)| match message {
+                Serverbound::Move(position, _) => (addr, Some(position)),
+                _ => (addr, None),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            coalesced,
+            vec![(flooder, None), (other, Some(Vec3::Z)), (flooder, Some(Vec3::X * 99.0))]
+        );
+    }
+
+    #[test]
+    pub fn test_distinct_spawns() {
+        let (tx, rx) = unbounded();
+        let mut server = Server::new(0, tx);
+        let first: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        server.handle(first, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+        server.handle(second, Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
+
+        let spawns = rx
+            .try_iter()
+            .filter_map(|(to, message)| match message {
+                Clientbound::Spawn(_, position) if to == first => Some(position),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spawns.len(), 2);
+        assert!(spawns[0].distance(spawns[1]) > 1.0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Bytes sent and received per message kind, logged and reset every
+/// `REPORT_INTERVAL`.
+pub struct Bandwidth {
+    sent: HashMap<&'static str, Usage>,
+    received: HashMap<&'static str, Usage>,
+    since: Instant,
+}
+
+impl Bandwidth {
+    pub fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+            received: HashMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn sent(&mut self, kind: &'static str, bytes: u64) {
+        self.sent.entry(kind).or_default().add(bytes)
+    }
+
+    pub fn received(&mut self, kind: &'static str, bytes: u64) {
+        self.received.entry(kind).or_default().add(bytes)
+    }
+
+    fn summary(usage: &HashMap<&'static str, Usage>, seconds: f32) -> String {
+        let mut usage = usage.iter().collect::<Vec<_>>();
+        usage.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.bytes));
+        usage
+            .into_iter()
+            .map(|(kind, usage)| {
+                format!(
+                    "{kind}: {} in {}B ({:.0}B/s)",
+                    usage.count,
+                    usage.bytes,
+                    usage.bytes as f32 / seconds
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn report(&mut self) {
+        let elapsed = self.since.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return;
+        }
+        let seconds = elapsed.as_secs_f32();
+        debug!("Sent {}", Self::summary(&self.sent, seconds));
+        debug!("Received {}", Self::summary(&self.received, seconds));
+        self.sent.clear();
+        self.received.clear();
+        self.since = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_bandwidth() {
+        let mut bandwidth = Bandwidth::new();
+        bandwidth.sent("Move", 20);
+        bandwidth.sent("Move", 20);
+        bandwidth.sent("Spawn", 30);
+        bandwidth.received("Move", 24);
+        assert_eq!(bandwidth.sent["Move"], Usage { count: 2, bytes: 40 });
+        assert_eq!(bandwidth.received["Move"], Usage { count: 1, bytes: 24 });
+        assert_eq!(
+            Bandwidth::summary(&bandwidth.sent, 2.0),
+            "Move: 2 in 40B (20B/s), Spawn: 1 in 30B (15B/s)"
+        );
+    }
+}
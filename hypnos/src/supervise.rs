@@ -0,0 +1,77 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, warn};
+
+const BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug)]
+pub enum RestartPolicy {
+    Never,
+    Always,
+    UpTo(u32),
+}
+
+impl RestartPolicy {
+    fn allows(&self, restarts: u32) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            Self::UpTo(n) => restarts < *n,
+        }
+    }
+}
+
+/// Runs `f` until it returns `Ok`, restarting it with backoff when it fails or
+/// panics for as long as `policy` allows. Gives back the last failure.
+pub fn supervise(
+    name: &str,
+    policy: RestartPolicy,
+    mut f: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let mut restarts = 0;
+    let mut backoff = BACKOFF;
+    loop {
+        let e = match panic::catch_unwind(AssertUnwindSafe(&mut f)) {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("{name} panicked"),
+        };
+        if !policy.allows(restarts) {
+            error!("{name} failed, giving up: {e}");
+            return Err(e);
+        }
+        restarts += 1;
+        warn!("{name} failed, restarting in {backoff:?}: {e}");
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flaky(failures: u32) -> impl FnMut() -> Result<()> {
+        let mut runs = 0;
+        move || {
+            runs += 1;
+            match runs {
+                1 => panic!("first run"),
+                n if n <= failures => Err(anyhow!("run {n}")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_supervise() {
+        assert!(supervise("flaky", RestartPolicy::UpTo(3), flaky(2)).is_ok());
+        assert!(supervise("flaky", RestartPolicy::UpTo(1), flaky(2)).is_err());
+        assert!(supervise("flaky", RestartPolicy::Never, flaky(2)).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use glam::Vec3;
+use hypnos::{
+    limit::DEFAULT_RATE,
+    net::{handle_networking, Peers, DEFAULT_TIMEOUT},
+    server::{coalesce, Server},
+};
+use nyx::{
+    movement::integrate,
+    protocol::{
+        Clientbound, ClientboundBundle, Serverbound, Tick, PLAYER_SPEED, PROTOCOL_VERSION, TPS,
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A real server socket and networking thread, with the tick loop stepped by
+/// hand so the test decides when bundles are flushed.
+struct Harness {
+    server: Server,
+    rx: Receiver<(SocketAddr, Serverbound)>,
+    flush_tx: Sender<Tick>,
+    tick: Tick,
+    client: UdpSocket,
+    networking: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let (serverbound_tx, serverbound_rx) = unbounded();
+        let (clientbound_tx, clientbound_rx) = unbounded();
+        let (flush_tx, flush_rx) = unbounded();
+        let networking = std::thread::spawn(|| {
+            handle_networking(
+                socket,
+                &mut Peers::default(),
+                clientbound_rx,
+                flush_rx,
+                serverbound_tx,
+                DEFAULT_TIMEOUT,
+                DEFAULT_RATE,
+            )
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        Self {
+            server: Server::new(0, clientbound_tx),
+            rx: serverbound_rx,
+            flush_tx,
+            tick: Tick(0),
+            client,
+            networking,
+        }
+    }
+
+    fn send(&self, message: Serverbound) {
+        self.client
+            .send(&bincode::serialize(&message).unwrap())
+            .unwrap();
+    }
+
+    fn step(&mut self) {
+        for (addr, message) in coalesce(self.rx.try_iter()) {
+            self.server.handle(addr, message).unwrap();
+        }
+        self.tick.inc();
+        self.server.set_tick(self.tick);
+        self.flush_tx.send(self.tick).unwrap();
+    }
+
+    /// Steps the server until the messages received so far match, or panics
+    /// after `TIMEOUT`. Related messages may be split across bundles.
+    fn expect<T>(&mut self, mut f: impl FnMut(&[Clientbound]) -> Option<T>) -> T {
+        let mut buf = [0; 4096];
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            self.step();
+            let Ok(n) = self.client.recv(&mut buf) else {
+                continue;
+            };
+            let bundle: ClientboundBundle = bincode::deserialize(&buf[0..n]).unwrap();
+            received.extend(bundle.messages);
+            if let Some(found) = f(&received) {
+                return found;
+            }
+        }
+        panic!("Timed out at {:?}", self.tick);
+    }
+}
+
+#[test]
+pub fn test_move_round_trip() {
+    let mut harness = Harness::new();
+
+    harness.send(Serverbound::AuthRequest(PROTOCOL_VERSION));
+    let (id, spawn) = harness.expect(|messages| {
+        let id = messages.iter().find_map(|message| match message {
+            Clientbound::AuthSuccess(id) => Some(*id),
+            _ => None,
+        })?;
+        messages.iter().find_map(|message| match message {
+            Clientbound::Spawn(other, position) if *other == id => Some((id, *position)),
+            _ => None,
+        })
+    });
+
+    let target = integrate(spawn, Vec3::X, PLAYER_SPEED, 1.0 / TPS);
+    harness.send(Serverbound::Move(target, Tick(1)));
+    let moved = harness.expect(|messages| {
+        messages.iter().find_map(|message| match message {
+            Clientbound::Move(other, position, _) if *other == id => Some(*position),
+            _ => None,
+        })
+    });
+    assert_eq!(moved, target);
+    assert_ne!(moved, spawn);
+}
+
+#[test]
+pub fn test_ticks_advance() {
+    let mut harness = Harness::new();
+    harness.send(Serverbound::AuthRequest(PROTOCOL_VERSION));
+
+    let mut buf = [0; 4096];
+    let mut ticks = Vec::new();
+    let start = Instant::now();
+    while ticks.len() < 5 && start.elapsed() < TIMEOUT {
+        harness.step();
+        if let Ok(n) = harness.client.recv(&mut buf) {
+            let bundle: ClientboundBundle = bincode::deserialize(&buf[0..n]).unwrap();
+            ticks.push(bundle.tick);
+        }
+    }
+    assert_eq!(ticks.len(), 5);
+    assert!(ticks.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+pub fn test_shutdown() {
+    let mut harness = Harness::new();
+    harness.send(Serverbound::AuthRequest(PROTOCOL_VERSION));
+    harness.expect(|messages| {
+        messages
+            .iter()
+            .find(|message| matches!(message, Clientbound::AuthSuccess(..)))
+    });
+
+    harness.server.shutdown().unwrap();
+    let Harness {
+        flush_tx,
+        client,
+        networking,
+        ..
+    } = harness;
+    drop(flush_tx);
+    networking.join().unwrap().unwrap();
+
+    // The final flush happens on the way out, with no tick left to trigger it
+    let mut buf = [0; 4096];
+    let mut received = Vec::new();
+    while let Ok(n) = client.recv(&mut buf) {
+        let bundle: ClientboundBundle = bincode::deserialize(&buf[0..n]).unwrap();
+        received.extend(bundle.messages);
+    }
+    assert!(received
+        .iter()
+        .any(|message| matches!(message, Clientbound::Shutdown)));
+}
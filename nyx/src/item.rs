@@ -257,8 +257,7 @@ impl<T> LootTable<T> {
         self
     }
 
-    pub fn pick(&self) -> &T {
-        let mut rng = rand::thread_rng();
+    pub fn pick<R: Rng>(&self, rng: &mut R) -> &T {
         let mut p: f32 = rng.gen();
         self.entries
             .iter()
@@ -273,3 +272,24 @@ impl<T> LootTable<T> {
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    pub fn test_seeded_pick() {
+        let table = LootTable::default()
+            .add(0.5, Rarity::Common)
+            .add(0.5, Rarity::Rare);
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| *table.pick(&mut rng))
+                .collect::<Vec<Rarity>>()
+        };
+        assert_eq!(picks(42), picks(42));
+    }
+}
@@ -1,6 +1,7 @@
 pub mod data;
 pub mod equipment;
 pub mod item;
+pub mod movement;
 pub mod protocol;
 pub mod task;
 
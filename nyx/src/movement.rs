@@ -0,0 +1,24 @@
+use glam::Vec3;
+
+/// Canonical player movement step, shared by client prediction and any server
+/// side simulation so both sides perform the exact same arithmetic.
+///
+/// `direction` is normalised here, so diagonal input is no faster than a
+/// single axis. `dt` is the time in seconds covered by this step: the client
+/// passes its frame delta, the server passes `1.0 / TPS` per tick.
+pub fn integrate(position: Vec3, direction: Vec3, speed: f32, dt: f32) -> Vec3 {
+    position + direction.normalize_or_zero() * (speed * dt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PLAYER_SPEED;
+
+    #[test]
+    pub fn test_diagonal_speed() {
+        let straight = integrate(Vec3::ZERO, Vec3::Z, PLAYER_SPEED, 1.0);
+        let diagonal = integrate(Vec3::ZERO, Vec3::X + Vec3::Z, PLAYER_SPEED, 1.0);
+        assert!((straight.length() - diagonal.length()).abs() < 1e-5);
+    }
+}
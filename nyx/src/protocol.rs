@@ -1,13 +1,34 @@
+use bincode::Options;
 use glam::Vec3;
+use serde::de::DeserializeOwned;
 
 use crate::{equipment::{Equipment, EquipmentId, Passive}, item::{Item, ItemStack, Rarity}};
 
+/// Bumped whenever a message changes shape, so mismatched clients are
+/// turned away instead of misreading each other
+pub const PROTOCOL_VERSION: u32 = 6;
 pub const TPS: f32 = 20.0;
+/// Largest datagram either side will send or decode, in bytes
+pub const MAX_MESSAGE_LEN: usize = 4096;
+pub const PLAYER_SPEED: f32 = 5.0;
+pub const PLAYER_HEALTH: f32 = 100.0;
+/// How close a player has to walk to a drop to pick it up
+pub const PICKUP_RANGE: f32 = 1.0;
+/// Longest player name the server accepts, in bytes
+pub const MAX_NAME_LEN: usize = 32;
+/// Longest chat message the server accepts, in bytes
+pub const MAX_CHAT_LEN: usize = 256;
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct ClientId(pub u64);
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[derive(
+    Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
 pub struct Tick(pub u64);
+#[derive(
+    Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+pub struct DropId(pub u64);
 
 impl Tick {
     pub fn inc(&mut self) {
@@ -18,22 +39,93 @@ impl Tick {
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub enum Clientbound {
     AuthSuccess(ClientId),
+    /// Authentication refused, with the reason
+    Reject(String),
     Spawn(ClientId, Vec3),
     Despawn(ClientId),
+    /// Sent for every accepted move, even one that stays put, to the mover and
+    /// the players who can see it. Clients despawn players they stop hearing
+    /// from, so this doubles as a keep-alive.
     Move(ClientId, Vec3, Tick),
     SetStack(ItemStack),
     AddEquipment(Equipment),
-    SetPassives(EquipmentId, Vec<Passive>)
+    SetPassives(EquipmentId, Vec<Passive>),
+    SetSpeed(f32),
+    /// An authoritative jump, e.g. a respawn, which clients should snap to
+    /// rather than smooth over
+    Teleport(ClientId, Vec3),
+    SetHealth(ClientId, f32),
+    SetName(ClientId, String),
+    Chat(ClientId, String),
+    /// Echoes the nonce of a `Serverbound::Ping`
+    Pong(u64),
+    /// The server is going away, clients should stop rather than wait on it
+    Shutdown,
+    /// An item stack lying in the world
+    SpawnDrop(DropId, Vec3, ItemStack),
+    DespawnDrop(DropId),
+    /// Sent ahead of a sync's spawns with every player and drop the client
+    /// should know about, anything else it has is stale
+    Synced(Vec<ClientId>, Vec<DropId>),
+}
+
+impl Clientbound {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::AuthSuccess(..) => "AuthSuccess",
+            Self::Reject(..) => "Reject",
+            Self::Spawn(..) => "Spawn",
+            Self::Despawn(..) => "Despawn",
+            Self::Move(..) => "Move",
+            Self::SetStack(..) => "SetStack",
+            Self::AddEquipment(..) => "AddEquipment",
+            Self::SetPassives(..) => "SetPassives",
+            Self::SetSpeed(..) => "SetSpeed",
+            Self::Teleport(..) => "Teleport",
+            Self::SetHealth(..) => "SetHealth",
+            Self::SetName(..) => "SetName",
+            Self::Chat(..) => "Chat",
+            Self::Pong(..) => "Pong",
+            Self::Shutdown => "Shutdown",
+            Self::SpawnDrop(..) => "SpawnDrop",
+            Self::DespawnDrop(..) => "DespawnDrop",
+            Self::Synced(..) => "Synced",
+        }
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub enum Serverbound {
-    AuthRequest,
+    /// Carries the client's `PROTOCOL_VERSION`
+    AuthRequest(u32),
     Move(Vec3, Tick),
     Disconnect,
     Craft(usize, Vec<Rarity>),
     Gather(usize),
-    Refine(EquipmentId, Item)
+    Refine(EquipmentId, Item),
+    SetName(String),
+    Chat(String),
+    Ping(u64),
+    /// Asks for everything a newly joined client would be sent, for when the
+    /// client notices it has missed something
+    RequestSync,
+}
+
+impl Serverbound {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::AuthRequest(..) => "AuthRequest",
+            Self::Move(..) => "Move",
+            Self::Disconnect => "Disconnect",
+            Self::Craft(..) => "Craft",
+            Self::Gather(..) => "Gather",
+            Self::Refine(..) => "Refine",
+            Self::SetName(..) => "SetName",
+            Self::Chat(..) => "Chat",
+            Self::Ping(..) => "Ping",
+            Self::RequestSync => "RequestSync",
+        }
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -41,3 +133,32 @@ pub struct ClientboundBundle {
     pub tick: Tick,
     pub messages: Vec<Clientbound>
 }
+
+/// Decodes a datagram, refusing anything that claims to be larger than
+/// `MAX_MESSAGE_LEN` instead of allocating for it.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    // Same encoding as `bincode::serialize`, plus the limit
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_LEN as u64)
+        .deserialize(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_decode_oversized() {
+        let message = Serverbound::Chat(String::from("hello"));
+        let bytes = bincode::serialize(&message).unwrap();
+        assert!(matches!(decode(&bytes), Ok(Serverbound::Chat(text)) if text == "hello"));
+
+        // Claim a string of u64::MAX bytes after the variant tag
+        let mut bytes = bincode::serialize(&message).unwrap();
+        bytes[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        let error = decode::<Serverbound>(&bytes).unwrap_err();
+        assert!(matches!(*error, bincode::ErrorKind::SizeLimit));
+    }
+}
@@ -39,6 +39,26 @@ impl<T: Element> Element for Container<T> {
     }
 }
 
+pub struct Rect {
+    pub size: Vec2,
+    pub colour: Vec4,
+    pub radius: f32,
+}
+
+impl Element for Rect {
+    fn layout(&mut self, constraint: Constraint<Vec2>) -> Vec2 {
+        self.size.min(constraint.max)
+    }
+
+    fn paint(&mut self, area: Area, scene: &mut Scene, _: &[Event], _: &mut Signals) {
+        scene.rectangle(Rectangle {
+            area,
+            colour: self.colour,
+            radius: self.radius,
+        });
+    }
+}
+
 pub struct Text {
     pub text: String,
     pub font: Rc<Font>,
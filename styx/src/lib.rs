@@ -2,7 +2,7 @@ pub mod components;
 
 pub use fontdue::{Font, FontSettings};
 
-use std::{collections::HashMap, mem::size_of, rc::Rc};
+use std::{collections::HashMap, mem::size_of, path::Path, rc::Rc};
 
 use anyhow::Result;
 use etagere::Size;
@@ -415,12 +415,19 @@ pub struct Frame {
 }
 
 impl Renderer {
-    pub fn new(ctx: &Context, render_pass: &RenderPass, subpass: usize) -> Result<Self> {
+    /// `shaders` is the directory holding the compiled UI shaders
+    pub fn new(
+        ctx: &Context,
+        shaders: &Path,
+        render_pass: &RenderPass,
+        subpass: usize,
+        samples: SampleCountFlags,
+    ) -> Result<Self> {
         let ui_vertex =
-            ShaderModule::new(&ctx.device, &std::fs::read("assets/shaders/ui.vert.spv")?)?;
+            ShaderModule::new(&ctx.device, &std::fs::read(shaders.join("ui.vert.spv"))?)?;
 
         let ui_fragment =
-            ShaderModule::new(&ctx.device, &std::fs::read("assets/shaders/ui.frag.spv")?)?;
+            ShaderModule::new(&ctx.device, &std::fs::read(shaders.join("ui.frag.spv"))?)?;
 
         let layout = descriptor::Layout::new(
             ctx,
@@ -440,7 +447,7 @@ impl Renderer {
             .subpass(subpass as u32)
             .viewport(Viewport::Dynamic)
             .layouts(vec![&layout])
-            .multisampled(ctx.device.physical.get_samples())
+            .multisampled(samples)
             .build(&ctx.device)?;
 
         Ok(Self { pipeline, layout })
@@ -548,7 +555,7 @@ impl Renderer {
             .write_buffer(0, &rectangle_buffer)
             .write_buffer(1, &viewport_buffer)
             .write_image(2, &view, &sampler, ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .finish();
+            .finish()?;
 
         Ok(Frame {
             vertex_buffer,
@@ -560,7 +567,11 @@ impl Renderer {
         })
     }
 
-    pub fn draw<'a>(&'a self, frame: Frame, cmd: command::Recorder<'a>) -> command::Recorder<'a> {
+    pub fn draw<'a>(
+        &'a self,
+        frame: Frame,
+        cmd: command::Recorder<'a>,
+    ) -> Result<command::Recorder<'a>> {
         let set = Rc::into_inner(frame.set)
             .unwrap()
             .write_image(
@@ -569,14 +580,15 @@ impl Renderer {
                 &frame.sampler,
                 ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             )
-            .finish();
+            .finish()?;
 
-        cmd.next_subpass()
+        Ok(cmd
+            .next_subpass()
             .bind_graphics_pipeline(&self.pipeline)
             .bind_vertex_buffer(&frame.vertex_buffer, 0)
             .bind_index_buffer(&frame.index_buffer)
             .bind_descriptor_set(&set, 0)
-            .draw_indexed(frame.num_indices, 1, 0, 0, 0)
+            .draw_indexed(frame.num_indices, 1, 0, 0, 0))
     }
 }
 
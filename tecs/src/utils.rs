@@ -48,6 +48,11 @@ impl Clock {
             .with_ticker(Self::tick)
     }
 
+    /// Time from the clock starting to the latest tick
+    pub fn elapsed(&self) -> Duration {
+        self.last - self.start
+    }
+
     pub fn tick<E>(world: &World<E>) {
         let mut clock = world.get_mut::<Clock>().unwrap();
         let now = Instant::now();
@@ -70,3 +75,58 @@ impl Display for Name {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate as tecs;
+    use crate::Is;
+    use tecs_derive::Archetype;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Position(u32);
+
+    #[derive(Archetype, Clone)]
+    struct Projectile {
+        position: Position,
+        velocity: u32,
+    }
+
+    #[derive(Archetype, Clone)]
+    struct Pickup {
+        position: Position,
+        item: usize,
+    }
+
+    fn world() -> World<()> {
+        World::new()
+            .register_unsaved::<Projectile>()
+            .register_unsaved::<Pickup>()
+    }
+
+    #[test]
+    pub fn test_shared_column() {
+        let world = world();
+        world.spawn(Projectile {
+            position: Position(0),
+            velocity: 3,
+        });
+        world.spawn(Pickup {
+            position: Position(1),
+            item: 5,
+        });
+        world.spawn(Pickup {
+            position: Position(2),
+            item: 6,
+        });
+
+        let (positions,) = world.query::<(&Position,)>();
+        let mut positions = positions.iter().map(|position| position.0).collect::<Vec<_>>();
+        positions.sort();
+        assert_eq!(positions, [0, 1, 2]);
+
+        let (positions, _) = world.query::<(&Position, Is<Pickup>)>();
+        assert_eq!(positions.iter().count(), 2);
+    }
+}
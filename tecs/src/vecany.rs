@@ -1,10 +1,48 @@
-use std::any::TypeId;
+use std::{any::TypeId, collections::TryReserveError, mem::ManuallyDrop};
 
+/// Type-erased `Vec`. The buffer is only ever allocated by a `Vec<T>` of the stored
+/// type, so slices handed out by `downcast_ref`/`downcast_mut` are aligned for `T`.
 pub struct VecAny {
     ptr: Option<*mut ()>,
     len: usize,
     cap: usize,
     ty: TypeId,
+    drop: Option<unsafe fn(*mut (), usize, usize)>,
+}
+
+unsafe fn drop_raw<T>(ptr: *mut (), len: usize, cap: usize) {
+    drop(Vec::<T>::from_raw_parts(ptr.cast(), len, cap))
+}
+
+#[derive(Debug)]
+pub enum VecAnyError {
+    /// The `VecAny` holds a different type than the one asked for
+    WrongType,
+    Reserve(TryReserveError),
+}
+
+impl From<TryReserveError> for VecAnyError {
+    fn from(e: TryReserveError) -> Self {
+        Self::Reserve(e)
+    }
+}
+
+/// Hands the `Vec` borrowed by `run` back to its `VecAny` when dropped, even
+/// if the closure panics, so the elements are only ever owned once.
+struct Guard<'a, T> {
+    vecany: &'a mut VecAny,
+    data: ManuallyDrop<Vec<T>>,
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        let data = unsafe { ManuallyDrop::take(&mut self.data) };
+        let (ptr, len, cap) = data.into_raw_parts();
+        self.vecany.ptr = Some(ptr.cast());
+        self.vecany.len = len;
+        self.vecany.cap = cap;
+        self.vecany.drop = Some(drop_raw::<T>);
+    }
 }
 
 impl VecAny {
@@ -18,6 +56,7 @@ impl VecAny {
             len: 0,
             cap: 0,
             ty,
+            drop: None,
         }
     }
 
@@ -28,24 +67,34 @@ impl VecAny {
             len,
             cap,
             ty: TypeId::of::<T>(),
+            drop: Some(drop_raw::<T>),
         }
     }
 
-    pub fn run<T: 'static>(&mut self, f: impl FnOnce(&mut Vec<T>)) {
+    pub fn run<T: 'static, R>(&mut self, f: impl FnOnce(&mut Vec<T>) -> R) -> Option<R> {
         if self.ty != TypeId::of::<T>() {
-            return;
+            return None;
         }
 
-        let mut data: Vec<T> = match self.ptr {
+        let data: Vec<T> = match self.ptr {
             Some(ptr) => unsafe { Vec::<T>::from_raw_parts(ptr.cast(), self.len, self.cap) },
             None => Vec::<T>::new(),
         };
-        f(&mut data);
+        let mut guard = Guard {
+            vecany: self,
+            data: ManuallyDrop::new(data),
+        };
+        Some(f(&mut guard.data))
+    }
 
-        let (ptr, len, cap) = data.into_raw_parts();
-        self.ptr = Some(ptr.cast());
-        self.len = len;
-        self.cap = cap;
+    fn aligned<T>(ptr: *mut ()) -> *mut T {
+        let ptr = ptr.cast::<T>();
+        assert!(
+            ptr.is_aligned(),
+            "VecAny buffer is not aligned for {}",
+            std::any::type_name::<T>()
+        );
+        ptr
     }
 
     pub fn downcast_ref<T: 'static>(&self) -> Option<&[T]> {
@@ -54,7 +103,7 @@ impl VecAny {
         }
 
         Some(match self.ptr {
-            Some(ptr) => unsafe { std::slice::from_raw_parts(ptr.cast(), self.len) },
+            Some(ptr) => unsafe { std::slice::from_raw_parts(Self::aligned(ptr), self.len) },
             None => &[],
         })
     }
@@ -65,13 +114,25 @@ impl VecAny {
         }
 
         Some(match self.ptr {
-            Some(ptr) => unsafe { std::slice::from_raw_parts_mut(ptr.cast(), self.len) },
+            Some(ptr) => unsafe { std::slice::from_raw_parts_mut(Self::aligned(ptr), self.len) },
             None => &mut [],
         })
     }
 
     pub fn push<T: 'static>(&mut self, item: T) {
-        self.run(|data| data.push(item))
+        self.run(|data| data.push(item));
+    }
+
+    pub fn try_reserve<T: 'static>(&mut self, additional: usize) -> Result<(), VecAnyError> {
+        self.run(|data: &mut Vec<T>| data.try_reserve(additional))
+            .ok_or(VecAnyError::WrongType)??;
+        Ok(())
+    }
+
+    pub fn try_push<T: 'static>(&mut self, item: T) -> Result<(), VecAnyError> {
+        self.try_reserve::<T>(1)?;
+        self.push(item);
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -87,6 +148,14 @@ impl VecAny {
     }
 }
 
+impl Drop for VecAny {
+    fn drop(&mut self) {
+        if let (Some(ptr), Some(drop)) = (self.ptr, self.drop) {
+            unsafe { drop(ptr, self.len, self.cap) }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,4 +181,62 @@ mod tests {
         assert_eq!(Some(data.as_slice()), vecany.downcast_ref::<usize>());
         assert_eq!(Some(data.as_mut_slice()), vecany.downcast_mut::<usize>());
     }
+
+    #[test]
+    pub fn test_reserve_failure() {
+        let mut vecany = VecAny::new::<usize>();
+        vecany.push(0_usize);
+        assert!(vecany.try_reserve::<usize>(usize::MAX).is_err());
+        assert_eq!(vecany.len(), 1);
+        vecany.try_push(1_usize).unwrap();
+        assert_eq!(Some([0, 1].as_slice()), vecany.downcast_ref::<usize>());
+    }
+
+    #[test]
+    pub fn test_push_wrong_type() {
+        let mut vecany = VecAny::new::<usize>();
+        assert!(matches!(vecany.try_push(0_u8), Err(VecAnyError::WrongType)));
+        assert!(vecany.is_empty());
+    }
+
+    #[test]
+    pub fn test_drop() {
+        let item = std::rc::Rc::new(0_usize);
+        let mut vecany = VecAny::new_uninit(TypeId::of::<std::rc::Rc<usize>>());
+        (0..10).for_each(|_| vecany.push(item.clone()));
+        assert_eq!(std::rc::Rc::strong_count(&item), 11);
+        drop(vecany);
+        assert_eq!(std::rc::Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    pub fn test_panic_in_run() {
+        let item = std::rc::Rc::new(0_usize);
+        let mut vecany = VecAny::new::<std::rc::Rc<usize>>();
+        (0..3).for_each(|_| vecany.push(item.clone()));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            vecany.run(|data: &mut Vec<std::rc::Rc<usize>>| {
+                data.push(item.clone());
+                panic!("inside run");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(vecany.len(), 4);
+        assert_eq!(std::rc::Rc::strong_count(&item), 5);
+        drop(vecany);
+        assert_eq!(std::rc::Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    pub fn test_alignment() {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[repr(align(16))]
+        struct Aligned(u8);
+
+        let mut vecany = VecAny::new_uninit(TypeId::of::<Aligned>());
+        (0..5).for_each(|i| vecany.push(Aligned(i)));
+        let data = vecany.downcast_ref::<Aligned>().unwrap();
+        assert_eq!(data[1..3], [Aligned(1), Aligned(2)]);
+        assert!(data[1..].as_ptr().is_aligned());
+    }
 }
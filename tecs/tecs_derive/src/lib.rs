@@ -36,7 +36,7 @@ pub fn derive_answer_fn(input: TokenStream) -> TokenStream {
                 table.length.set(table.length.get() - 1);
                 let mut columns = table.columns_mut();
                 #(
-                    columns.next().unwrap().data.run::<#types>(|data| { data.swap_remove(row.0 as usize); });
+                    columns.next().unwrap().data.run::<#types, _>(|data| { data.swap_remove(row.0 as usize); });
                 )*
             }
 
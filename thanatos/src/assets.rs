@@ -1,62 +1,297 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
-use glam::{Vec3, Vec4};
+use anyhow::{anyhow, bail, Result};
+use glam::{Mat4, Vec2, Vec3, Vec4};
 use gltf::Glb;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
-use crate::renderer::Vertex;
+use crate::{renderer::Vertex, skeleton::Skeleton};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Self {
+        points.into_iter().fold(
+            Self {
+                min: Vec3::INFINITY,
+                max: Vec3::NEG_INFINITY,
+            },
+            |aabb, point| Self {
+                min: aabb.min.min(point),
+                max: aabb.max.max(point),
+            },
+        )
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Vec3::select(
+                glam::BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
+                self.max,
+                self.min,
+            )
+        })
+    }
+
+    pub fn transform(&self, matrix: Mat4) -> Self {
+        Self::from_points(
+            self.corners()
+                .map(|corner| matrix.transform_point3(corner)),
+        )
+    }
+}
 
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
     pub num_indices: u32,
+    pub aabb: Aabb,
+    /// The glTF material asked for alpha blending
+    pub transparent: bool,
+    /// Poses the vertices if the mesh is skinned
+    pub skeleton: Option<Skeleton>,
+}
+
+/// Per-vertex tangents for a triangle list, accumulated from each triangle's
+/// UV gradients and orthogonalised against the normal. `w` holds the
+/// handedness of the bitangent, as in glTF's `TANGENT` attribute.
+pub fn generate_tangents(
+    positions: &[Vec3],
+    normals: &[Vec3],
+    uvs: &[Vec2],
+    indices: &[u32],
+) -> Vec<[f32; 4]> {
+    let mut tangents = vec![Vec3::ZERO; positions.len()];
+    let mut bitangents = vec![Vec3::ZERO; positions.len()];
+
+    indices.chunks_exact(3).for_each(|triangle| {
+        let [a, b, c] = [0, 1, 2].map(|i| triangle[i] as usize);
+        let e1 = positions[b] - positions[a];
+        let e2 = positions[c] - positions[a];
+        let d1 = uvs[b] - uvs[a];
+        let d2 = uvs[c] - uvs[a];
+
+        let det = d1.x * d2.y - d2.x * d1.y;
+        if det.abs() < f32::EPSILON {
+            return;
+        }
+        let tangent = (e1 * d2.y - e2 * d1.y) / det;
+        let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+        [a, b, c].into_iter().for_each(|i| {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        });
+    });
+
+    normals
+        .iter()
+        .zip(tangents)
+        .zip(bitangents)
+        .map(|((normal, tangent), bitangent)| {
+            let tangent = (tangent - *normal * normal.dot(tangent)).normalize_or_zero();
+            let handedness = if normal.cross(tangent).dot(bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            tangent.extend(handedness).to_array()
+        })
+        .collect()
 }
 
+/// Used in place of any mesh that can't be found or read, so the client
+/// still runs without an assets directory or with a broken model.
+const FALLBACK_MESH: &[u8] = include_bytes!("../../assets/meshes/cube.glb");
+
 impl Mesh {
     pub fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
-        let model = Glb::load(&std::fs::read(path).unwrap()).unwrap();
+        Self::from_glb(&std::fs::read(path)?)
+    }
 
-        let positions: Vec<Vec3> = bytemuck::cast_slice::<u8, f32>(
-            &model.gltf.meshes[0].primitives[0]
-                .get_attribute_data(&model, "POSITION")
-                .unwrap(),
-        )
-        .chunks(3)
-        .map(Vec3::from_slice)
-        .collect();
-
-        let normals: Vec<Vec3> = bytemuck::cast_slice::<u8, f32>(
-            &model.gltf.meshes[0].primitives[0]
-                .get_attribute_data(&model, "NORMAL")
-                .unwrap(),
-        )
-        .chunks(3)
-        .map(Vec3::from_slice)
-        .collect();
+    pub fn from_glb(data: &[u8]) -> Result<Self> {
+        let model = Glb::load(data)?;
+        let primitive = model
+            .gltf
+            .meshes
+            .first()
+            .and_then(|mesh| mesh.primitives.first())
+            .ok_or_else(|| anyhow!("No mesh primitives"))?;
+        let attribute = |name: &str| primitive.get_attribute_f32s(&model, name);
+
+        let positions: Vec<Vec3> = attribute("POSITION")?
+            .ok_or_else(|| anyhow!("No POSITION attribute"))?
+            .chunks_exact(3)
+            .map(Vec3::from_slice)
+            .collect();
+
+        let normals: Vec<Vec3> = attribute("NORMAL")?
+            .ok_or_else(|| anyhow!("No NORMAL attribute"))?
+            .chunks_exact(3)
+            .map(Vec3::from_slice)
+            .collect();
+        if normals.len() != positions.len() {
+            bail!("{} normals for {} positions", normals.len(), positions.len());
+        }
+
+        let indices: Vec<u32> = primitive
+            .get_indices_data(&model)?
+            .ok_or_else(|| anyhow!("No indices"))?;
+        if let Some(index) = indices.iter().find(|index| **index as usize >= positions.len()) {
+            bail!("Index {index} is past {} vertices", positions.len());
+        }
+
+        let tangents: Vec<[f32; 4]> = match (attribute("TANGENT")?, attribute("TEXCOORD_0")?) {
+            (Some(tangents), _) => tangents
+                .chunks_exact(4)
+                .map(|tangent| [tangent[0], tangent[1], tangent[2], tangent[3]])
+                .collect(),
+            (None, Some(uvs)) => {
+                let uvs = uvs.chunks_exact(2).map(Vec2::from_slice).collect::<Vec<_>>();
+                if uvs.len() != positions.len() {
+                    bail!("{} UVs for {} positions", uvs.len(), positions.len());
+                }
+                generate_tangents(&positions, &normals, &uvs, &indices)
+            }
+            (None, None) => vec![[0.0; 4]; positions.len()],
+        };
+        if tangents.len() != positions.len() {
+            bail!("{} tangents for {} positions", tangents.len(), positions.len());
+        }
+
+        let skin = match (attribute("WEIGHTS_0")?, model.gltf.skins.first()) {
+            (Some(weights), Some(skin)) => {
+                let joints = primitive
+                    .get_attribute_u32s(&model, "JOINTS_0")?
+                    .ok_or_else(|| anyhow!("WEIGHTS_0 without JOINTS_0"))?;
+                if joints.len() != positions.len() * 4 || weights.len() != positions.len() * 4 {
+                    bail!(
+                        "{} joints and {} weights for {} positions",
+                        joints.len() / 4,
+                        weights.len() / 4,
+                        positions.len()
+                    );
+                }
+                let count = skin.joints.len();
+                if let Some(joint) = joints.iter().find(|joint| **joint as usize >= count) {
+                    bail!("Joint {joint} is past {count} joints");
+                }
+                let skin = joints
+                    .chunks_exact(4)
+                    .zip(weights.chunks_exact(4))
+                    .map(|(joints, weights)| {
+                        (
+                            [joints[0], joints[1], joints[2], joints[3]],
+                            [weights[0], weights[1], weights[2], weights[3]],
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                Some(skin)
+            }
+            _ => None,
+        };
+        let skinned = skin.is_some();
+        // Unskinned vertices are bound to joint 0, the identity
+        let skin = skin.unwrap_or_else(|| vec![([0; 4], [1.0, 0.0, 0.0, 0.0]); positions.len()]);
+
+        let aabb = Aabb::from_points(positions.iter().copied());
+
+        let transparent = primitive
+            .material
+            .and_then(|material| model.gltf.materials.get(material))
+            .and_then(|material| material.alpha_mode.as_deref())
+            == Some("BLEND");
 
         let vertices: Vec<Vertex> = positions
             .into_iter()
             .zip(normals)
-            .map(|(position, normal)| Vertex { position, normal })
+            .zip(tangents)
+            .zip(skin)
+            .map(|(((position, normal), tangent), (joints, weights))| Vertex {
+                position,
+                normal,
+                tangent,
+                joints,
+                weights,
+            })
             .collect();
 
-        let indices: Vec<u32> = model.gltf.meshes[0].primitives[0]
-            .get_indices_data(&model)
-            .unwrap();
-
         Ok(Mesh {
             vertices,
             num_indices: indices.len() as u32,
             indices,
+            aabb,
+            transparent,
+            skeleton: if skinned { Skeleton::from_glb(model)? } else { None },
         })
     }
+
+    pub fn aabb(&self) -> Aabb {
+        self.aabb
+    }
 }
 
+// Must match the std430 layout of `Material` in shader.frag.glsl:
+//   0   colour     vec4
+//   16  emissive   vec4
+//   32  metallic   float
+//   36  roughness  float
+//   40  padding, struct size rounded up to its 16 byte alignment
 #[repr(C)]
-#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable, Serialize, Deserialize)]
 pub struct Material {
     pub colour: Vec4,
+    #[serde(default)]
+    pub emissive: Vec4,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "Material::default_roughness")]
+    pub roughness: f32,
+    #[serde(skip)]
+    _padding: [f32; 2],
+}
+
+const _: () = assert!(std::mem::size_of::<Material>() == 48);
+const _: () = assert!(std::mem::offset_of!(Material, emissive) == 16);
+const _: () = assert!(std::mem::offset_of!(Material, metallic) == 32);
+const _: () = assert!(std::mem::offset_of!(Material, roughness) == 36);
+
+impl Material {
+    pub fn new(colour: Vec4) -> Self {
+        Self {
+            colour,
+            emissive: Vec4::ZERO,
+            metallic: 0.0,
+            roughness: Self::default_roughness(),
+            _padding: [0.0; 2],
+        }
+    }
+
+    pub fn with_metallic(mut self, metallic: f32) -> Self {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn with_emissive(mut self, emissive: Vec3) -> Self {
+        self.emissive = emissive.extend(0.0);
+        self
+    }
+
+    fn default_roughness() -> f32 {
+        1.0
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
@@ -68,15 +303,310 @@ impl AsRef<Path> for MeshId {
     }
 }
 
+/// Root of the assets directory. Paths in scenes are written relative to the
+/// repository as `assets/...`, so that prefix is swapped for the root.
+#[derive(Clone, Debug)]
+pub struct Assets {
+    root: PathBuf,
+}
+
+impl Assets {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        self.root.join(path.strip_prefix("assets").unwrap_or(path))
+    }
+
+    /// Reads the file at `path`, resolved against the root
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let path = self.resolve(path);
+        std::fs::read(&path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))
+    }
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Self::new("assets")
+    }
+}
+
 #[derive(Default)]
-pub struct MeshCache(HashMap<MeshId, Mesh>);
+pub struct MeshCache {
+    assets: Assets,
+    meshes: HashMap<MeshId, Mesh>,
+}
 
 impl MeshCache {
+    pub fn new(assets: Assets) -> Self {
+        Self {
+            assets,
+            meshes: HashMap::new(),
+        }
+    }
+
     pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<&Mesh> {
         let id = MeshId(path.as_ref().to_str().unwrap().to_owned());
-        if self.0.get(&id).is_none() {
-            self.0.insert(id.clone(), Mesh::load(path)?);
+        if self.meshes.get(&id).is_none() {
+            let resolved = self.assets.resolve(&path);
+            let mesh = match Mesh::load(&resolved) {
+                Ok(mesh) => mesh,
+                Err(e) => {
+                    warn!("Failed to load {}, using fallback: {e}", resolved.display());
+                    Mesh::from_glb(FALLBACK_MESH)?
+                }
+            };
+            self.meshes.insert(id.clone(), mesh);
         }
-        Ok(self.0.get(&id).unwrap())
+        Ok(self.meshes.get(&id).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+
+    use super::*;
+
+    #[test]
+    pub fn test_aabb_transform() {
+        let cube = Aabb::from_points(
+            Aabb {
+                min: Vec3::splat(-1.0),
+                max: Vec3::ONE,
+            }
+            .corners(),
+        );
+        assert_eq!(cube.min, Vec3::splat(-1.0));
+        assert_eq!(cube.max, Vec3::ONE);
+
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 1.0, 1.0),
+            Quat::IDENTITY,
+            Vec3::new(0.0, 5.0, 0.0),
+        );
+        let moved = cube.transform(matrix);
+        assert_eq!(moved.min, Vec3::new(-2.0, 4.0, -1.0));
+        assert_eq!(moved.max, Vec3::new(2.0, 6.0, 1.0));
+    }
+
+    #[test]
+    pub fn test_assets_dir() {
+        let root = std::env::temp_dir().join(format!("thanatos-assets-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("meshes")).unwrap();
+        std::fs::write(root.join("meshes/custom.glb"), FALLBACK_MESH).unwrap();
+
+        let assets = Assets::new(&root);
+        assert_eq!(
+            assets.resolve("assets/meshes/custom.glb"),
+            root.join("meshes/custom.glb")
+        );
+        assert_eq!(assets.read("meshes/custom.glb").unwrap(), FALLBACK_MESH);
+        assert!(assets.read("meshes/missing.glb").is_err());
+
+        let fallback = Mesh::from_glb(FALLBACK_MESH).unwrap();
+        let mut cache = MeshCache::new(assets);
+        let custom = cache.load("assets/meshes/custom.glb").unwrap();
+        assert_eq!(custom.vertices, fallback.vertices);
+        let missing = cache.load("assets/meshes/missing.glb").unwrap();
+        assert_eq!(missing.indices, fallback.indices);
+
+        let mut corrupt = FALLBACK_MESH.to_vec();
+        corrupt.truncate(corrupt.len() / 2);
+        std::fs::write(root.join("meshes/corrupt.glb"), corrupt).unwrap();
+        assert!(Mesh::load(root.join("meshes/corrupt.glb")).is_err());
+        let corrupt = cache.load("assets/meshes/corrupt.glb").unwrap();
+        assert_eq!(corrupt.indices, fallback.indices);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    pub fn test_generate_tangents() {
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let normals = [Vec3::Z; 3];
+        let uvs = [Vec2::ZERO, Vec2::X, Vec2::Y];
+        let tangents = generate_tangents(&positions, &normals, &uvs, &[0, 1, 2]);
+        assert!(tangents.iter().all(|tangent| *tangent == [1.0, 0.0, 0.0, 1.0]));
+
+        // Mirrored V flips the bitangent
+        let uvs = [Vec2::ZERO, Vec2::X, Vec2::NEG_Y];
+        let tangents = generate_tangents(&positions, &normals, &uvs, &[0, 1, 2]);
+        assert!(tangents.iter().all(|tangent| *tangent == [1.0, 0.0, 0.0, -1.0]));
+
+        // Degenerate UVs leave the tangent unset rather than NaN
+        let tangents = generate_tangents(&positions, &normals, &[Vec2::ZERO; 3], &[0, 1, 2]);
+        assert!(tangents.iter().all(|tangent| tangent[..3] == [0.0; 3]));
+    }
+
+    /// A triangle whose first vertex follows joint 0 and the others joint 1,
+    /// a child of joint 0 that moves from y = 1 to y = 3 over a second
+    fn rigged_glb(joints: [u8; 12]) -> Vec<u8> {
+        let positions: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let normals: [f32; 9] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0];
+        let indices: [u32; 3] = [0, 1, 2];
+        let weights: [f32; 12] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+        let times: [f32; 2] = [0.0, 1.0];
+        let translations: [f32; 6] = [0.0, 1.0, 0.0, 0.0, 3.0, 0.0];
+
+        let mut buffer = Vec::new();
+        let mut views = Vec::new();
+        for data in [
+            bytemuck::cast_slice::<f32, u8>(&positions),
+            bytemuck::cast_slice::<f32, u8>(&normals),
+            bytemuck::cast_slice::<u32, u8>(&indices),
+            &joints[..],
+            bytemuck::cast_slice::<f32, u8>(&weights),
+            bytemuck::cast_slice::<f32, u8>(&times),
+            bytemuck::cast_slice::<f32, u8>(&translations),
+        ] {
+            views.push(format!(
+                r#"{{ "buffer": 0, "byteOffset": {}, "byteLength": {} }}"#,
+                buffer.len(),
+                data.len()
+            ));
+            buffer.extend_from_slice(data);
+        }
+
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "byteLength": {} }}],
+                "bufferViews": [{}],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                    {{ "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                    {{ "bufferView": 2, "componentType": 5125, "count": 3, "type": "SCALAR" }},
+                    {{ "bufferView": 3, "componentType": 5121, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 4, "componentType": 5126, "count": 3, "type": "VEC4" }},
+                    {{ "bufferView": 5, "componentType": 5126, "count": 2, "type": "SCALAR" }},
+                    {{ "bufferView": 6, "componentType": 5126, "count": 2, "type": "VEC3" }}
+                ],
+                "meshes": [{{ "primitives": [{{
+                    "attributes": {{ "POSITION": 0, "NORMAL": 1, "JOINTS_0": 3, "WEIGHTS_0": 4 }},
+                    "indices": 2
+                }}] }}],
+                "nodes": [
+                    {{ "translation": [2.0, 0.0, 0.0], "children": [1] }},
+                    {{}},
+                    {{ "mesh": 0, "skin": 0 }}
+                ],
+                "skins": [{{ "joints": [0, 1] }}],
+                "animations": [{{
+                    "channels": [{{
+                        "sampler": 0,
+                        "target": {{ "node": 1, "path": "translation" }}
+                    }}],
+                    "samplers": [{{ "input": 5, "output": 6 }}]
+                }}]
+            }}"#,
+            buffer.len(),
+            views.join(", ")
+        );
+        glb(&json, &buffer)
+    }
+
+    /// Packs `json` and its binary `buffer` into a GLB container
+    fn glb(json: &str, buffer: &[u8]) -> Vec<u8> {
+        let mut glb = Vec::new();
+        let length = 28 + json.len() + buffer.len();
+        for word in [0x46546C67, 2, length as u32, json.len() as u32, 0x4E4F534A] {
+            glb.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        glb.extend_from_slice(json.as_bytes());
+        for word in [buffer.len() as u32, 0x004E4942] {
+            glb.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        glb.extend_from_slice(buffer);
+        glb
+    }
+
+    #[test]
+    pub fn test_tangent_count() {
+        let positions: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let normals: [f32; 9] = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0];
+        let indices: [u32; 3] = [0, 1, 2];
+        let tangents: [f32; 8] = [1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0];
+        let buffer = [
+            bytemuck::cast_slice::<f32, u8>(&positions),
+            bytemuck::cast_slice::<f32, u8>(&normals),
+            bytemuck::cast_slice::<u32, u8>(&indices),
+            bytemuck::cast_slice::<f32, u8>(&tangents),
+        ]
+        .concat();
+
+        let json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "buffers": [{{ "byteLength": {} }}],
+                "bufferViews": [
+                    {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 36, "byteLength": 36 }},
+                    {{ "buffer": 0, "byteOffset": 72, "byteLength": 12 }},
+                    {{ "buffer": 0, "byteOffset": 84, "byteLength": 32 }}
+                ],
+                "accessors": [
+                    {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                    {{ "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }},
+                    {{ "bufferView": 2, "componentType": 5125, "count": 3, "type": "SCALAR" }},
+                    {{ "bufferView": 3, "componentType": 5126, "count": 2, "type": "VEC4" }}
+                ],
+                "meshes": [{{ "primitives": [{{
+                    "attributes": {{ "POSITION": 0, "NORMAL": 1, "TANGENT": 3 }},
+                    "indices": 2
+                }}] }}],
+                "nodes": [{{ "mesh": 0 }}]
+            }}"#,
+            buffer.len()
+        );
+        let Err(error) = Mesh::from_glb(&glb(&json, &buffer)) else {
+            panic!("Loaded a mesh with too few tangents");
+        };
+        assert_eq!(error.to_string(), "2 tangents for 3 positions");
+    }
+
+    #[test]
+    pub fn test_skinned_mesh() {
+        let joints = [0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+        let mesh = Mesh::from_glb(&rigged_glb(joints)).unwrap();
+        assert_eq!(mesh.vertices[0].joints, [0, 0, 0, 0]);
+        assert_eq!(mesh.vertices[1].joints, [1, 0, 0, 0]);
+        assert_eq!(mesh.vertices[2].weights, [1.0, 0.0, 0.0, 0.0]);
+
+        let skeleton = mesh.skeleton.as_ref().unwrap();
+        assert_eq!(skeleton.joints(), 2);
+        let posed = skeleton.joint_matrices(0.0).unwrap();
+        assert!(posed[0].abs_diff_eq(Mat4::from_translation(Vec3::new(2.0, 0.0, 0.0)), 1e-6));
+        assert!(posed[1].abs_diff_eq(Mat4::from_translation(Vec3::new(2.0, 1.0, 0.0)), 1e-6));
+
+        // Halfway through the second loop
+        let posed = skeleton.joint_matrices(1.5).unwrap();
+        assert!(posed[1].abs_diff_eq(Mat4::from_translation(Vec3::new(2.0, 2.0, 0.0)), 1e-6));
+
+        let joints = [0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0];
+        assert!(Mesh::from_glb(&rigged_glb(joints)).is_err());
+
+        // Unskinned meshes are bound entirely to the identity joint
+        let fallback = Mesh::from_glb(FALLBACK_MESH).unwrap();
+        assert!(fallback.skeleton.is_none());
+        assert!(fallback.vertices.iter().all(|vertex| *vertex == Vertex {
+            joints: [0; 4],
+            weights: [1.0, 0.0, 0.0, 0.0],
+            ..*vertex
+        }));
+    }
+
+    #[test]
+    pub fn test_material_bytes() {
+        let material = Material::new(Vec4::new(1.0, 0.5, 0.0, 1.0))
+            .with_metallic(0.8)
+            .with_roughness(0.4)
+            .with_emissive(Vec3::X);
+        let bytes = bytemuck::bytes_of(&material);
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(bytemuck::cast_slice::<u8, f32>(&bytes[32..40]), [0.8, 0.4]);
+        assert_eq!(*bytemuck::from_bytes::<Material>(bytes), material);
     }
 }
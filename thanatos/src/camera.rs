@@ -1,14 +1,143 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
 use glam::{Mat4, Quat, Vec2, Vec3, Vec4, Vec4Swizzles};
+use log::warn;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    assets::Aabb,
     event::Event,
-    window::{Mouse, Window},
+    window::{Keybind, Keyboard, Mouse, Window},
     World,
 };
 
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CameraMode {
+    FirstPerson,
+    #[default]
+    ThirdPerson,
+}
+
+impl CameraMode {
+    pub fn toggle(self) -> Self {
+        match self {
+            Self::FirstPerson => Self::ThirdPerson,
+            Self::ThirdPerson => Self::FirstPerson,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    pub mode: CameraMode,
+    pub distance: f32,
+    pub theta: f32,
+    pub pitch: f32,
+    pub min_pitch: f32,
+    pub max_pitch: f32,
+    pub fov: f32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            mode: CameraMode::default(),
+            distance: 10.0,
+            theta: 0.0,
+            pitch: std::f32::consts::FRAC_PI_4,
+            min_pitch: -MAX_PITCH,
+            max_pitch: MAX_PITCH,
+            fov: std::f32::consts::PI / 2.0,
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Clamps anything out of range, warning about what was changed.
+    pub fn validated(mut self) -> Self {
+        let min_pitch = self.min_pitch.clamp(-MAX_PITCH, MAX_PITCH);
+        let max_pitch = self.max_pitch.clamp(min_pitch, MAX_PITCH);
+        let pitch = self.pitch.clamp(min_pitch, max_pitch);
+        if (min_pitch, max_pitch, pitch) != (self.min_pitch, self.max_pitch, self.pitch) {
+            warn!("Camera pitch out of range, clamped to {pitch} in {min_pitch}..{max_pitch}");
+        }
+        self.min_pitch = min_pitch;
+        self.max_pitch = max_pitch;
+        self.pitch = pitch;
+
+        if self.distance <= 0.0 {
+            warn!("Camera distance {} must be positive", self.distance);
+            self.distance = Self::default().distance;
+        }
+        if !(self.fov > 0.0 && self.fov < std::f32::consts::PI) {
+            warn!("Camera fov {} out of range", self.fov);
+            self.fov = Self::default().fov;
+        }
+        self
+    }
+}
+
+/// Named camera configurations, cycled through with `Keybind::CameraPreset`.
+/// The one named `default` is applied on startup.
+#[derive(Clone, Debug, Default)]
+pub struct CameraPresets {
+    presets: Vec<(String, CameraConfig)>,
+    current: usize,
+}
+
+impl CameraPresets {
+    pub fn parse(json: &str) -> Result<Self> {
+        let presets: BTreeMap<String, CameraConfig> = serde_json::from_str(json)?;
+        let presets = presets
+            .into_iter()
+            .map(|(name, config)| (name, config.validated()))
+            .collect::<Vec<_>>();
+        let current = presets
+            .iter()
+            .position(|(name, _)| name == "default")
+            .unwrap_or_default();
+        Ok(Self { presets, current })
+    }
+
+    /// Falls back to no presets, leaving the camera on its defaults, if the
+    /// file is missing or invalid
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let presets = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Self::parse(&json));
+        presets.unwrap_or_else(|e| {
+            warn!("Couldn't load camera presets from {}: {e}", path.display());
+            Self::default()
+        })
+    }
+
+    pub fn current(&self) -> Option<(&str, CameraConfig)> {
+        self.presets
+            .get(self.current)
+            .map(|(name, config)| (name.as_str(), *config))
+    }
+
+    pub fn next(&mut self) -> Option<(&str, CameraConfig)> {
+        if self.presets.is_empty() {
+            return None;
+        }
+        self.current = (self.current + 1) % self.presets.len();
+        self.current()
+    }
+}
+
 pub struct Camera {
+    pub mode: CameraMode,
     pub target: Vec3,
     pub theta: f32,
+    pub pitch: f32,
+    pub min_pitch: f32,
+    pub max_pitch: f32,
     pub distance: f32,
     pub fov: f32,
     pub aspect: f32,
@@ -18,29 +147,69 @@ impl Camera {
     pub fn new(window: &Window) -> Self {
         let size = window.window.inner_size();
         let aspect = size.width as f32 / size.height as f32;
-        Self {
+        Self::from_config(CameraConfig::default(), aspect)
+    }
+
+    pub fn from_config(config: CameraConfig, aspect: f32) -> Self {
+        let mut camera = Self {
+            mode: CameraMode::default(),
             target: Vec3::ZERO,
             theta: 0.0,
-            distance: 10.0,
-            fov: std::f32::consts::PI / 2.0,
+            pitch: 0.0,
+            min_pitch: 0.0,
+            max_pitch: 0.0,
+            distance: 0.0,
+            fov: 0.0,
             aspect,
-        }
+        };
+        camera.configure(config);
+        camera
+    }
+
+    pub fn configure(&mut self, config: CameraConfig) {
+        let config = config.validated();
+        self.mode = config.mode;
+        self.distance = config.distance;
+        self.theta = config.theta;
+        self.pitch = config.pitch;
+        self.min_pitch = config.min_pitch;
+        self.max_pitch = config.max_pitch;
+        self.fov = config.fov;
+    }
+
+    /// Horizontal rotation of the camera, used to map movement onto the
+    /// direction the player is facing.
+    pub fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.theta)
+    }
+
+    /// Unit vector the camera is looking along.
+    pub fn look(&self) -> Vec3 {
+        self.rotation() * Quat::from_rotation_x(-self.pitch) * Vec3::Z
     }
 
     pub fn eye(&self) -> Vec3 {
-        let eye = Vec3::new(0.0, -1.0, -1.0).normalize() * self.distance;
-        let rotated = Quat::from_rotation_y(self.theta) * eye;
-        rotated + self.target
+        match self.mode {
+            CameraMode::FirstPerson => self.target,
+            CameraMode::ThirdPerson => self.target - self.look() * self.distance,
+        }
     }
 
     pub fn direction(&self) -> Vec3 {
-        (self.eye() - self.target).normalize()
+        -self.look()
+    }
+
+    pub fn view(&self) -> Mat4 {
+        let eye = self.eye();
+        Mat4::look_at_rh(eye, eye + self.look(), Vec3::Y)
+    }
+
+    pub fn projection(&self, aspect: f32) -> Mat4 {
+        Mat4::perspective_infinite_rh(self.fov, aspect, 0.1)
     }
 
     pub fn get_matrix(&self) -> Mat4 {
-        let view = Mat4::look_at_rh(self.eye(), self.target, Vec3::Y);
-        let projection = Mat4::perspective_infinite_rh(self.fov, self.aspect, 0.1);
-        projection * view
+        self.projection(self.aspect) * self.view()
     }
 
     pub fn ndc_to_world(&self, pos: Vec2) -> Vec3 {
@@ -63,17 +232,201 @@ impl Camera {
         let mouse = world.get::<Mouse>().unwrap();
         let mut camera = world.get_mut::<Camera>().unwrap();
         if mouse.is_down(winit::event::MouseButton::Right) {
-            println!("{:?}", mouse.delta.x);
-            camera.theta -= mouse.delta.x * 0.02;
+            camera.theta -= mouse.motion.x * 0.02;
+            camera.pitch =
+                (camera.pitch - mouse.motion.y * 0.02).clamp(camera.min_pitch, camera.max_pitch);
         }
     }
 
-    pub fn add(self) -> impl FnOnce(World) -> World {
+    pub fn toggle_mode(world: &World) {
+        let keyboard = world.get::<Keyboard>().unwrap();
+        if keyboard.pressed(Keybind::ToggleCamera) {
+            let mut camera = world.get_mut::<Camera>().unwrap();
+            camera.mode = camera.mode.toggle();
+        }
+    }
+
+    pub fn cycle_preset(world: &World) {
+        let keyboard = world.get::<Keyboard>().unwrap();
+        if keyboard.pressed(Keybind::CameraPreset) {
+            let mut presets = world.get_mut::<CameraPresets>().unwrap();
+            if let Some((_, config)) = presets.next() {
+                world.get_mut::<Camera>().unwrap().configure(config);
+            }
+        }
+    }
+
+    pub fn add(mut self, presets: CameraPresets) -> impl FnOnce(World) -> World {
+        if let Some((_, config)) = presets.current() {
+            self.configure(config);
+        }
         move |world| {
             world
                 .with_resource(self)
+                .with_resource(presets)
                 .with_handler(Self::handle_resize)
                 .with_ticker(Self::rotate_camera)
+                .with_ticker(Self::toggle_mode)
+                .with_ticker(Self::cycle_preset)
+        }
+    }
+}
+
+/// The six planes bounding what a view-projection matrix can see, pointing
+/// inwards. They aren't normalised, only which side a point is on matters.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Depth is clipped to 0..1, as in Vulkan
+    pub fn from_matrix(view_proj: Mat4) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|i| view_proj.row(i));
+        Self {
+            planes: [w + x, w - x, w + y, w - y, z, w - z],
+        }
+    }
+
+    /// Whether any of `aabb` might be visible. Boxes straddling a plane are
+    /// kept, only those entirely behind one are rejected.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner = Vec3::select(plane.xyz().cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            plane.xyz().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(mode: CameraMode) -> Camera {
+        let mut camera = Camera::from_config(
+            CameraConfig {
+                mode,
+                theta: 0.5,
+                pitch: 0.0,
+                ..Default::default()
+            },
+            1.0,
+        );
+        camera.target = Vec3::new(1.0, 2.0, 3.0);
+        camera
+    }
+
+    #[test]
+    pub fn test_third_person_view() {
+        let mut camera = camera(CameraMode::ThirdPerson);
+        camera.pitch = std::f32::consts::FRAC_PI_4;
+        let view = camera.view();
+        assert!(view.transform_point3(camera.eye()).length() < 1e-4);
+        let target = view.transform_point3(camera.target);
+        assert!(target.truncate().length() < 1e-4);
+        assert!((target.z + camera.distance).abs() < 1e-4);
+
+        let offset = camera.rotation() * Vec3::new(0.0, -1.0, -1.0).normalize() * camera.distance;
+        assert!((camera.eye() - (camera.target + offset)).length() < 1e-4);
+    }
+
+    #[test]
+    pub fn test_first_person_view() {
+        let mut camera = camera(CameraMode::FirstPerson);
+        assert_eq!(camera.eye(), camera.target);
+        assert!(camera.view().transform_point3(camera.target).length() < 1e-4);
+
+        let ahead = camera.view().transform_point3(camera.target + camera.rotation() * Vec3::Z);
+        assert!(ahead.truncate().length() < 1e-4);
+        assert!((ahead.z + 1.0).abs() < 1e-4);
+
+        camera.pitch = 0.3;
+        let look = camera.view().transform_vector3(camera.look());
+        assert!((look - Vec3::NEG_Z).length() < 1e-4);
+        assert!((camera.look().y - 0.3_f32.sin()).abs() < 1e-4);
+    }
+
+    #[test]
+    pub fn test_frustum() {
+        let view = Mat4::look_at_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+        let projection = Mat4::perspective_infinite_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1);
+        let frustum = Frustum::from_matrix(projection * view);
+        let cube = |centre: Vec3, half: f32| Aabb {
+            min: centre - half,
+            max: centre + half,
+        };
+
+        assert!(frustum.intersects(&cube(Vec3::new(0.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(Vec3::new(0.0, 0.0, 10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(Vec3::new(-100.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects(&cube(Vec3::new(0.0, 100.0, -10.0), 1.0)));
+        // Straddling the left plane and the near plane
+        assert!(frustum.intersects(&cube(Vec3::new(-10.0, 0.0, -10.0), 1.0)));
+        assert!(frustum.intersects(&cube(Vec3::new(0.0, 0.0, -0.1), 0.05)));
+        // Entirely between the eye and the near plane
+        assert!(!frustum.intersects(&cube(Vec3::new(0.0, 0.0, -0.05), 0.01)));
+        // The far plane is at infinity
+        assert!(frustum.intersects(&cube(Vec3::new(0.0, 0.0, -1e6), 1.0)));
+    }
+
+    #[test]
+    pub fn test_toggle_mode() {
+        assert_eq!(CameraMode::ThirdPerson.toggle(), CameraMode::FirstPerson);
+        assert_eq!(CameraMode::FirstPerson.toggle(), CameraMode::ThirdPerson);
+    }
+
+    #[test]
+    pub fn test_validate_config() {
+        let config = CameraConfig {
+            pitch: 3.0,
+            min_pitch: -0.5,
+            max_pitch: 1.0,
+            distance: -1.0,
+            ..Default::default()
+        }
+        .validated();
+        assert_eq!(config.pitch, 1.0);
+        assert_eq!(config.min_pitch, -0.5);
+        assert_eq!(config.distance, CameraConfig::default().distance);
+
+        let config = CameraConfig {
+            min_pitch: -10.0,
+            max_pitch: -20.0,
+            ..Default::default()
         }
+        .validated();
+        assert_eq!(config.min_pitch, -MAX_PITCH);
+        assert_eq!(config.max_pitch, -MAX_PITCH);
+    }
+
+    #[test]
+    pub fn test_presets() {
+        let mut presets = CameraPresets::parse(
+            r#"{
+                "close": { "distance": 5.0 },
+                "default": { "distance": 10.0 },
+                "overhead": { "distance": 25.0, "pitch": 1.5 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(presets.current().unwrap().0, "default");
+        let (name, config) = presets.next().unwrap();
+        assert_eq!(name, "overhead");
+        assert_eq!(config.distance, 25.0);
+        assert_eq!(config.mode, CameraMode::ThirdPerson);
+        assert_eq!(presets.next().unwrap().0, "close");
+    }
+
+    #[test]
+    pub fn test_presets_fallback() {
+        let path = std::env::temp_dir().join(format!("thanatos-cameras-{}", std::process::id()));
+        assert!(CameraPresets::load(&path).current().is_none());
+
+        std::fs::write(&path, "{ not json").unwrap();
+        let mut presets = CameraPresets::load(&path);
+        assert!(presets.current().is_none());
+        assert!(presets.next().is_none());
+        std::fs::remove_file(path).unwrap();
     }
 }
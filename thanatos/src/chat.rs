@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+use glam::Vec2;
+use nyx::protocol::{ClientId, Clientbound, Serverbound, MAX_CHAT_LEN};
+use tecs::utils::Name;
+use winit::keyboard::NamedKey;
+
+use crate::{
+    event::{Event, Key},
+    net::{Connection, OtherPlayer},
+    renderer::Ui,
+    window::{Keybind, Keyboard},
+    World,
+};
+
+/// How many received lines are kept and shown
+const CHAT_LINES: usize = 8;
+
+#[derive(Default)]
+pub struct Chat {
+    pub lines: VecDeque<String>,
+    typing: Option<String>,
+    /// Set when typing stopped this frame, so the Enter that sent the message
+    /// doesn't also count as the chat keybind
+    closed: bool,
+}
+
+impl Chat {
+    pub fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+        while self.lines.len() > CHAT_LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    /// Starts typing if the chat key was `pressed` this frame and didn't just
+    /// close it, returning whether it did.
+    fn open(&mut self, pressed: bool) -> bool {
+        let closed = std::mem::take(&mut self.closed);
+        if !pressed || closed || self.typing.is_some() {
+            return false;
+        }
+        self.typing = Some(String::new());
+        true
+    }
+
+    fn close(&mut self) -> Option<String> {
+        self.closed = true;
+        self.typing.take()
+    }
+
+    /// Appends to the message being typed, up to what the server accepts.
+    fn input(&mut self, text: &str) {
+        if let Some(typing) = self.typing.as_mut() {
+            if typing.len() + text.len() <= MAX_CHAT_LEN {
+                typing.push_str(text);
+            }
+        }
+    }
+}
+
+fn sender(world: &World, client_id: ClientId) -> String {
+    if Some(client_id) == world.get::<Connection>().unwrap().id {
+        return String::from("You");
+    }
+    OtherPlayer::find(world, client_id)
+        .and_then(|id| world.get_component::<Name>(id).map(|name| name.0.clone()))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| format!("Player {}", client_id.0))
+}
+
+fn finish(world: &World, send: bool) {
+    let text = world.get_mut::<Chat>().unwrap().close();
+    world.get_mut::<Keyboard>().unwrap().captured = false;
+    if let Some(text) = text.filter(|text| send && !text.trim().is_empty()) {
+        let mut conn = world.get_mut::<Connection>().unwrap();
+        conn.write(Serverbound::Chat(text)).unwrap();
+    }
+}
+
+fn handle_event(world: &World, event: &Event) {
+    match event {
+        Event::Recieved(Clientbound::Chat(client_id, text)) => {
+            let from = sender(world, *client_id);
+            world.get_mut::<Chat>().unwrap().push(format!("{from}: {text}"));
+        }
+        Event::KeyPress(key) => {
+            if world.get::<Chat>().unwrap().typing.is_none() {
+                return;
+            }
+            match key {
+                Key::Named(NamedKey::Enter) => finish(world, true),
+                Key::Named(NamedKey::Escape) => finish(world, false),
+                Key::Named(NamedKey::Backspace) => {
+                    if let Some(typing) = world.get_mut::<Chat>().unwrap().typing.as_mut() {
+                        typing.pop();
+                    }
+                }
+                Key::Named(NamedKey::Space) => world.get_mut::<Chat>().unwrap().input(" "),
+                Key::Character(text) => world.get_mut::<Chat>().unwrap().input(text),
+                _ => (),
+            }
+        }
+        _ => (),
+    }
+}
+
+fn tick(world: &World) {
+    let mut chat = world.get_mut::<Chat>().unwrap();
+    {
+        let mut keyboard = world.get_mut::<Keyboard>().unwrap();
+        if chat.open(keyboard.pressed(Keybind::Chat)) {
+            keyboard.captured = true;
+        }
+    }
+
+    let mut ui = world.get_mut::<Ui>().unwrap();
+    let typing = chat.typing.as_ref().map(|typing| format!("> {typing}"));
+    chat.lines
+        .iter()
+        .cloned()
+        .chain(typing)
+        .enumerate()
+        .for_each(|(i, line)| ui.text(Vec2::new(8.0, 100.0 + i as f32 * 20.0), line, 16.0));
+}
+
+pub fn add(world: World) -> World {
+    world
+        .with_resource(Chat::default())
+        .with_handler(handle_event)
+        .with_ticker(tick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_enter_closes() {
+        let mut chat = Chat::default();
+        assert!(chat.open(true));
+        chat.input("hello");
+
+        // Enter is handled as a key press before the tick polls the keybind
+        assert_eq!(chat.close().as_deref(), Some("hello"));
+        assert!(!chat.open(true));
+        assert!(chat.typing.is_none());
+
+        assert!(!chat.open(false));
+        assert!(chat.open(true));
+    }
+}
@@ -1,4 +1,5 @@
 use glam::Vec3;
+use log::trace;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Debug)]
@@ -48,7 +49,7 @@ impl Collider {
 
     fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
         let discriminant = b * b - 4.0 * a * c;
-        println!("{}", discriminant);
+        trace!("Discriminant {discriminant}");
         if discriminant < 0.0 {
             return None;
         }
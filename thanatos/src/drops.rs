@@ -0,0 +1,82 @@
+use glam::Vec3;
+use nyx::protocol::{Clientbound, DropId};
+use tecs::prelude::*;
+
+use crate::{
+    assets::{Material, MeshId},
+    colours::rarity_colour,
+    event::Event,
+    renderer::{Layer, RenderObject},
+    transform::Transform,
+    World,
+};
+
+const DROP_SCALE: f32 = 0.25;
+
+/// An item stack lying on the ground, waiting for a player to walk over it
+#[derive(Archetype, Clone)]
+pub struct DroppedItem {
+    pub id: DropId,
+    pub render: RenderObject,
+    pub transform: Transform,
+}
+
+impl DroppedItem {
+    fn find(world: &World, id: DropId) -> Option<EntityId> {
+        let (entities, ids, _) = world.query::<(EntityId, &DropId, Is<DroppedItem>)>();
+        entities
+            .into_iter()
+            .zip(ids.iter())
+            .find(|(_, other)| **other == id)
+            .map(|(entity, _)| entity)
+    }
+}
+
+fn handle_net(world: &World, event: &Event) {
+    match event {
+        Event::Recieved(Clientbound::SpawnDrop(id, position, stack)) => {
+            if DroppedItem::find(world, *id).is_some() {
+                return;
+            }
+            let mut transform = Transform::IDENTITY;
+            transform.translation = *position;
+            transform.scale = Vec3::splat(DROP_SCALE);
+            world.spawn(DroppedItem {
+                id: *id,
+                render: RenderObject {
+                    mesh: MeshId(String::from("assets/meshes/cube.glb")),
+                    material: Material::new(rarity_colour(stack.item.rarity)),
+                    layer: Layer::Opaque,
+                },
+                transform,
+            });
+        }
+        Event::Recieved(Clientbound::DespawnDrop(id)) => {
+            if let Some(entity) = DroppedItem::find(world, *id) {
+                world.despawn::<DroppedItem>(entity);
+            }
+        }
+        Event::Recieved(Clientbound::Synced(_, drops)) => {
+            // Picked up while the DespawnDrop was lost
+            let stale = {
+                let (entities, ids, _) = world.query::<(EntityId, &DropId, Is<DroppedItem>)>();
+                entities
+                    .into_iter()
+                    .zip(ids.iter())
+                    .filter(|(_, id)| !drops.contains(id))
+                    .map(|(entity, _)| entity)
+                    .collect::<Vec<_>>()
+            };
+            stale
+                .into_iter()
+                .for_each(|entity| world.despawn::<DroppedItem>(entity));
+        }
+        _ => (),
+    }
+}
+
+pub fn add(world: World) -> World {
+    world
+        .register_unsaved::<DroppedItem>()
+        .with_handler(handle_net)
+}
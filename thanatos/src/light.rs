@@ -0,0 +1,90 @@
+use bytemuck::Zeroable;
+use glam::{Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+use tecs::prelude::*;
+
+use crate::{transform::Transform, World};
+
+/// A point light shining from its entity's `Transform`, falling off with the
+/// square of the distance
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Light {
+    pub colour: Vec3,
+    pub intensity: f32,
+}
+
+// Must match the std430 layout of `Light` in shader.frag.glsl:
+//   0   position   vec4, w unused
+//   16  radiance   vec4, colour * intensity, w unused
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GpuLight {
+    position: Vec4,
+    radiance: Vec4,
+}
+
+const _: () = assert!(std::mem::size_of::<GpuLight>() == 32);
+
+impl Light {
+    pub fn pack(&self, position: Vec3) -> GpuLight {
+        GpuLight {
+            position: position.extend(1.0),
+            radiance: (self.colour * self.intensity).extend(0.0),
+        }
+    }
+
+    /// Packs every light in the world. Storage buffers can't be empty, so
+    /// with no lights a single black one is returned in their place.
+    pub fn gather(world: &World) -> Vec<GpuLight> {
+        let (lights, transforms) = world.query::<(&Light, &Transform)>();
+        let lights = lights
+            .iter()
+            .zip(transforms.iter())
+            .map(|(light, transform)| light.pack(transform.translation))
+            .collect::<Vec<_>>();
+        if lights.is_empty() {
+            return vec![GpuLight::zeroed()];
+        }
+        lights
+    }
+}
+
+#[derive(Archetype, Clone)]
+pub struct PointLight {
+    pub light: Light,
+    pub transform: Transform,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, colour: Vec3, intensity: f32) -> Self {
+        Self {
+            light: Light { colour, intensity },
+            transform: Transform {
+                translation: position,
+                ..Transform::IDENTITY
+            },
+        }
+    }
+}
+
+pub fn add(world: World) -> World {
+    world.register_unsaved::<PointLight>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_pack() {
+        let light = Light {
+            colour: Vec3::new(1.0, 0.5, 0.0),
+            intensity: 4.0,
+        };
+        let packed = light.pack(Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(
+            bytemuck::cast_slice::<GpuLight, f32>(&[packed]),
+            [1.0, 2.0, 3.0, 1.0, 4.0, 2.0, 0.0, 0.0]
+        );
+    }
+}
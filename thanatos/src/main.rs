@@ -1,33 +1,45 @@
 mod assets;
 mod camera;
+mod chat;
 mod collider;
 mod colours;
 mod craft;
+mod drops;
 mod equipment;
 mod event;
 mod gather;
 mod interact;
 mod inventory;
+mod light;
 mod net;
 mod player;
+mod post;
 mod renderer;
+mod shadow;
+mod skeleton;
 mod transform;
 mod window;
 
-use crate::{camera::Camera, window::Window};
+use crate::{
+    camera::{Camera, CameraPresets},
+    window::Window,
+};
 use anyhow::Result;
-use assets::{Material, MeshCache, MeshId};
+use assets::{Assets, Material, MeshCache, MeshId};
 use collider::{Collider, ColliderKind};
 use event::Event;
 use gather::Gatherable;
-use glam::{Vec3, Vec4};
+use glam::{Vec2, Vec3, Vec4};
+use hephaestus::PresentModeKHR;
 use interact::Interactable;
-use net::Connection;
+use light::PointLight;
+use net::{Connection, Latency, OtherPlayer};
 use nyx::task::Proficiencies;
-use player::Player;
-use renderer::{RenderObject, Renderer};
+use player::{Health, Player};
+use log::warn;
+use renderer::{parse_colour, Layer, RenderObject, Renderer, Ui};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tecs::prelude::*;
 use tecs::scene::Scene;
 use tecs::utils::{Clock, Name, State, Timer};
@@ -47,9 +59,10 @@ impl CopperOre {
         Ok(CopperOre {
             render: RenderObject {
                 mesh: MeshId(String::from("assets/meshes/copper_ore.glb")),
-                material: Material {
-                    colour: Vec4::new(1.0, 0.5, 0.0, 1.0),
-                },
+                material: Material::new(Vec4::new(1.0, 0.5, 0.0, 1.0))
+                    .with_metallic(0.8)
+                    .with_roughness(0.4),
+                layer: Layer::Opaque,
             },
             transform: Transform::IDENTITY,
             gatherable: Gatherable {
@@ -73,12 +86,38 @@ impl CopperOre {
 
 pub type World = tecs::World<Event>;
 
+fn arg(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    let assets = Assets::new(arg("--assets").unwrap_or_else(|| String::from("assets")));
+
     let window = Window::new();
 
-    let renderer = Renderer::new(&window)?;
+    let msaa = arg("--msaa").and_then(|samples| samples.parse().ok());
+    let mut renderer = Renderer::new(&window, &assets, msaa)?;
+    if let Some(bias) = arg("--shadow-bias").and_then(|bias| bias.parse().ok()) {
+        renderer.set_shadow_bias(bias);
+    }
+    if let Some(tonemap) = arg("--tonemap").and_then(|tonemap| tonemap.parse().ok()) {
+        renderer.set_tonemap(tonemap);
+    }
+    if let Some(colour) = arg("--clear-colour") {
+        match parse_colour(&colour) {
+            Ok(colour) => renderer.set_clear_colour(colour),
+            Err(e) => warn!("Ignoring --clear-colour: {e}"),
+        }
+    }
+    match arg("--vsync").as_deref() {
+        Some("on") => renderer.set_present_mode(PresentModeKHR::FIFO)?,
+        Some("off") => renderer.set_present_mode(PresentModeKHR::IMMEDIATE)?,
+        Some(vsync) => warn!("Ignoring --vsync {vsync:?}, expected on or off"),
+        None => (),
+    }
+    renderer.set_wireframe(std::env::args().any(|arg| arg == "--wireframe"));
     let camera = Camera::new(&window);
 
     let world = World::new()
@@ -86,16 +125,19 @@ fn main() -> Result<()> {
         .register::<CopperOre>()
         .with_resource(State::Running)
         .with_resource(Proficiencies::default())
-        .with_resource(MeshCache::default())
-        .with(Connection::add)
+        .with_resource(MeshCache::new(assets.clone()))
+        .with(Connection::add(arg("--name")))
         .with(window.add())
-        .with(renderer.add())
-        .with(camera.add())
+        .with(renderer.add(Ui::new(&assets)?))
+        .with(camera.add(CameraPresets::load(assets.resolve("cameras.json"))))
         .with(Clock::add)
         .with(inventory::add)
+        .with(drops::add)
+        .with(chat::add)
         .with(craft::add)
         .with(equipment::add)
         .with(interact::add)
+        .with(light::add)
         .with_handler(|world, event| match event {
             Event::Stop => {
                 *world.get_mut::<State>().unwrap() = State::Stopped;
@@ -104,9 +146,49 @@ fn main() -> Result<()> {
         })
         .with_ticker(|world| {
             let clock = world.get::<Clock>().unwrap();
-            println!("FPS: {}", 1.0 / clock.delta.as_secs_f32());
+            let renderer = world.get::<Renderer>().unwrap();
+            let mut ui = world.get_mut::<Ui>().unwrap();
+            let fps = format!("FPS: {:.0}", 1.0 / clock.delta.as_secs_f32());
+            let ms = |duration: Duration| duration.as_secs_f32() * 1000.0;
+            let text = match renderer.gpu_times() {
+                Some(gpu) => format!(
+                    "{fps} GPU: {:.2}ms (shadows {:.2}ms, scene {:.2}ms)",
+                    ms(gpu.total()),
+                    ms(gpu.shadows),
+                    ms(gpu.scene)
+                ),
+                None => fps,
+            };
+            let text = match world.get::<Latency>().unwrap().rtt {
+                Some(rtt) => format!("{text} Ping: {:.0}ms", rtt.as_secs_f32() * 1000.0),
+                None => text,
+            };
+            ui.text(Vec2::new(8.0, 8.0), text, 16.0);
+
+            let stats = renderer.stats();
+            ui.text(
+                Vec2::new(8.0, 48.0),
+                format!(
+                    "Meshes: {} Vertices: {} Indices: {}",
+                    stats.meshes, stats.vertices, stats.indices
+                ),
+                16.0,
+            );
+
+            let (health, _) = world.query_one::<(&Health, Is<Player>)>();
+            ui.text(Vec2::new(8.0, 28.0), format!("Health: {:.0}", health.0), 16.0);
+
+            let (names, _) = world.query::<(&Name, Is<OtherPlayer>)>();
+            let names = names
+                .iter()
+                .filter(|name| !name.0.is_empty())
+                .map(|name| name.0.as_str())
+                .collect::<Vec<_>>();
+            if !names.is_empty() {
+                ui.text(Vec2::new(8.0, 68.0), format!("Players: {}", names.join(", ")), 16.0);
+            }
         })
-        .with_ticker(Player::tick)
+        .with(player::add)
         .with_ticker(gather::tick)
         .with(net::add);
 
@@ -117,7 +199,7 @@ fn main() -> Result<()> {
     world.spawn(Player {
         render: RenderObject {
             mesh: MeshId(String::from("assets/meshes/cube.glb")),
-            material: Material { colour: Vec4::ONE },
+            material: Material::new(Vec4::ONE),
         },
         transform,
         health: Health(100.0),
@@ -138,14 +220,33 @@ fn main() -> Result<()> {
     std::fs::write("assets/scenes/test.scene", buffer).unwrap();
     */
 
-    let buffer = std::fs::read("assets/scenes/test.scene").unwrap();
+    let buffer = std::fs::read(assets.resolve("scenes/test.scene")).unwrap();
     Scene::load(&world, &mut serde_json::Deserializer::from_slice(&buffer)).unwrap();
 
+    world.spawn(PointLight::new(Vec3::new(4.0, 2.0, 0.0), Vec3::new(1.0, 0.4, 0.2), 20.0));
+    world.spawn(PointLight::new(Vec3::new(-4.0, 2.0, 0.0), Vec3::new(0.2, 0.4, 1.0), 20.0));
+
+    let frame_time = arg("--fps-cap")
+        .and_then(|fps| fps.parse::<f32>().ok())
+        .filter(|fps| *fps > 0.0)
+        .map(|fps| Duration::from_secs_f32(1.0 / fps));
+    let mut deadline = Instant::now();
+
     loop {
         if let State::Stopped = *world.get::<State>().unwrap() {
             break;
         }
         world.tick();
+
+        if let Some(frame_time) = frame_time {
+            deadline += frame_time;
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            } else {
+                deadline = now;
+            }
+        }
     }
 
     Ok(())
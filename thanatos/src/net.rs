@@ -1,32 +1,47 @@
 use anyhow::Result;
 use glam::{Vec3, Vec4};
-use nyx::protocol::{ClientId, Clientbound, ClientboundBundle, Serverbound, Tick, TPS};
+use log::{debug, error, info, trace, warn};
+use nyx::protocol::{
+    self, ClientId, Clientbound, ClientboundBundle, Serverbound, Tick, MAX_MESSAGE_LEN,
+    PROTOCOL_VERSION, TPS,
+};
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::{HashMap, VecDeque},
     io::ErrorKind,
     net::UdpSocket,
     time::{Duration, Instant},
 };
-use tecs::prelude::*;
+use tecs::{prelude::*, utils::Name};
 
 use crate::{
     assets::{Material, MeshId},
     event::Event,
-    player::Player,
-    renderer::RenderObject,
+    player::{Health, Player},
+    renderer::{Layer, RenderObject},
     transform::Transform,
-    World,
+    Clock, World,
 };
 
+pub const CORRECTION_RATE: f32 = 10.0;
+const SNAP_DISTANCE: f32 = 2.0;
+const DESPAWN_GRACE: u64 = TPS as u64 * 2;
+/// Minimum ticks between sync requests, so a burst of unknown players only
+/// asks once
+const SYNC_INTERVAL: u64 = TPS as u64;
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Pings older than this are assumed lost
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Connection {
     socket: UdpSocket,
     pub id: Option<ClientId>,
     pub tick: Tick,
+    name: Option<String>,
 }
 
 impl Connection {
-    pub fn new() -> Result<Self> {
+    pub fn new(name: Option<String>) -> Result<Self> {
         let socket = UdpSocket::bind("127.0.0.1:0")?;
         socket.connect("127.0.0.1:8080")?;
         socket.set_nonblocking(true)?;
@@ -34,8 +49,9 @@ impl Connection {
             socket,
             id: None,
             tick: Tick(0),
+            name,
         };
-        conn.write(Serverbound::AuthRequest).unwrap();
+        conn.write(Serverbound::AuthRequest(PROTOCOL_VERSION)).unwrap();
         Ok(conn)
     }
 
@@ -46,22 +62,36 @@ impl Connection {
     }
 
     fn get(&mut self) -> Option<ClientboundBundle> {
-        let mut buffer = [0; 4096];
+        let mut buffer = [0; MAX_MESSAGE_LEN];
         match self.socket.recv(&mut buffer) {
-            Ok(_) => Some(bincode::deserialize(&buffer).unwrap()),
+            Ok(n) => match protocol::decode(&buffer[..n]) {
+                Ok(bundle) => Some(bundle),
+                Err(e) => {
+                    warn!("Dropping {n} byte bundle: {e}");
+                    None
+                }
+            },
             Err(e) if e.kind() == ErrorKind::WouldBlock => None,
             Err(e) => panic!("{e}"),
         }
     }
 
     pub fn tick(world: &World) {
+        let mut stopping = false;
         let messages: Vec<Clientbound> = {
             let mut conn = world.get_mut::<Connection>().unwrap();
 
             let Some(bundle) = conn.get() else { return };
+            // Reordered datagrams, a tick's messages may be split over
+            // several bundles so only strictly older ones are dropped
+            if bundle.tick < conn.tick {
+                debug!("Dropping stale bundle for {:?}", bundle.tick);
+                return;
+            }
             conn.tick = bundle.tick;
-            println!("Received: {:?}", bundle.tick);
-            bundle
+            trace!("Received bundle for {:?}", bundle.tick);
+            let authenticating = conn.id.is_none();
+            let messages = bundle
                 .messages
                 .into_iter()
                 .filter(|message| match message {
@@ -69,55 +99,172 @@ impl Connection {
                         conn.id = Some(*id);
                         false
                     }
+                    Clientbound::Reject(reason) => {
+                        error!("Rejected by server: {reason}");
+                        stopping = true;
+                        false
+                    }
+                    Clientbound::Shutdown => {
+                        info!("Server shut down");
+                        // Nobody left to tell we're disconnecting
+                        conn.id = None;
+                        stopping = true;
+                        false
+                    }
                     _ => true,
                 })
-                .collect()
+                .collect();
+            if authenticating && conn.id.is_some() {
+                if let Some(name) = conn.name.clone() {
+                    conn.write(Serverbound::SetName(name)).unwrap();
+                }
+            }
+            messages
         };
+        if stopping {
+            world.submit(Event::Stop);
+            return;
+        }
         messages
             .into_iter()
             .for_each(|message| world.submit(Event::Recieved(message)));
         world.submit(Event::ServerTick);
     }
 
-    pub fn add(world: World) -> World {
-        world
-            .with_resource(Self::new().unwrap())
-            .with_ticker(Self::tick)
+    pub fn stop(world: &World, event: &Event) {
+        if let Event::Stop = event {
+            let mut conn = world.get_mut::<Connection>().unwrap();
+            if conn.id.take().is_some() {
+                debug!("Disconnecting");
+                conn.write(Serverbound::Disconnect).unwrap();
+            }
+        }
+    }
+
+    pub fn add(name: Option<String>) -> impl FnOnce(World) -> World {
+        move |world| {
+            world
+                .with_resource(Self::new(name).unwrap())
+                .with_resource(Latency::default())
+                .with_ticker(Self::tick)
+                .with_ticker(Latency::tick)
+                .with_handler(Self::stop)
+                .with_handler(Latency::pong)
+        }
+    }
+}
+
+/// Round trip time to the server, measured with pings. This includes up to
+/// a tick of waiting for the server to flush the pong.
+#[derive(Default)]
+pub struct Latency {
+    pending: HashMap<u64, Instant>,
+    next: u64,
+    last_ping: Option<Instant>,
+    pub rtt: Option<Duration>,
+}
+
+impl Latency {
+    pub fn tick(world: &World) {
+        let mut latency = world.get_mut::<Latency>().unwrap();
+        let mut conn = world.get_mut::<Connection>().unwrap();
+        if conn.id.is_none()
+            || latency
+                .last_ping
+                .is_some_and(|last| last.elapsed() < PING_INTERVAL)
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        latency
+            .pending
+            .retain(|_, sent| now.duration_since(*sent) < PING_TIMEOUT);
+        let nonce = latency.next;
+        latency.next += 1;
+        latency.pending.insert(nonce, now);
+        latency.last_ping = Some(now);
+        conn.write(Serverbound::Ping(nonce)).unwrap();
+    }
+
+    pub fn pong(world: &World, event: &Event) {
+        if let Event::Recieved(Clientbound::Pong(nonce)) = event {
+            let mut latency = world.get_mut::<Latency>().unwrap();
+            if let Some(sent) = latency.pending.remove(nonce) {
+                latency.rtt = Some(sent.elapsed());
+            }
+        }
     }
 }
 
+/// How far behind the server remote players are rendered, in seconds.
+pub const INTERPOLATION_DELAY: f32 = 2.0 / TPS;
+/// Time constant for extrapolating a remote player past their last snapshot.
+/// Confidence in their velocity decays over this, so they never overshoot by
+/// more than `velocity * EXTRAPOLATION_TIME`.
+pub const EXTRAPOLATION_TIME: f32 = 0.1;
+
 #[derive(Clone, Debug)]
 pub struct Positions {
     queue: VecDeque<(Instant, Vec3)>,
+    velocity: Vec3,
 }
 
 impl Positions {
     pub fn new() -> Self {
         Self {
             queue: VecDeque::new(),
+            velocity: Vec3::ZERO,
         }
     }
 
     pub fn push(&mut self, position: Vec3) {
-        self.queue.push_back((
-            Instant::now() + Duration::from_secs_f32(2.0 / TPS),
-            position,
-        ))
+        self.push_at(Instant::now(), position)
+    }
+
+    fn push_at(&mut self, now: Instant, position: Vec3) {
+        let time = now + Duration::from_secs_f32(INTERPOLATION_DELAY);
+        if let Some(&(last_time, last)) = self.queue.back() {
+            let dt = (time - last_time).as_secs_f32();
+            if dt > 0.0 {
+                self.velocity = (position - last) / dt;
+            }
+        }
+
+        // Blend from wherever extrapolation got to rather than snapping back
+        if self.queue.len() == 1 && self.queue[0].0 < now {
+            let extrapolated = self.get_at(now).unwrap();
+            self.queue[0] = (now, extrapolated);
+        }
+
+        self.queue.push_back((time, position))
     }
 
     pub fn get(&mut self) -> Option<Vec3> {
-        let now = Instant::now();
+        self.get_at(Instant::now())
+    }
+
+    fn get_at(&mut self, now: Instant) -> Option<Vec3> {
         match self.queue.len() {
             0 => None,
-            1 => self.queue.get(1).map(|x| x.1),
+            1 => {
+                let (time, position) = self.queue[0];
+                if time >= now {
+                    return Some(position);
+                }
+                let t = (now - time).as_secs_f32();
+                let confidence = 1.0 - (-t / EXTRAPOLATION_TIME).exp();
+                Some(position + self.velocity * EXTRAPOLATION_TIME * confidence)
+            }
             _ => {
                 let first = self.queue.front().unwrap();
                 let second = self.queue.get(1).unwrap();
                 if second.0 < now {
                     self.queue.pop_front();
-                    self.get()
+                    self.get_at(now)
                 } else {
-                    let t = (now - first.0).as_secs_f32() / (second.0 - first.0).as_secs_f32();
+                    let t = now.saturating_duration_since(first.0).as_secs_f32()
+                        / (second.0 - first.0).as_secs_f32();
                     Some(second.1 * t + first.1 * (1.0 - t))
                 }
             }
@@ -125,60 +272,174 @@ impl Positions {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LastSeen(pub Tick);
+
+impl LastSeen {
+    pub fn expired(&self, now: Tick) -> bool {
+        now.0.saturating_sub(self.0 .0) > DESPAWN_GRACE
+    }
+}
+
 #[derive(Archetype, Clone)]
 pub struct OtherPlayer {
     pub client_id: ClientId,
     pub render: RenderObject,
     pub transform: Transform,
     pub positions: Positions,
+    pub last_seen: LastSeen,
+    pub health: Health,
+    pub name: Name,
+}
+
+impl OtherPlayer {
+    pub fn all(world: &World) -> Vec<(EntityId, ClientId)> {
+        let (entities, client_ids, _) = world.query::<(EntityId, &ClientId, Is<OtherPlayer>)>();
+        entities
+            .into_iter()
+            .zip(client_ids.iter().copied())
+            .collect()
+    }
+
+    pub fn find(world: &World, client_id: ClientId) -> Option<EntityId> {
+        Self::all(world)
+            .into_iter()
+            .find(|(_, id)| *id == client_id)
+            .map(|(entity, _)| entity)
+    }
 }
 
 pub struct MovementSystem {
     positions: RefCell<HashMap<Tick, Vec3>>,
+    correction: Cell<Vec3>,
+    last_sync: Cell<Option<Tick>>,
 }
 
 impl MovementSystem {
     fn spawn(&self, world: &World, client_id: ClientId, position: Vec3) {
+        // Already known, e.g. when a sync resends everyone
+        if OtherPlayer::find(world, client_id).is_some() {
+            self.teleport_other_player(world, client_id, position);
+            return;
+        }
+
         let render = RenderObject {
             mesh: MeshId(String::from("assets/meshes/cube.glb")),
-            material: Material { colour: Vec4::ONE },
+            material: Material::new(Vec4::ONE),
+            layer: Layer::Opaque,
         };
         let mut transform = Transform::IDENTITY;
         transform.translation = position;
+        let tick = world.get::<Connection>().unwrap().tick;
         world.spawn(OtherPlayer {
             client_id,
             render,
             transform,
             positions: Positions::new(),
+            last_seen: LastSeen(tick),
+            health: Health::default(),
+            name: Name(String::new()),
         });
     }
 
+    fn spawn_player(&self, world: &World, position: Vec3) {
+        let (mut transform, _) = world.query_one::<(&mut Transform, Is<Player>)>();
+        transform.translation = position;
+        self.positions.borrow_mut().clear();
+        self.correction.set(Vec3::ZERO);
+    }
+
     fn move_player(&self, world: &World, position: Vec3, tick: Tick) {
         let (mut transform, _) = world.query_one::<(&mut Transform, Is<Player>)>();
 
-        if let Some(actual) = self.positions.borrow().get(&tick) {
-            if position == *actual {
+        let Some(actual) = self.positions.borrow().get(&tick).copied() else {
+            transform.translation = position;
+            self.correction.set(Vec3::ZERO);
+            return;
+        };
+
+        let error = position - actual;
+        if error.length() > SNAP_DISTANCE {
+            transform.translation += error;
+            self.correction.set(Vec3::ZERO);
+        } else {
+            self.correction.set(error);
+        }
+    }
+
+    fn apply_correction(&self, world: &World) {
+        let correction = self.correction.get();
+        if correction == Vec3::ZERO {
+            return;
+        }
+
+        let clock = world.get::<Clock>().unwrap();
+        let (mut transform, _) = world.query_one::<(&mut Transform, Is<Player>)>();
+        let t = 1.0 - (-CORRECTION_RATE * clock.delta.as_secs_f32()).exp();
+        let step = if correction.length() < 0.001 {
+            correction
+        } else {
+            correction * t
+        };
+        transform.translation += step;
+        self.correction.set(correction - step);
+    }
+
+    fn teleport_other_player(&self, world: &World, client_id: ClientId, position: Vec3) {
+        let Some(id) = OtherPlayer::find(world, client_id) else {
+            return;
+        };
+        if let Some(mut positions) = world.get_component_mut::<Positions>(id) {
+            *positions = Positions::new();
+            positions.push(position);
+        }
+        if let Some(mut transform) = world.get_component_mut::<Transform>(id) {
+            transform.translation = position;
+        }
+    }
+
+    /// Asks the server to resend everything, for when its Spawn was lost and
+    /// we're hearing about a player we've never seen.
+    fn request_sync(&self, world: &World) {
+        let mut conn = world.get_mut::<Connection>().unwrap();
+        let tick = conn.tick;
+        if let Some(last) = self.last_sync.get() {
+            if tick.0.saturating_sub(last.0) < SYNC_INTERVAL {
                 return;
             }
         }
-
-        transform.translation = position;
+        debug!("Requesting sync at {tick:?}");
+        self.last_sync.set(Some(tick));
+        conn.write(Serverbound::RequestSync).unwrap();
     }
 
     fn move_other_player(&self, world: &World, client_id: ClientId, position: Vec3) {
-        let (mut positions, client_ids, _) =
-            world.query::<(&mut Positions, &ClientId, Is<OtherPlayer>)>();
-        let mut n = client_ids
-            .iter()
-            .position(|other| client_id == *other)
-            .unwrap() as i64;
-
-        positions.for_each(|positions| {
-            if n == 0 {
-                positions.push(position);
-            };
-            n -= 1
-        })
+        let Some(id) = OtherPlayer::find(world, client_id) else {
+            self.request_sync(world);
+            return;
+        };
+        if let Some(mut positions) = world.get_component_mut::<Positions>(id) {
+            positions.push(position);
+        }
+        if let Some(mut last_seen) = world.get_component_mut::<LastSeen>(id) {
+            *last_seen = LastSeen(world.get::<Connection>().unwrap().tick);
+        }
+    }
+
+    fn despawn_missing(&self, world: &World) {
+        let tick = world.get::<Connection>().unwrap().tick;
+        let expired = {
+            let (entities, last_seen, _) = world.query::<(EntityId, &LastSeen, Is<OtherPlayer>)>();
+            entities
+                .into_iter()
+                .zip(last_seen.iter())
+                .filter(|(_, last_seen)| last_seen.expired(tick))
+                .map(|(entity, _)| entity)
+                .collect::<Vec<_>>()
+        };
+        expired
+            .into_iter()
+            .for_each(|id| world.despawn::<OtherPlayer>(id));
     }
 
     fn update_buffered_positions(world: &World) {
@@ -191,17 +452,37 @@ impl MovementSystem {
         });
     }
 
-    fn despawn(&self, world: &World, client_id: ClientId) {
-        let id = {
-            let (entities, client_ids, _) = world.query::<(EntityId, &ClientId, Is<OtherPlayer>)>();
-            entities
-                .iter()
-                .zip(client_ids.iter().collect::<Vec<_>>())
-                .find(|(_, id)| **id == client_id)
-                .map(|(entity, _)| *entity)
+    fn set_health(&self, world: &World, client_id: ClientId, health: f32) {
+        if Some(client_id) == world.get::<Connection>().unwrap().id {
+            let (mut current, _) = world.query_one::<(&mut Health, Is<Player>)>();
+            *current = Health(health);
+        } else if let Some(id) = OtherPlayer::find(world, client_id) {
+            if let Some(mut current) = world.get_component_mut::<Health>(id) {
+                *current = Health(health);
+            }
+        }
+    }
+
+    fn set_name(&self, world: &World, client_id: ClientId, name: &str) {
+        let Some(id) = OtherPlayer::find(world, client_id) else {
+            return;
         };
+        if let Some(mut current) = world.get_component_mut::<Name>(id) {
+            *current = Name(name.to_string());
+        }
+    }
 
-        if let Some(id) = id {
+    /// Forgets players the server no longer says are around, whose Despawn
+    /// must have been lost
+    fn reconcile(&self, world: &World, players: &[ClientId]) {
+        OtherPlayer::all(world)
+            .into_iter()
+            .filter(|(_, client_id)| !players.contains(client_id))
+            .for_each(|(id, _)| world.despawn::<OtherPlayer>(id));
+    }
+
+    fn despawn(&self, world: &World, client_id: ClientId) {
+        if let Some(id) = OtherPlayer::find(world, client_id) {
             world.despawn::<OtherPlayer>(id);
         }
     }
@@ -223,26 +504,50 @@ impl System<Event> for MovementSystem {
     fn event(&self, world: &World, event: &Event) {
         match event {
             Event::Recieved(message) => match message {
-                Clientbound::Spawn(client_id, position) => self.spawn(world, *client_id, *position),
+                Clientbound::Spawn(client_id, position) => {
+                    let id = world.get::<Connection>().unwrap().id;
+                    if Some(*client_id) == id {
+                        self.spawn_player(world, *position);
+                    } else {
+                        self.spawn(world, *client_id, *position);
+                    }
+                }
                 Clientbound::Move(client_id, position, tick) => {
-                    println!("Moving {client_id:?} from {tick:?}");
-                    let conn = world.get::<Connection>().unwrap();
-                    if *client_id == conn.id.unwrap() {
+                    debug!("Moving {client_id:?} from {tick:?}");
+                    let id = world.get::<Connection>().unwrap().id;
+                    if Some(*client_id) == id {
                         self.move_player(world, *position, *tick);
                     } else {
                         self.move_other_player(world, *client_id, *position);
                     }
                 }
+                Clientbound::Teleport(client_id, position) => {
+                    let id = world.get::<Connection>().unwrap().id;
+                    if Some(*client_id) == id {
+                        self.spawn_player(world, *position);
+                    } else {
+                        self.teleport_other_player(world, *client_id, *position);
+                    }
+                }
+                Clientbound::SetHealth(client_id, health) => {
+                    self.set_health(world, *client_id, *health)
+                }
+                Clientbound::SetName(client_id, name) => self.set_name(world, *client_id, name),
                 Clientbound::Despawn(client_id) => self.despawn(world, *client_id),
+                Clientbound::Synced(players, _) => self.reconcile(world, players),
                 _ => (),
             },
-            Event::ServerTick => self.send_player_position(world),
+            Event::ServerTick => {
+                self.send_player_position(world);
+                self.despawn_missing(world);
+            }
             _ => (),
         }
     }
 
     fn tick(&self, world: &World) {
         Self::update_buffered_positions(world);
+        self.apply_correction(world);
     }
 }
 
@@ -251,5 +556,83 @@ pub fn add(world: World) -> World {
         .register_unsaved::<OtherPlayer>()
         .with_system(MovementSystem {
             positions: RefCell::new(HashMap::new()),
+            correction: Cell::new(Vec3::ZERO),
+            last_sync: Cell::new(None),
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use nyx::protocol::PLAYER_SPEED;
+
+    use super::*;
+
+    #[test]
+    pub fn test_despawn_grace() {
+        let last_seen = LastSeen(Tick(100));
+        assert!(!last_seen.expired(Tick(100)));
+        assert!(!last_seen.expired(Tick(100 + DESPAWN_GRACE)));
+        assert!(last_seen.expired(Tick(101 + DESPAWN_GRACE)));
+        assert!(!last_seen.expired(Tick(50)));
+    }
+
+    #[test]
+    pub fn test_sync_recovers() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+        let conn = Connection {
+            socket,
+            id: Some(ClientId(0)),
+            tick: Tick(10),
+            name: None,
+        };
+        let world = add(World::new().with_resource(conn));
+        let received = |message| world.submit(Event::Recieved(message));
+        received(Clientbound::Spawn(ClientId(2), Vec3::ZERO));
+
+        // The Spawn for this player was lost, so moving it asks for a sync
+        received(Clientbound::Move(ClientId(1), Vec3::X, Tick(10)));
+        assert!(OtherPlayer::find(&world, ClientId(1)).is_none());
+        let mut buffer = [0; MAX_MESSAGE_LEN];
+        let n = server.recv(&mut buffer).unwrap();
+        assert!(matches!(
+            protocol::decode::<Serverbound>(&buffer[..n]),
+            Ok(Serverbound::RequestSync)
+        ));
+
+        // Player 2 left while its Despawn was lost too
+        received(Clientbound::Synced(vec![ClientId(1)], Vec::new()));
+        received(Clientbound::Spawn(ClientId(1), Vec3::X));
+        assert!(OtherPlayer::find(&world, ClientId(1)).is_some());
+        assert!(OtherPlayer::find(&world, ClientId(2)).is_none());
+    }
+
+    #[test]
+    pub fn test_abrupt_stop() {
+        let start = Instant::now();
+        let tick = Duration::from_secs_f32(1.0 / TPS);
+        let step = Vec3::X * PLAYER_SPEED / TPS;
+        let mut positions = Positions::new();
+
+        (0..10).for_each(|i| positions.push_at(start + tick * i, step * i as f32));
+        let last = step * 9.0;
+        let bound = last.x + PLAYER_SPEED * EXTRAPOLATION_TIME + 1e-4;
+
+        // Snapshots stop for a second, then one arrives showing them stood still
+        let resumed = start + tick * 30;
+        let mut stopped = false;
+        let mut now = start;
+        while now < resumed + Duration::from_secs(1) {
+            if now >= resumed && !stopped {
+                positions.push_at(now, last);
+                stopped = true;
+            }
+            let position = positions.get_at(now).unwrap();
+            assert!(position.x <= bound, "{position} overshot {bound}");
+            now += Duration::from_millis(5);
+        }
+        assert!((positions.get_at(now).unwrap() - last).length() < 1e-4);
+    }
+}
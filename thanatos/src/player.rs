@@ -1,18 +1,30 @@
 use crate::{
-    camera::Camera, renderer::RenderObject, transform::Transform, window::Keyboard, Clock, World,
+    camera::Camera, event::Event, renderer::RenderObject, transform::Transform,
+    window::Keyboard, Clock, World,
+};
+use glam::Vec3;
+use nyx::{
+    movement::integrate,
+    protocol::{Clientbound, PLAYER_HEALTH, PLAYER_SPEED},
 };
-use glam::{Quat, Vec3};
 use serde::{Deserialize, Serialize};
 use tecs::prelude::*;
 
-const SPEED: f32 = 5.0;
-
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
 pub struct Health(pub f32);
 
 impl Default for Health {
     fn default() -> Self {
-        Self(100.0)
+        Self(PLAYER_HEALTH)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Speed(pub f32);
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self(PLAYER_SPEED)
     }
 }
 
@@ -22,48 +34,56 @@ pub struct Player {
     pub transform: Transform,
     #[serde(skip)]
     pub health: Health,
+    #[serde(skip)]
+    pub speed: Speed,
 }
 
 impl Player {
-    pub fn death(world: &World) {
-        let (mut health, mut transform, _) =
-            world.query_one::<(&mut Health, &mut Transform, Is<Player>)>();
-
-        if health.0 < 0.0 {
-            transform.translation = Vec3::ZERO;
-            health.0 = 100.0;
-        }
-    }
-
     pub fn tick(world: &World) {
         let keyboard = world.get::<Keyboard>().unwrap();
         let mut camera = world.get_mut::<Camera>().unwrap();
         let clock = world.get::<Clock>().unwrap();
 
-        let (mut transform, _) = world.query_one::<(&mut Transform, Is<Player>)>();
-
-        let rotation = Quat::from_rotation_y(camera.theta);
+        let (mut transform, speed, _) =
+            world.query_one::<(&mut Transform, &Speed, Is<Player>)>();
 
+        let mut direction = Vec3::ZERO;
         if keyboard.is_down("w") {
-            transform.translation += rotation * Vec3::Z * SPEED * clock.delta.as_secs_f32();
+            direction += Vec3::Z;
         }
 
         if keyboard.is_down("s") {
-            transform.translation -= rotation * Vec3::Z * SPEED * clock.delta.as_secs_f32();
+            direction -= Vec3::Z;
         }
 
         if keyboard.is_down("a") {
-            transform.translation += rotation * Vec3::X * SPEED * clock.delta.as_secs_f32();
+            direction += Vec3::X;
         }
 
         if keyboard.is_down("d") {
-            transform.translation -= rotation * Vec3::X * SPEED * clock.delta.as_secs_f32();
+            direction -= Vec3::X;
         }
 
+        transform.translation = integrate(
+            transform.translation,
+            camera.rotation() * direction,
+            speed.0,
+            clock.delta.as_secs_f32(),
+        );
+
         camera.target = transform.translation;
     }
+
+    pub fn event(world: &World, event: &Event) {
+        if let Event::Recieved(Clientbound::SetSpeed(new)) = event {
+            let (mut speed, _) = world.query_one::<(&mut Speed, Is<Player>)>();
+            speed.0 = *new;
+        }
+    }
 }
 
 pub fn add(world: World) -> World {
-    world.with_ticker(Player::tick).with_ticker(Player::death)
+    world
+        .with_ticker(Player::tick)
+        .with_handler(Player::event)
 }
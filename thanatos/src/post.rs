@@ -0,0 +1,94 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use hephaestus::{
+    descriptor,
+    image::{ImageView, Sampler},
+    pipeline::{
+        self, AttachmentInfo, ImageLayout, PipelineBindPoint, RenderPass, ShaderModule, Subpass,
+        Viewport,
+    },
+    vertex, AttachmentLoadOp, AttachmentStoreOp, Context, DescriptorType, Format,
+    SampleCountFlags,
+};
+
+use crate::assets::Assets;
+
+/// The scene is lit into this, so highlights keep their detail until they're
+/// tone mapped rather than clipping at 1
+pub const HDR_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+/// Enough sets for one HDR target per swapchain image
+const MAX_TARGETS: usize = 16;
+
+/// Tone maps the scene's HDR target onto the swapchain image, then draws the
+/// UI over it in a second subpass.
+pub struct PostPass {
+    pub render_pass: RenderPass,
+    pub pipeline: pipeline::Graphics,
+    layout: Rc<descriptor::Layout>,
+    sampler: Rc<Sampler>,
+}
+
+impl PostPass {
+    pub fn new(ctx: &Context, assets: &Assets, camera_layout: &descriptor::Layout) -> Result<Self> {
+        let vertex = ShaderModule::new(&ctx.device, &assets.read("shaders/tonemap.vert.spv")?)?;
+        let fragment = ShaderModule::new(&ctx.device, &assets.read("shaders/tonemap.frag.spv")?)?;
+
+        let render_pass = {
+            let mut builder = RenderPass::builder();
+            let colour = builder.attachment(
+                ctx.swapchain.as_ref().unwrap().format,
+                AttachmentInfo {
+                    initial_layout: ImageLayout::UNDEFINED,
+                    final_layout: ImageLayout::PRESENT_SRC_KHR,
+                    // Every pixel is written by the tone mapping
+                    load_op: AttachmentLoadOp::DONT_CARE,
+                    store_op: AttachmentStoreOp::STORE,
+                    samples: SampleCountFlags::TYPE_1,
+                },
+            );
+            builder.subpass(
+                Subpass::new(PipelineBindPoint::GRAPHICS)
+                    .colour(colour, ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            );
+            builder.subpass(
+                Subpass::new(PipelineBindPoint::GRAPHICS)
+                    .colour(colour, ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            );
+            builder.build(&ctx.device)?
+        };
+
+        let layout =
+            descriptor::Layout::new(ctx, &[DescriptorType::COMBINED_IMAGE_SAMPLER], MAX_TARGETS)?;
+
+        // The vertex shader makes a full screen triangle from the vertex
+        // index, so there are no vertex buffers
+        let pipeline = pipeline::Graphics::builder()
+            .vertex(&vertex)
+            .vertex_info(vertex::Info::new(0))
+            .fragment(&fragment)
+            .render_pass(&render_pass)
+            .subpass(0)
+            .viewport(Viewport::Dynamic)
+            .layouts(vec![camera_layout, &layout])
+            .build(&ctx.device)?;
+
+        let sampler = Sampler::new(&ctx.device)?;
+
+        Ok(Self {
+            render_pass,
+            pipeline,
+            layout,
+            sampler,
+        })
+    }
+
+    /// A set reading `view`, an HDR target the scene pass has resolved into
+    pub fn set(&self, view: &Rc<ImageView>) -> Result<Rc<descriptor::Set>> {
+        Ok(self
+            .layout
+            .alloc()?
+            .write_image(0, view, &self.sampler, ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .finish()?)
+    }
+}
@@ -1,40 +1,67 @@
-use std::{collections::VecDeque, mem::size_of, rc::Rc};
+use std::{
+    cmp::Ordering, collections::VecDeque, mem::size_of, ops::Range, rc::Rc, time::Duration,
+};
 
 use crate::{
-    assets::{Material, MeshCache, MeshId},
-    camera::Camera,
+    assets::{Assets, Material, Mesh, MeshCache, MeshId},
+    camera::{Camera, Frustum},
     event::Event,
+    light::{GpuLight, Light},
+    post::{PostPass, HDR_FORMAT},
+    shadow::{ShadowMap, SHADOW_SIZE},
     transform::Transform,
     window::{Mouse, Window},
     World,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytemuck::offset_of;
-use glam::{Vec2, Vec3};
+use glam::{Mat4, Vec2, Vec3, Vec4};
 use hephaestus::{
     buffer::Static,
+    command::Recorder,
     descriptor,
     image::{Image, ImageInfo, ImageView},
     pipeline::{
         self, clear_colour, clear_depth, AttachmentInfo, Framebuffer, ImageLayout,
         PipelineBindPoint, RenderPass, ShaderModule, Subpass, Viewport,
     },
+    query::TimestampPool,
     task::{Fence, Semaphore, SubmitInfo, Task},
     vertex::{self, AttributeType},
     AttachmentLoadOp, AttachmentStoreOp, BufferUsageFlags, Context, DescriptorType, Extent2D,
-    Format, ImageAspectFlags, ImageUsageFlags, PipelineStageFlags, SampleCountFlags, VkResult,
+    Feature, Format, ImageAspectFlags, ImageUsageFlags, PipelineStageFlags, PresentModeKHR,
+    SampleCountFlags, VkResult, OUT_OF_DATE, SURFACE_LOST,
 };
-use log::info;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use styx::{Element, Font, FontSettings, Signals};
-use tecs::EntityId;
+use tecs::{utils::Clock, EntityId};
 use winit::event::MouseButton;
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Default, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Clone, Copy, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
+    /// xyz is the tangent, w the bitangent handedness
+    pub tangent: [f32; 4],
+    /// Indices into the frame's joint matrices, relative to the mesh's
+    /// skeleton until the renderer offsets them
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+impl Default for Vertex {
+    /// Entirely bound to joint 0, which is always the identity
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            normal: Vec3::ZERO,
+            tangent: [0.0; 4],
+            joints: [0; 4],
+            weights: [1.0, 0.0, 0.0, 0.0],
+        }
+    }
 }
 
 impl Vertex {
@@ -42,12 +69,138 @@ impl Vertex {
         vertex::Info::new(size_of::<Self>())
             .attribute(AttributeType::Vec3, 0)
             .attribute(AttributeType::Vec3, offset_of!(Vertex, normal))
+            .attribute(AttributeType::Vec4, offset_of!(Vertex, tangent))
+            .attribute(AttributeType::UVec4, offset_of!(Vertex, joints))
+            .attribute(AttributeType::Vec4, offset_of!(Vertex, weights))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SceneStats {
+    pub meshes: usize,
+    pub vertices: usize,
+    pub indices: usize,
+}
+
+impl SceneStats {
+    pub fn add(&mut self, mesh: &Mesh) {
+        self.meshes += 1;
+        self.vertices += mesh.vertices.len();
+        self.indices += mesh.indices.len();
+    }
+}
+
+/// The requested MSAA sample count if the device supports it for both colour
+/// and depth, otherwise no multisampling at all.
+pub fn pick_samples(supported: SampleCountFlags, requested: u32) -> SampleCountFlags {
+    let samples = SampleCountFlags::from_raw(requested);
+    if requested.is_power_of_two() && supported.contains(samples) {
+        return samples;
+    }
+    warn!("{requested}x MSAA isn't supported, falling back to 1x");
+    SampleCountFlags::TYPE_1
+}
+
+/// The scene pass's colour attachment, and when multisampled the single
+/// sampled attachment it's resolved into for the post pass to read. Vulkan
+/// can't resolve a single sampled attachment, so at 1x the colour attachment
+/// is read directly instead.
+fn scene_colour(samples: SampleCountFlags) -> (AttachmentInfo, Option<AttachmentInfo>) {
+    let sampled = AttachmentInfo {
+        initial_layout: ImageLayout::UNDEFINED,
+        final_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        load_op: AttachmentLoadOp::DONT_CARE,
+        store_op: AttachmentStoreOp::STORE,
+        samples: SampleCountFlags::TYPE_1,
+    };
+    if samples == SampleCountFlags::TYPE_1 {
+        let colour = AttachmentInfo {
+            load_op: AttachmentLoadOp::CLEAR,
+            ..sampled
+        };
+        return (colour, None);
+    }
+    let colour = AttachmentInfo {
+        initial_layout: ImageLayout::UNDEFINED,
+        final_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        load_op: AttachmentLoadOp::CLEAR,
+        store_op: AttachmentStoreOp::DONT_CARE,
+        samples,
+    };
+    (colour, Some(sampled))
+}
+
+/// How lit colours are squeezed into the displayable range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Tonemap {
+    #[default]
+    Aces,
+    Reinhard,
+}
+
+impl std::str::FromStr for Tonemap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aces" => Ok(Self::Aces),
+            "reinhard" => Ok(Self::Reinhard),
+            _ => Err(anyhow::anyhow!("unknown tonemap {s:?}")),
+        }
+    }
+}
+
+/// Parses `r,g,b` or `r,g,b,a`, with alpha defaulting to 1
+pub fn parse_colour(s: &str) -> Result<[f32; 4]> {
+    let channels = s
+        .split(',')
+        .map(|channel| channel.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match *channels {
+        [r, g, b] => Ok([r, g, b, 1.0]),
+        [r, g, b, a] => Ok([r, g, b, a]),
+        _ => Err(anyhow::anyhow!("expected 3 or 4 channels in {s:?}")),
+    }
+}
+
+/// Whether wireframe can be turned on, warning when the device can't draw it
+pub fn wireframe_supported(supported: bool, requested: bool) -> bool {
+    if requested && !supported {
+        warn!("Wireframe isn't supported by this device, ignoring");
+        return false;
+    }
+    requested
+}
+
+/// How long the GPU spent on each pass of a frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpuTimes {
+    pub shadows: Duration,
+    /// The scene, its tone mapping and the UI
+    pub scene: Duration,
+}
+
+impl GpuTimes {
+    /// One timestamp before the shadow pass, one between the passes and one
+    /// after the scene pass
+    const TIMESTAMPS: u32 = 3;
+
+    fn from_durations(durations: &[Duration]) -> Option<Self> {
+        match *durations {
+            [shadows, scene] => Some(Self { shadows, scene }),
+            _ => None,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.shadows + self.scene
     }
 }
 
 struct Frame {
     task: Task,
     fence: Rc<Fence>,
+    timestamps: Option<Rc<TimestampPool>>,
 }
 
 impl Drop for Frame {
@@ -56,10 +209,60 @@ impl Drop for Frame {
     }
 }
 
+/// Objects are drawn a layer at a time, in the order declared here.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Layer {
+    #[default]
+    Opaque,
+    Transparent,
+}
+
+impl Layer {
+    /// Splits a slice sorted by layer into the range each layer occupies.
+    pub fn ranges(sorted: &[Layer]) -> Vec<(Layer, Range<usize>)> {
+        let mut ranges: Vec<(Layer, Range<usize>)> = Vec::new();
+        sorted.iter().enumerate().for_each(|(i, layer)| match ranges.last_mut() {
+            Some((last, range)) if last == layer => range.end = i + 1,
+            _ => ranges.push((*layer, i..i + 1)),
+        });
+        ranges
+    }
+
+    /// Sorts `objects` layer by layer, transparent ones back to front so
+    /// they blend over what's behind them, and returns each layer's range.
+    pub fn sort<T>(
+        objects: &mut [T],
+        key: impl Fn(&T) -> (Layer, f32),
+    ) -> Vec<(Layer, Range<usize>)> {
+        objects.sort_by(|a, b| {
+            let (a, b) = (key(a), key(b));
+            a.0.cmp(&b.0).then_with(|| match a.0 {
+                Layer::Transparent => b.1.total_cmp(&a.1),
+                Layer::Opaque => Ordering::Equal,
+            })
+        });
+        Self::ranges(&objects.iter().map(|object| key(object).0).collect::<Vec<_>>())
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RenderObject {
     pub mesh: MeshId,
     pub material: Material,
+    #[serde(default)]
+    pub layer: Layer,
+}
+
+impl RenderObject {
+    /// The layer actually drawn in, translucent materials and meshes are
+    /// always moved to `Layer::Transparent`.
+    pub fn layer(&self, mesh: &Mesh) -> Layer {
+        if self.material.colour.w < 1.0 || mesh.transparent {
+            Layer::Transparent
+        } else {
+            self.layer
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -68,6 +271,7 @@ pub enum Anchor {
     Cursor,
     Center,
     BottomRight,
+    Absolute(Vec2),
 }
 
 pub struct Ui {
@@ -78,27 +282,41 @@ pub struct Ui {
 }
 
 impl Ui {
-    pub fn new() -> Self {
-        let font = Rc::new(
-            Font::from_bytes(
-                std::fs::read("assets/fonts/JetBrainsMono-Medium.ttf").unwrap(),
-                FontSettings::default(),
-            )
-            .unwrap(),
-        );
+    pub fn new(assets: &Assets) -> Result<Self> {
+        let font = Font::from_bytes(
+            assets.read("fonts/JetBrainsMono-Medium.ttf")?,
+            FontSettings::default(),
+        )
+        .map_err(|e| anyhow!("Invalid font: {e}"))?;
 
-        Self {
-            font,
+        Ok(Self {
+            font: Rc::new(font),
             signals: Signals::default(),
             events: Vec::new(),
             elements: Vec::new(),
-        }
+        })
     }
 
     pub fn add<T: Element + 'static>(&mut self, anchor: Anchor, element: T) {
         self.elements.push((anchor, Box::new(element)))
     }
 
+    pub fn text<T: ToString>(&mut self, position: Vec2, text: T, font_size: f32) {
+        let text = styx::components::text(text, font_size, self.font.clone());
+        self.add(Anchor::Absolute(position), text)
+    }
+
+    pub fn rect(&mut self, position: Vec2, size: Vec2, colour: Vec4) {
+        self.add(
+            Anchor::Absolute(position),
+            styx::components::Rect {
+                size,
+                colour,
+                radius: 0.0,
+            },
+        )
+    }
+
     pub fn event(world: &World, event: &Event) {
         let event = match event {
             Event::MousePress(button) => {
@@ -136,6 +354,7 @@ impl Ui {
                 Anchor::Center => (window_size - size) / 2.0,
                 Anchor::Cursor => mouse.position,
                 Anchor::BottomRight => window_size - size,
+                Anchor::Absolute(position) => *position,
             };
 
             element.paint(
@@ -156,49 +375,71 @@ impl Ui {
 pub struct Renderer {
     render_pass: RenderPass,
     pipeline: pipeline::Graphics,
+    transparent: pipeline::Graphics,
+    wireframe: Option<pipeline::Graphics>,
+    show_wireframe: bool,
+    shadows: ShadowMap,
+    post: PostPass,
     ui: styx::Renderer,
     framebuffers: Vec<Framebuffer>,
+    post_framebuffers: Vec<Framebuffer>,
+    /// The post pass's view of each HDR target
+    hdr_sets: Vec<Rc<descriptor::Set>>,
     semaphores: Vec<Rc<Semaphore>>,
     frame_index: usize,
     tasks: VecDeque<Frame>,
     camera_layout: Rc<descriptor::Layout>,
     object_layout: Rc<descriptor::Layout>,
-    images: Vec<(Rc<Image>, Rc<Image>)>,
-    views: Vec<(Rc<ImageView>, Rc<ImageView>)>,
+    /// Colour, depth and resolve, which is only there when multisampling
+    images: Vec<(Rc<Image>, Rc<Image>, Option<Rc<Image>>)>,
+    views: Vec<(Rc<ImageView>, Rc<ImageView>, Option<Rc<ImageView>>)>,
+    samples: SampleCountFlags,
+    clear_colour: [f32; 4],
+    hdr: bool,
+    tonemap: Tonemap,
+    exposure: f32,
+    /// One per frame that can be in flight, empty if timestamps aren't
+    /// supported
+    timestamps: Vec<Rc<TimestampPool>>,
+    gpu_times: Option<GpuTimes>,
+    stats: SceneStats,
     pub ctx: Context,
 }
 
 impl Renderer {
     pub const FRAMES_IN_FLIGHT: usize = 3;
 
-    pub fn new(window: &Window) -> Result<Self> {
+    /// `msaa` is the number of samples per pixel, or the most the device
+    /// supports if `None`.
+    pub fn new(window: &Window, assets: &Assets, msaa: Option<u32>) -> Result<Self> {
         let size = window.window.inner_size();
-        let ctx = Context::new("thanatos", &window.window, (size.width, size.height))?;
-
-        let vertex = ShaderModule::new(
-            &ctx.device,
-            &std::fs::read("assets/shaders/shader.vert.spv").unwrap(),
-        )?;
-
-        let fragment = ShaderModule::new(
-            &ctx.device,
-            &std::fs::read("assets/shaders/shader.frag.spv").unwrap(),
-        )?;
-
-        let samples = ctx.device.physical.get_samples();
+        // Objects are drawn with one indirect call when the device supports
+        // it, otherwise one draw_indexed per object
+        let ctx = Context::builder("thanatos")
+            .optional(Feature::MultiDrawIndirect)
+            .optional(Feature::DrawIndirectFirstInstance)
+            .optional(Feature::Timestamps)
+            .optional(Feature::FillModeNonSolid)
+            .build(&window.window, (size.width, size.height))?;
+
+        let vertex = ShaderModule::new(&ctx.device, &assets.read("shaders/shader.vert.spv")?)?;
+        let fragment = ShaderModule::new(&ctx.device, &assets.read("shaders/shader.frag.spv")?)?;
+
+        let samples = match msaa {
+            Some(requested) => {
+                let limits = ctx.limits();
+                let supported = limits.framebuffer_color_sample_counts
+                    & limits.framebuffer_depth_sample_counts;
+                pick_samples(supported, requested)
+            }
+            None => ctx.device.physical.get_samples(),
+        };
+        info!("Using {}x MSAA", samples.as_raw());
 
         let render_pass = {
             let mut builder = RenderPass::builder();
-            let colour = builder.attachment(
-                ctx.swapchain.as_ref().unwrap().format,
-                AttachmentInfo {
-                    initial_layout: ImageLayout::UNDEFINED,
-                    final_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-                    load_op: AttachmentLoadOp::CLEAR,
-                    store_op: AttachmentStoreOp::DONT_CARE,
-                    samples,
-                },
-            );
+            let (colour, resolve) = scene_colour(samples);
+            let colour = builder.attachment(HDR_FORMAT, colour);
 
             let depth = builder.attachment(
                 Format::D32_SFLOAT,
@@ -211,34 +452,26 @@ impl Renderer {
                 },
             );
 
-            let resolve = builder.attachment(
-                ctx.swapchain.as_ref().unwrap().format,
-                AttachmentInfo {
-                    initial_layout: ImageLayout::UNDEFINED,
-                    final_layout: ImageLayout::PRESENT_SRC_KHR,
-                    load_op: AttachmentLoadOp::DONT_CARE,
-                    store_op: AttachmentStoreOp::STORE,
-                    samples: SampleCountFlags::TYPE_1,
-                },
-            );
-
-            builder.subpass(
-                Subpass::new(PipelineBindPoint::GRAPHICS)
-                    .colour(colour, ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .depth(depth, ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                    .resolve(resolve, ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
-            );
-            builder.subpass(
-                Subpass::new(PipelineBindPoint::GRAPHICS)
-                    .colour(colour, ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .resolve(resolve, ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
-            );
+            let subpass = Subpass::new(PipelineBindPoint::GRAPHICS)
+                .colour(colour, ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .depth(depth, ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+            let subpass = match resolve {
+                Some(resolve) => {
+                    let resolve = builder.attachment(HDR_FORMAT, resolve);
+                    subpass.resolve(resolve, ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                }
+                None => subpass,
+            };
+            builder.subpass(subpass);
+            builder.sampled_colour();
             builder.build(&ctx.device)?
         };
 
         let camera_layout = descriptor::Layout::new(&ctx, &[DescriptorType::UNIFORM_BUFFER], 1000)?;
         let object_layout =
-            descriptor::Layout::new(&ctx, &[DescriptorType::STORAGE_BUFFER; 2], 1000)?;
+            descriptor::Layout::new(&ctx, &[DescriptorType::STORAGE_BUFFER; 4], 1000)?;
+
+        let shadows = ShadowMap::new(&ctx, assets, Vertex::info(), &camera_layout, &object_layout)?;
 
         let pipeline = pipeline::Graphics::builder()
             .vertex(&vertex)
@@ -247,37 +480,78 @@ impl Renderer {
             .render_pass(&render_pass)
             .subpass(0)
             .viewport(Viewport::Dynamic)
-            .layouts(vec![&camera_layout, &object_layout])
+            .layouts(vec![&camera_layout, &object_layout, &shadows.layout])
             .depth()
             .multisampled(samples)
             .build(&ctx.device)?;
 
-        let ui = styx::Renderer::new(&ctx, &render_pass, 1)?;
+        let transparent = pipeline::Graphics::builder()
+            .vertex(&vertex)
+            .vertex_info(Vertex::info())
+            .fragment(&fragment)
+            .render_pass(&render_pass)
+            .subpass(0)
+            .viewport(Viewport::Dynamic)
+            .layouts(vec![&camera_layout, &object_layout, &shadows.layout])
+            .depth_read_only()
+            .multisampled(samples)
+            .build(&ctx.device)?;
 
-        let (images, views) = Self::create_images(&ctx)?;
+        let wireframe = if ctx.device.enabled(Feature::FillModeNonSolid) {
+            Some(
+                pipeline::Graphics::builder()
+                    .vertex(&vertex)
+                    .vertex_info(Vertex::info())
+                    .fragment(&fragment)
+                    .render_pass(&render_pass)
+                    .subpass(0)
+                    .viewport(Viewport::Dynamic)
+                    .layouts(vec![&camera_layout, &object_layout, &shadows.layout])
+                    .depth()
+                    .wireframe()
+                    .multisampled(samples)
+                    .build(&ctx.device)?,
+            )
+        } else {
+            None
+        };
 
-        let framebuffers = ctx
-            .swapchain
-            .as_ref()
-            .unwrap()
-            .views
-            .iter()
-            .zip(&views)
-            .map(|(resolve, (colour, depth))| {
-                render_pass.get_framebuffer(&ctx.device, &[colour, depth, resolve])
-            })
-            .collect::<VkResult<Vec<Framebuffer>>>()?;
+        let post = PostPass::new(&ctx, assets, &camera_layout)?;
+        // Drawn after tone mapping, so the UI's colours are displayed as is
+        let ui = styx::Renderer::new(
+            &ctx,
+            &assets.resolve("shaders"),
+            &post.render_pass,
+            1,
+            SampleCountFlags::TYPE_1,
+        )?;
+
+        let (images, views) = Self::create_images(&ctx, samples)?;
+        let (framebuffers, post_framebuffers, hdr_sets) =
+            Self::create_framebuffers(&ctx, &render_pass, &post, &views)?;
 
         let semaphores = (0..Self::FRAMES_IN_FLIGHT)
             .map(|_| Semaphore::new(&ctx.device))
             .collect::<VkResult<Vec<Rc<Semaphore>>>>()?;
+        // One more than FRAMES_IN_FLIGHT, as that many frames are queued
+        // before the oldest is waited on
+        let timestamps = (0..=Self::FRAMES_IN_FLIGHT)
+            .filter_map(|_| TimestampPool::new(&ctx.device, GpuTimes::TIMESTAMPS).transpose())
+            .collect::<VkResult<Vec<_>>>()?;
 
         Ok(Self {
             ctx,
             render_pass,
             pipeline,
+            transparent,
+            wireframe,
+            show_wireframe: false,
+            shadows,
+            post,
             ui,
             framebuffers,
+            post_framebuffers,
+            hdr_sets,
             semaphores,
             frame_index: 0,
             tasks: VecDeque::new(),
@@ -285,27 +559,90 @@ impl Renderer {
             object_layout,
             images,
             views,
+            samples,
+            clear_colour: [0.0, 0.0, 0.0, 1.0],
+            hdr: true,
+            tonemap: Tonemap::default(),
+            exposure: 1.0,
+            timestamps,
+            gpu_times: None,
+            stats: SceneStats::default(),
         })
     }
 
-    pub fn add(self) -> impl FnOnce(World) -> World {
+    /// Draws every layer as triangle edges, does nothing if the device
+    /// can't fill polygons as lines
+    pub fn set_wireframe(&mut self, wireframe: bool) {
+        self.show_wireframe = wireframe_supported(self.wireframe.is_some(), wireframe);
+    }
+
+    /// FIFO waits for vblank, IMMEDIATE doesn't, falls back to FIFO if the
+    /// surface can't present in `present_mode`
+    pub fn set_present_mode(&mut self, present_mode: PresentModeKHR) -> Result<()> {
+        self.ctx.set_present_mode(present_mode);
+        let extent = self.ctx.surface.extent;
+        self.recreate_swapchain((extent.width, extent.height))?;
+        Ok(())
+    }
+
+    pub fn set_hdr(&mut self, hdr: bool) {
+        self.hdr = hdr;
+    }
+
+    pub fn set_tonemap(&mut self, tonemap: Tonemap) {
+        self.tonemap = tonemap;
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    /// Depth subtracted before comparing against the shadow map, raise it if
+    /// lit surfaces show shadow acne
+    pub fn set_shadow_bias(&mut self, bias: f32) {
+        self.shadows.bias = bias;
+    }
+
+    pub fn gpu_times(&self) -> Option<GpuTimes> {
+        self.gpu_times
+    }
+
+    /// Size of the last frame's scene
+    pub fn stats(&self) -> SceneStats {
+        self.stats
+    }
+
+    /// Linear colour behind the scene, tone mapped along with it
+    pub fn set_clear_colour(&mut self, colour: [f32; 4]) {
+        self.clear_colour = colour;
+    }
+
+    pub fn add(self, ui: Ui) -> impl FnOnce(World) -> World {
         move |world| {
             world
                 .with_resource(self)
-                .with_resource(Ui::new())
+                .with_resource(ui)
                 .with_ticker(Self::draw)
+                .with_handler(Self::resize)
                 .with_handler(Ui::event)
         }
     }
 
     fn create_images(
         ctx: &Context,
+        samples: SampleCountFlags,
     ) -> VkResult<(
-        Vec<(Rc<Image>, Rc<Image>)>,
-        Vec<(Rc<ImageView>, Rc<ImageView>)>,
+        Vec<(Rc<Image>, Rc<Image>, Option<Rc<Image>>)>,
+        Vec<(Rc<ImageView>, Rc<ImageView>, Option<Rc<ImageView>>)>,
     )> {
         let swapchain = ctx.swapchain.as_ref().unwrap();
-        let samples = ctx.device.physical.get_samples();
+        let resolves = samples != SampleCountFlags::TYPE_1;
+        // Without a resolve the post pass samples the colour image itself
+        let colour_usage = if resolves {
+            ImageUsageFlags::COLOR_ATTACHMENT
+        } else {
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED
+        };
         let images = ctx
             .swapchain
             .as_ref()
@@ -317,9 +654,9 @@ impl Renderer {
                     Image::new(
                         ctx,
                         ImageInfo {
-                            format: swapchain.format,
+                            format: HDR_FORMAT,
                             extent: swapchain.extent,
-                            usage: ImageUsageFlags::COLOR_ATTACHMENT,
+                            usage: colour_usage,
                             samples,
                         },
                     )?,
@@ -332,18 +669,32 @@ impl Renderer {
                             samples,
                         },
                     )?,
+                    resolves
+                        .then(|| {
+                            Image::new(
+                                ctx,
+                                ImageInfo {
+                                    format: HDR_FORMAT,
+                                    extent: swapchain.extent,
+                                    usage: ImageUsageFlags::COLOR_ATTACHMENT
+                                        | ImageUsageFlags::SAMPLED,
+                                    samples: SampleCountFlags::TYPE_1,
+                                },
+                            )
+                        })
+                        .transpose()?,
                 ))
             })
             .collect::<VkResult<Vec<_>>>()?;
 
         let views = images
             .iter()
-            .map(|(colour, depth)| {
+            .map(|(colour, depth, resolve)| {
                 Ok((
                     ImageView::new(
                         &ctx.device,
                         &colour,
-                        swapchain.format,
+                        HDR_FORMAT,
                         ImageAspectFlags::COLOR,
                         swapchain.extent,
                     )?,
@@ -354,6 +705,18 @@ impl Renderer {
                         ImageAspectFlags::DEPTH,
                         ctx.swapchain.as_ref().unwrap().extent,
                     )?,
+                    resolve
+                        .as_ref()
+                        .map(|resolve| {
+                            ImageView::new(
+                                &ctx.device,
+                                resolve,
+                                HDR_FORMAT,
+                                ImageAspectFlags::COLOR,
+                                swapchain.extent,
+                            )
+                        })
+                        .transpose()?,
                 ))
             })
             .collect::<VkResult<Vec<_>>>()?;
@@ -361,43 +724,197 @@ impl Renderer {
         Ok((images, views))
     }
 
-    pub fn recreate_swapchain(&mut self, size: (u32, u32)) -> VkResult<()> {
+    /// The scene and post pass framebuffers for each swapchain image, and the
+    /// sets the post pass reads each HDR target through
+    fn create_framebuffers(
+        ctx: &Context,
+        render_pass: &RenderPass,
+        post: &PostPass,
+        views: &[(Rc<ImageView>, Rc<ImageView>, Option<Rc<ImageView>>)],
+    ) -> Result<(Vec<Framebuffer>, Vec<Framebuffer>, Vec<Rc<descriptor::Set>>)> {
+        let framebuffers = views
+            .iter()
+            .map(|(colour, depth, resolve)| {
+                let attachments = [colour, depth]
+                    .into_iter()
+                    .chain(resolve)
+                    .map(Rc::as_ref)
+                    .collect::<Vec<&ImageView>>();
+                render_pass.get_framebuffer(&ctx.device, &attachments)
+            })
+            .collect::<VkResult<Vec<Framebuffer>>>()?;
+        let post_framebuffers = ctx
+            .swapchain
+            .as_ref()
+            .unwrap()
+            .views
+            .iter()
+            .map(|view| post.render_pass.get_framebuffer(&ctx.device, &[view]))
+            .collect::<VkResult<Vec<Framebuffer>>>()?;
+        let hdr_sets = views
+            .iter()
+            .map(|(colour, _, resolve)| post.set(resolve.as_ref().unwrap_or(colour)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((framebuffers, post_framebuffers, hdr_sets))
+    }
+
+    pub fn recreate_swapchain(&mut self, size: (u32, u32)) -> Result<()> {
         unsafe { self.ctx.device.device_wait_idle()? }
         self.ctx.surface.extent = Extent2D {
             width: size.0,
             height: size.1,
         };
-        self.ctx.recreate_swapchain().unwrap();
+        self.ctx.recreate_swapchain()?;
 
         self.framebuffers.clear();
+        self.post_framebuffers.clear();
+        self.hdr_sets.clear();
         self.views.clear();
         self.images.clear();
 
-        let (images, views) = Self::create_images(&self.ctx)?;
+        let (images, views) = Self::create_images(&self.ctx, self.samples)?;
+        let (framebuffers, post_framebuffers, hdr_sets) =
+            Self::create_framebuffers(&self.ctx, &self.render_pass, &self.post, &views)?;
         self.images = images;
         self.views = views;
-
-        self.framebuffers = self
-            .ctx
-            .swapchain
-            .as_ref()
-            .unwrap()
-            .views
-            .iter()
-            .zip(&self.views)
-            .map(|(resolve, (colour, depth))| {
-                self.render_pass
-                    .get_framebuffer(&self.ctx.device, &[colour, depth, resolve])
-            })
-            .collect::<VkResult<Vec<Framebuffer>>>()?;
+        self.framebuffers = framebuffers;
+        self.post_framebuffers = post_framebuffers;
+        self.hdr_sets = hdr_sets;
 
         Ok(())
     }
 
+    /// Rebuilds everything from a fresh surface after the old one was lost
+    fn recreate_surface(&mut self, window: &Window, size: (u32, u32)) -> Result<()> {
+        unsafe { self.ctx.device.device_wait_idle()? }
+        self.ctx.recreate_surface(&window.window, size)?;
+        self.recreate_swapchain(size)
+    }
+
+    /// Recreates the swapchain, and the surface too if it was lost. Going out
+    /// of date part way through leaves the rest to the next frame, as `draw`
+    /// recreates whatever swapchain is missing or out of date.
+    fn rebuild(&mut self, window: &Window, size: (u32, u32)) -> Result<()> {
+        let Err(e) = self.recreate_swapchain(size) else {
+            return Ok(());
+        };
+        if e.downcast_ref() == Some(&SURFACE_LOST) {
+            warn!("Surface lost, recreating it");
+            self.recreate_surface(window, size)
+        } else if e.downcast_ref() == Some(&OUT_OF_DATE) {
+            debug!("Swapchain went out of date while recreating it, retrying next frame");
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
+
+    pub fn resize(world: &World, event: &Event) {
+        if let Event::Resized(size) = event {
+            if size.width == 0 || size.height == 0 {
+                return;
+            }
+            let window = world.get::<Window>().unwrap();
+            let mut renderer = world.get_mut::<Renderer>().unwrap();
+            let extent = renderer.ctx.surface.extent;
+            if (extent.width, extent.height) != (size.width, size.height) {
+                info!("Resizing to {}x{}", size.width, size.height);
+                if let Err(e) = renderer.rebuild(&window, (size.width, size.height)) {
+                    drop((renderer, window));
+                    Self::fail(world, e);
+                }
+            }
+        }
+    }
+
+    /// Draws the objects in `range`, in one call when indirect draws are
+    /// supported.
+    fn draw_range<'a>(
+        cmd: Recorder<'a>,
+        indirect: bool,
+        draw_buffer: &Rc<Static>,
+        draws: &[u32],
+        range: Range<usize>,
+    ) -> Recorder<'a> {
+        if indirect {
+            cmd.draw_indexed_indirect(draw_buffer, range.start as u64 * 20, range.len() as u32, 20)
+        } else {
+            draws[range.start * 5..range.end * 5]
+                .chunks(5)
+                .fold(cmd, |cmd, draw| {
+                    cmd.draw_indexed(draw[0], draw[1], draw[2], draw[3] as i32, draw[4])
+                })
+        }
+    }
+
+    /// The camera set and the per-object set for a frame's buffers
+    fn descriptor_sets(
+        &self,
+        camera: &Rc<Static>,
+        [transforms, materials, lights, joints]: [&Rc<Static>; 4],
+    ) -> Result<(Rc<descriptor::Set>, Rc<descriptor::Set>)> {
+        let camera_set = self.camera_layout.alloc()?.write_buffer(0, camera).finish()?;
+        let set = self
+            .object_layout
+            .alloc()?
+            .write_buffer(0, transforms)
+            .write_buffer(1, materials)
+            .write_buffer(2, lights)
+            .write_buffer(3, joints)
+            .finish()?;
+        Ok((camera_set, set))
+    }
+
+    fn write_timestamp<'a>(
+        cmd: Recorder<'a>,
+        timestamps: Option<&Rc<TimestampPool>>,
+        stage: PipelineStageFlags,
+        index: u32,
+    ) -> Recorder<'a> {
+        match timestamps {
+            Some(timestamps) => cmd.write_timestamp(timestamps, stage, index),
+            None => cmd,
+        }
+    }
+
+    /// Gives up on rendering after an error the swapchain can't recover from,
+    /// such as running out of memory or losing the device. Handlers run
+    /// straight away, so nothing may still be borrowed.
+    fn fail(world: &World, e: impl std::fmt::Display) {
+        error!("Rendering failed: {e}");
+        world.submit(Event::Stop);
+    }
+
     pub fn draw(world: &World) {
+        let window = world.get::<Window>().unwrap();
+        let size = window.window.inner_size();
+        // Minimised, there's no surface worth drawing to
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+
         let mut renderer = world.get_mut::<Renderer>().unwrap();
+        // Left missing by going out of date while being recreated
+        if renderer.ctx.swapchain.is_none() {
+            if let Err(e) = renderer.rebuild(&window, (size.width, size.height)) {
+                drop((renderer, window));
+                Self::fail(world, e);
+            }
+            return;
+        }
         if renderer.tasks.len() > Renderer::FRAMES_IN_FLIGHT {
             let frame = renderer.tasks.pop_front().unwrap();
+            frame.fence.wait().unwrap();
+            // The previous reading is kept if this one isn't available
+            if let Some(timestamps) = &frame.timestamps {
+                match timestamps.get() {
+                    Ok(Some(durations)) => {
+                        renderer.gpu_times = GpuTimes::from_durations(&durations);
+                    }
+                    Ok(None) => debug!("GPU timestamps aren't ready, skipping"),
+                    Err(e) => warn!("Failed to read GPU timestamps: {e}"),
+                }
+            }
             drop(frame);
         }
 
@@ -406,53 +923,106 @@ impl Renderer {
         let render_finished =
             renderer.semaphores[renderer.frame_index % Renderer::FRAMES_IN_FLIGHT].clone();
         let in_flight = Fence::new(&renderer.ctx.device).unwrap();
-        let (image_index, suboptimal) = task
-            .acquire_next_image(
-                &renderer.ctx.device,
-                renderer.ctx.swapchain.as_ref().unwrap(),
-                image_available.clone(),
-            )
-            .unwrap();
-
-        let window = world.get::<Window>().unwrap();
-        let size = window.window.inner_size();
+        let acquired = task.acquire_next_image(
+            &renderer.ctx.device,
+            renderer.ctx.swapchain.as_ref().unwrap(),
+            image_available.clone(),
+        );
+        let (image_index, suboptimal) = match acquired {
+            Ok(Some(acquired)) => acquired,
+            Ok(None) => {
+                debug!("Timed out acquiring a swapchain image, skipping frame");
+                return;
+            }
+            Err(SURFACE_LOST) => {
+                warn!("Surface lost, recreating it and skipping frame");
+                if let Err(e) = renderer.recreate_surface(&window, (size.width, size.height)) {
+                    drop((renderer, window));
+                    Self::fail(world, e);
+                }
+                return;
+            }
+            Err(e) => {
+                drop((renderer, window));
+                return Self::fail(world, e);
+            }
+        };
 
         if suboptimal {
             info!("Recreating swapchain");
-            renderer
-                .recreate_swapchain((size.width, size.height))
-                .unwrap();
+            if let Err(e) = renderer.rebuild(&window, (size.width, size.height)) {
+                drop((renderer, window));
+                Self::fail(world, e);
+            }
             return;
         }
 
+        // Everything below sizes itself from the swapchain, which is what the
+        // framebuffers were made from, rather than the window which may
+        // already have moved on mid-resize
+        let extent = renderer.ctx.swapchain.as_ref().unwrap().extent;
+        let (width, height) = (extent.width.max(1), extent.height.max(1));
+        let aspect = width as f32 / height as f32;
+
         let camera = world.get::<Camera>().unwrap();
+        let projection = camera.projection(aspect);
+        debug_assert!((projection.y_axis.y / projection.x_axis.x - aspect).abs() < 1e-4);
+        let view_proj = projection * camera.view();
+        let mut camera_data = view_proj.to_cols_array().to_vec();
+        camera_data.extend_from_slice(&camera.eye().extend(1.0).to_array());
+        camera_data.extend_from_slice(&[
+            renderer.exposure,
+            if renderer.hdr { 1.0 } else { 0.0 },
+            match renderer.tonemap {
+                Tonemap::Aces => 0.0,
+                Tonemap::Reinhard => 1.0,
+            },
+            0.0,
+        ]);
+        let shadow_view_proj = ShadowMap::view_proj(camera.target);
+        camera_data.extend_from_slice(&shadow_view_proj.to_cols_array());
+        camera_data.extend_from_slice(&[renderer.shadows.bias, 0.0, 0.0, 0.0]);
         let camera_buffer = Static::new(
             &renderer.ctx,
-            bytemuck::cast_slice::<f32, u8>(&camera.get_matrix().to_cols_array()),
+            bytemuck::cast_slice::<f32, u8>(&camera_data),
             BufferUsageFlags::UNIFORM_BUFFER,
         )
         .unwrap();
-        let camera_set = renderer
-            .camera_layout
-            .alloc()
-            .unwrap()
-            .write_buffer(0, &camera_buffer)
-            .finish();
-
-        let clear_values = [clear_colour([0.0, 0.0, 0.0, 1.0]), clear_depth(1.0)];
+        let clear_values = [clear_colour(renderer.clear_colour), clear_depth(1.0)];
 
         let mut meshes = world.get_mut::<MeshCache>().unwrap();
         let (entities, render_objects) = world.query::<(EntityId, &RenderObject)>();
 
-        let transforms = entities
+        let eye = camera.eye();
+        // Anything the sun sees may cast a shadow into view, so only objects
+        // outside both are skipped
+        let frustums = [
+            Frustum::from_matrix(view_proj),
+            Frustum::from_matrix(shadow_view_proj),
+        ];
+        let mut objects = entities
             .iter()
-            .map(|id| {
-                world
+            .zip(render_objects.iter())
+            .filter_map(|(id, object)| {
+                let transform = world
                     .get_component::<Transform>(*id)
                     .map(|x| *x)
-                    .unwrap_or_default()
+                    .unwrap_or_default();
+                let matrix = transform.matrix();
+                let mesh = meshes.load(&object.mesh).unwrap();
+                let aabb = mesh.aabb().transform(matrix);
+                if !frustums.iter().any(|frustum| frustum.intersects(&aabb)) {
+                    return None;
+                }
+                let distance = eye.distance_squared((aabb.min + aabb.max) / 2.0);
+                Some((object, matrix, object.layer(mesh), distance))
             })
-            .flat_map(|transform| transform.matrix().to_cols_array())
+            .collect::<Vec<_>>();
+        let layers = Layer::sort(&mut objects, |object| (object.2, object.3));
+
+        let transforms = objects
+            .iter()
+            .flat_map(|(_, matrix, ..)| matrix.to_cols_array())
             .collect::<Vec<f32>>();
         let transform_buffer = Static::new(
             &renderer.ctx,
@@ -461,9 +1031,9 @@ impl Renderer {
         )
         .unwrap();
 
-        let materials = render_objects
+        let materials = objects
             .iter()
-            .map(|object| object.material)
+            .map(|(object, ..)| object.material)
             .collect::<Vec<Material>>();
         let material_buffer = Static::new(
             &renderer.ctx,
@@ -472,30 +1042,71 @@ impl Renderer {
         )
         .unwrap();
 
-        let set = renderer
-            .object_layout
-            .alloc()
-            .unwrap()
-            .write_buffer(0, &transform_buffer)
-            .write_buffer(1, &material_buffer)
-            .finish();
+        let lights = Light::gather(world);
+        let light_buffer = Static::new(
+            &renderer.ctx,
+            bytemuck::cast_slice::<GpuLight, u8>(&lights),
+            BufferUsageFlags::STORAGE_BUFFER,
+        )
+        .unwrap();
 
-        let (vertices, indices) = render_objects.iter().fold(
-            (Vec::new(), Vec::new()),
-            |(mut vertices, mut indices), object| {
+        // Every skinned object plays its clip from when the clock started.
+        // Joint 0 is the identity that unskinned vertices are bound to.
+        let time = world.get::<Clock>().unwrap().elapsed().as_secs_f32();
+        let mut stats = SceneStats::default();
+        let (vertices, indices, joints) = objects.iter().fold(
+            (Vec::new(), Vec::new(), vec![Mat4::IDENTITY]),
+            |(mut vertices, mut indices, mut joints), (object, ..)| {
                 let mesh = meshes.load(&object.mesh).unwrap();
-                vertices.extend_from_slice(&mesh.vertices);
+                match &mesh.skeleton {
+                    Some(skeleton) => {
+                        let base = joints.len() as u32;
+                        vertices.extend(mesh.vertices.iter().map(|vertex| Vertex {
+                            joints: vertex.joints.map(|joint| joint + base),
+                            ..*vertex
+                        }));
+                        match skeleton.joint_matrices(time) {
+                            Ok(matrices) => joints.extend(matrices),
+                            Err(e) => {
+                                warn!("Failed to pose {}, using its bind pose: {e}", object.mesh.0);
+                                joints.extend(vec![Mat4::IDENTITY; skeleton.joints()]);
+                            }
+                        }
+                    }
+                    None => vertices.extend_from_slice(&mesh.vertices),
+                }
                 indices.extend_from_slice(&mesh.indices);
-                (vertices, indices)
+                stats.add(mesh);
+                (vertices, indices, joints)
             },
         );
+        renderer.stats = stats;
+
+        let joint_buffer = Static::new(
+            &renderer.ctx,
+            bytemuck::cast_slice::<Mat4, u8>(&joints),
+            BufferUsageFlags::STORAGE_BUFFER,
+        )
+        .unwrap();
+
+        let sets = renderer.descriptor_sets(
+            &camera_buffer,
+            [&transform_buffer, &material_buffer, &light_buffer, &joint_buffer],
+        );
+        let (camera_set, set) = match sets {
+            Ok(sets) => sets,
+            Err(e) => {
+                drop((renderer, window));
+                return Self::fail(world, e);
+            }
+        };
 
         let mut index_offset = 0;
         let mut vertex_offset = 0;
 
-        let draws = render_objects.iter().flat_map(|object| {
+        let draws = objects.iter().enumerate().flat_map(|(i, (object, ..))| {
             let mesh = meshes.load(&object.mesh).unwrap();
-            let draw = [mesh.indices.len() as u32, 1, index_offset, vertex_offset, 0];
+            let draw = [mesh.indices.len() as u32, 1, index_offset, vertex_offset, i as u32];
             index_offset += mesh.indices.len() as u32;
             vertex_offset += mesh.vertices.len() as u32;
             draw
@@ -523,7 +1134,7 @@ impl Renderer {
                     .prepare(
                         &renderer.ctx,
                         &scene,
-                        Vec2::new(size.width as f32, size.height as f32),
+                        Vec2::new(width as f32, height as f32),
                     )
                     .unwrap(),
             )
@@ -531,32 +1142,111 @@ impl Renderer {
             None
         };
 
+        // Reused every `FRAMES_IN_FLIGHT + 1` frames, by which point the
+        // frame that last used it has been waited on
+        let timestamps = renderer
+            .timestamps
+            .get(renderer.frame_index % (Renderer::FRAMES_IN_FLIGHT + 1))
+            .cloned();
+
         let cmd = renderer
             .ctx
             .command_pool
             .alloc()
             .unwrap()
             .begin()
-            .unwrap()
+            .unwrap();
+        let cmd = match &timestamps {
+            Some(timestamps) => cmd.reset_timestamps(timestamps),
+            None => cmd,
+        };
+        let cmd =
+            Self::write_timestamp(cmd, timestamps.as_ref(), PipelineStageFlags::TOP_OF_PIPE, 0);
+        let device = &renderer.ctx.device;
+        let indirect = device.enabled(Feature::MultiDrawIndirect)
+            && device.enabled(Feature::DrawIndirectFirstInstance);
+
+        // Only opaque objects cast shadows
+        let shadows = &renderer.shadows;
+        let cmd = cmd
+            .begin_render_pass(&shadows.render_pass, &shadows.framebuffer, &[clear_depth(1.0)])
+            .bind_graphics_pipeline(&shadows.pipeline)
+            .set_viewport(SHADOW_SIZE, SHADOW_SIZE)
+            .set_scissor(SHADOW_SIZE, SHADOW_SIZE)
+            .bind_descriptor_set(&camera_set, 0)
+            .bind_descriptor_set(&set, 1)
+            .bind_vertex_buffer(&vertex_buffer, 0)
+            .bind_index_buffer(&index_buffer);
+        let cmd = layers
+            .iter()
+            .filter(|(layer, _)| *layer == Layer::Opaque)
+            .fold(cmd, |cmd, (_, range)| {
+                Self::draw_range(cmd, indirect, &draw_buffer, &draws, range.clone())
+            })
+            .end_render_pass();
+        let cmd = Self::write_timestamp(
+            cmd,
+            timestamps.as_ref(),
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            1,
+        );
+
+        let cmd = cmd
             .begin_render_pass(
                 &renderer.render_pass,
                 renderer.framebuffers.get(image_index as usize).unwrap(),
                 &clear_values,
             )
             .bind_graphics_pipeline(&renderer.pipeline)
-            .set_viewport(size.width, size.height)
-            .set_scissor(size.width, size.height)
+            .set_viewport(width, height)
+            .set_scissor(width, height)
             .bind_descriptor_set(&camera_set, 0)
             .bind_descriptor_set(&set, 1)
+            .bind_descriptor_set(&shadows.set, 2)
             .bind_vertex_buffer(&vertex_buffer, 0)
-            .bind_index_buffer(&index_buffer).draw_indexed_indirect(&draw_buffer, 0, draws.len() as u32 / 5, 20);
+            .bind_index_buffer(&index_buffer);
+        let wireframe = renderer.wireframe.as_ref().filter(|_| renderer.show_wireframe);
+        let cmd = layers.into_iter().fold(cmd, |cmd, (layer, range)| {
+            let cmd = match (layer, wireframe) {
+                (_, Some(wireframe)) => cmd.bind_graphics_pipeline(wireframe),
+                (Layer::Opaque, None) => cmd.bind_graphics_pipeline(&renderer.pipeline),
+                (Layer::Transparent, None) => cmd.bind_graphics_pipeline(&renderer.transparent),
+            };
+            Self::draw_range(cmd, indirect, &draw_buffer, &draws, range)
+        });
 
-        let cmd = match frame {
-            Some(frame) => renderer.ui.draw(frame, cmd),
+        let post = &renderer.post;
+        let cmd = cmd
+            .end_render_pass()
+            .begin_render_pass(
+                &post.render_pass,
+                renderer.post_framebuffers.get(image_index as usize).unwrap(),
+                &[],
+            )
+            .bind_graphics_pipeline(&post.pipeline)
+            .set_viewport(width, height)
+            .set_scissor(width, height)
+            .bind_descriptor_set(&camera_set, 0)
+            .bind_descriptor_set(&renderer.hdr_sets[image_index as usize], 1)
+            .draw(3, 1, 0, 0);
+
+        let cmd = match frame.map(|frame| renderer.ui.draw(frame, cmd)) {
+            Some(Ok(cmd)) => cmd,
+            Some(Err(e)) => {
+                drop((renderer, window));
+                return Self::fail(world, e);
+            }
             None => cmd.next_subpass(),
         };
 
-        let cmd = cmd.end_render_pass().end().unwrap();
+        let cmd = cmd.end_render_pass();
+        let cmd = Self::write_timestamp(
+            cmd,
+            timestamps.as_ref(),
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            2,
+        );
+        let cmd = cmd.end().unwrap();
 
         task.submit(SubmitInfo {
             device: &renderer.ctx.device,
@@ -568,28 +1258,39 @@ impl Renderer {
         })
         .unwrap();
 
-        let suboptimal = task
-            .present(
-                &renderer.ctx.device,
-                renderer.ctx.swapchain.as_ref().unwrap(),
-                image_index,
-                &[render_finished],
-            )
-            .unwrap();
-
-        if suboptimal {
-            info!("Recreating swapchain");
-            renderer
-                .recreate_swapchain((size.width, size.height))
-                .unwrap();
-        }
+        let presented = task.present(
+            &renderer.ctx.device,
+            renderer.ctx.swapchain.as_ref().unwrap(),
+            image_index,
+            &[render_finished],
+        );
+        let recreated = match presented {
+            Ok(true) => {
+                info!("Recreating swapchain");
+                renderer.rebuild(&window, (size.width, size.height))
+            }
+            Ok(false) => Ok(()),
+            Err(SURFACE_LOST) => {
+                warn!("Surface lost, recreating it");
+                renderer.recreate_surface(&window, (size.width, size.height))
+            }
+            Err(e) => Err(e.into()),
+        };
 
         renderer.tasks.push_back(Frame {
             task,
             fence: in_flight,
+            timestamps,
         });
 
         renderer.frame_index += 1;
+
+        // The submission is already queued, so the frame is kept above until
+        // its fence is waited on
+        if let Err(e) = recreated {
+            drop((renderer, window));
+            Self::fail(world, e);
+        }
     }
 }
 
@@ -598,3 +1299,149 @@ impl Drop for Renderer {
         unsafe { self.ctx.device.device_wait_idle().unwrap() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::assets::Aabb;
+
+    use super::*;
+
+    fn mesh(vertices: usize, indices: usize) -> Mesh {
+        Mesh {
+            vertices: vec![Vertex::default(); vertices],
+            indices: vec![0; indices],
+            num_indices: indices as u32,
+            aabb: Aabb::from_points([Vec3::ZERO]),
+            transparent: false,
+            skeleton: None,
+        }
+    }
+
+    #[test]
+    pub fn test_layer_ranges() {
+        use Layer::*;
+        assert!(Layer::ranges(&[]).is_empty());
+        assert_eq!(
+            Layer::ranges(&[Opaque, Opaque, Transparent]),
+            vec![(Opaque, 0..2), (Transparent, 2..3)]
+        );
+        assert_eq!(Layer::ranges(&[Transparent]), vec![(Transparent, 0..1)]);
+    }
+
+    #[test]
+    pub fn test_pick_samples() {
+        let supported =
+            SampleCountFlags::TYPE_1 | SampleCountFlags::TYPE_2 | SampleCountFlags::TYPE_4;
+        assert_eq!(pick_samples(supported, 4), SampleCountFlags::TYPE_4);
+        assert_eq!(pick_samples(supported, 2), SampleCountFlags::TYPE_2);
+        assert_eq!(pick_samples(supported, 8), SampleCountFlags::TYPE_1);
+        assert_eq!(pick_samples(supported, 3), SampleCountFlags::TYPE_1);
+        assert_eq!(pick_samples(supported, 0), SampleCountFlags::TYPE_1);
+    }
+
+    #[test]
+    pub fn test_parse_colour() {
+        assert_eq!(parse_colour("0.1, 0.2, 0.3").unwrap(), [0.1, 0.2, 0.3, 1.0]);
+        assert_eq!(parse_colour("0,0,1,0.5").unwrap(), [0.0, 0.0, 1.0, 0.5]);
+        assert!(parse_colour("0,0").is_err());
+        assert!(parse_colour("0,0,1,1,1").is_err());
+        assert!(parse_colour("blue").is_err());
+    }
+
+    #[test]
+    pub fn test_scene_colour() {
+        // Falling back to 1x leaves nothing to resolve
+        let samples = pick_samples(SampleCountFlags::TYPE_1, 4);
+        let (colour, resolve) = scene_colour(samples);
+        assert!(resolve.is_none());
+        assert_eq!(colour.samples, SampleCountFlags::TYPE_1);
+        assert_eq!(colour.final_layout, ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        assert_eq!(colour.store_op, AttachmentStoreOp::STORE);
+
+        let (colour, resolve) = scene_colour(SampleCountFlags::TYPE_4);
+        let resolve = resolve.unwrap();
+        assert_eq!(colour.samples, SampleCountFlags::TYPE_4);
+        assert_eq!(colour.store_op, AttachmentStoreOp::DONT_CARE);
+        assert_eq!(resolve.samples, SampleCountFlags::TYPE_1);
+        assert_eq!(resolve.final_layout, ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    }
+
+    #[test]
+    pub fn test_tonemap() {
+        assert_eq!("aces".parse::<Tonemap>().unwrap(), Tonemap::Aces);
+        assert_eq!("reinhard".parse::<Tonemap>().unwrap(), Tonemap::Reinhard);
+        assert!("filmic".parse::<Tonemap>().is_err());
+    }
+
+    #[test]
+    pub fn test_wireframe_supported() {
+        assert!(wireframe_supported(true, true));
+        assert!(!wireframe_supported(false, true));
+        assert!(!wireframe_supported(true, false));
+        assert!(!wireframe_supported(false, false));
+    }
+
+    #[test]
+    pub fn test_transparent_layer() {
+        let mut object = RenderObject {
+            mesh: MeshId(String::from("cube.glb")),
+            material: Material::new(Vec4::ONE),
+            layer: Layer::Opaque,
+        };
+        let mut mesh = mesh(3, 3);
+        assert_eq!(object.layer(&mesh), Layer::Opaque);
+        object.material = Material::new(Vec4::new(1.0, 1.0, 1.0, 0.5));
+        assert_eq!(object.layer(&mesh), Layer::Transparent);
+        object.material = Material::new(Vec4::ONE);
+        mesh.transparent = true;
+        assert_eq!(object.layer(&mesh), Layer::Transparent);
+    }
+
+    #[test]
+    pub fn test_translucent_blended() {
+        let mesh = mesh(3, 3);
+        let solid = RenderObject {
+            mesh: MeshId(String::from("cube.glb")),
+            material: Material::new(Vec4::ONE),
+            layer: Layer::Opaque,
+        };
+        let glass = RenderObject {
+            material: Material::new(Vec4::new(1.0, 1.0, 1.0, 0.5)),
+            ..solid.clone()
+        };
+        let mut objects = [(&glass, 2.0), (&solid, 1.0), (&glass, 4.0)]
+            .map(|(object, distance)| (object, object.layer(&mesh), distance));
+        let layers = Layer::sort(&mut objects, |object| (object.1, object.2));
+        assert_eq!(layers, vec![(Layer::Opaque, 0..1), (Layer::Transparent, 1..3)]);
+        // Drawn with the blending pipeline, furthest first, keeping the alpha
+        // the fragment shader writes out
+        assert_eq!(objects[1].2, 4.0);
+        assert!(objects[1..]
+            .iter()
+            .all(|(object, ..)| object.material.colour.w == 0.5));
+    }
+
+    #[test]
+    pub fn test_gpu_times() {
+        let (shadows, scene) = (Duration::from_micros(300), Duration::from_micros(1200));
+        let times = GpuTimes::from_durations(&[shadows, scene]).unwrap();
+        assert_eq!(times, GpuTimes { shadows, scene });
+        assert_eq!(times.total(), Duration::from_micros(1500));
+        assert!(GpuTimes::from_durations(&[shadows]).is_none());
+    }
+
+    #[test]
+    pub fn test_scene_stats() {
+        let mut stats = SceneStats::default();
+        stats.add(&mesh(3, 3));
+        stats.add(&mesh(8, 36));
+        assert_eq!(
+            stats,
+            SceneStats {
+                meshes: 2,
+                vertices: 11,
+                indices: 39,
+            }
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use glam::{Mat4, Vec3};
+use hephaestus::{
+    descriptor,
+    image::{Image, ImageInfo, ImageView, Sampler},
+    pipeline::{
+        self, AttachmentInfo, Framebuffer, ImageLayout, PipelineBindPoint, RenderPass,
+        ShaderModule, Subpass, Viewport,
+    },
+    vertex, AttachmentLoadOp, AttachmentStoreOp, Context, DescriptorType, Extent2D, Format,
+    ImageAspectFlags, ImageUsageFlags, SampleCountFlags,
+};
+
+use crate::assets::Assets;
+
+/// Width and height of the shadow map in texels
+pub const SHADOW_SIZE: u32 = 2048;
+/// Half the width of the square around the camera target that casts shadows
+const SHADOW_EXTENT: f32 = 32.0;
+/// How far along the sun direction occluders are looked for
+const SHADOW_DEPTH: f32 = 128.0;
+/// Default depth bias, enough to stop surfaces shadowing themselves
+const SHADOW_BIAS: f32 = 0.002;
+
+/// Must match `LIGHT_DIRECTION` in shader.frag.glsl
+pub fn sun_direction() -> Vec3 {
+    Vec3::ONE.normalize()
+}
+
+/// Depth from the sun's point of view, rendered before the scene so the
+/// lighting pass can tell which fragments it can't see.
+pub struct ShadowMap {
+    pub render_pass: RenderPass,
+    pub pipeline: pipeline::Graphics,
+    pub framebuffer: Framebuffer,
+    pub set: Rc<descriptor::Set>,
+    pub layout: Rc<descriptor::Layout>,
+    pub bias: f32,
+}
+
+impl ShadowMap {
+    pub fn new(
+        ctx: &Context,
+        assets: &Assets,
+        vertex_info: vertex::Info,
+        camera_layout: &descriptor::Layout,
+        object_layout: &descriptor::Layout,
+    ) -> Result<Self> {
+        let vertex = ShaderModule::new(&ctx.device, &assets.read("shaders/shadow.vert.spv")?)?;
+
+        let render_pass = {
+            let mut builder = RenderPass::builder();
+            let depth = builder.attachment(
+                Format::D32_SFLOAT,
+                AttachmentInfo {
+                    initial_layout: ImageLayout::UNDEFINED,
+                    final_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    load_op: AttachmentLoadOp::CLEAR,
+                    store_op: AttachmentStoreOp::STORE,
+                    samples: SampleCountFlags::TYPE_1,
+                },
+            );
+            builder.subpass(
+                Subpass::new(PipelineBindPoint::GRAPHICS)
+                    .depth(depth, ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+            );
+            builder.sampled_depth();
+            builder.build(&ctx.device)?
+        };
+
+        let pipeline = pipeline::Graphics::builder()
+            .vertex(&vertex)
+            .vertex_info(vertex_info)
+            .render_pass(&render_pass)
+            .subpass(0)
+            .viewport(Viewport::Dynamic)
+            .layouts(vec![camera_layout, object_layout])
+            .depth_only()
+            .build(&ctx.device)?;
+
+        let extent = Extent2D {
+            width: SHADOW_SIZE,
+            height: SHADOW_SIZE,
+        };
+        let image = Image::new(
+            ctx,
+            ImageInfo {
+                format: Format::D32_SFLOAT,
+                extent,
+                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | ImageUsageFlags::SAMPLED,
+                samples: SampleCountFlags::TYPE_1,
+            },
+        )?;
+        let view = ImageView::new(
+            &ctx.device,
+            &image,
+            Format::D32_SFLOAT,
+            ImageAspectFlags::DEPTH,
+            extent,
+        )?;
+        let framebuffer = render_pass.get_framebuffer(&ctx.device, &[&view])?;
+
+        let layout = descriptor::Layout::new(ctx, &[DescriptorType::COMBINED_IMAGE_SAMPLER], 1)?;
+        let sampler = Sampler::shadow(&ctx.device)?;
+        let set = layout
+            .alloc()?
+            .write_image(0, &view, &sampler, ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .finish()?;
+
+        Ok(Self {
+            render_pass,
+            pipeline,
+            framebuffer,
+            set,
+            layout,
+            bias: SHADOW_BIAS,
+        })
+    }
+
+    /// An orthographic view down the sun direction, centred on `target` so
+    /// shadows follow the camera around.
+    pub fn view_proj(target: Vec3) -> Mat4 {
+        let eye = target + sun_direction() * SHADOW_DEPTH / 2.0;
+        let view = Mat4::look_at_rh(eye, target, Vec3::Y);
+        let projection = Mat4::orthographic_rh(
+            -SHADOW_EXTENT,
+            SHADOW_EXTENT,
+            -SHADOW_EXTENT,
+            SHADOW_EXTENT,
+            0.0,
+            SHADOW_DEPTH,
+        );
+        projection * view
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_view_proj() {
+        let target = Vec3::new(10.0, 0.0, -5.0);
+        let view_proj = ShadowMap::view_proj(target);
+        let centre = view_proj.project_point3(target);
+        assert!(centre.truncate().length() < 1e-4);
+        assert!((centre.z - 0.5).abs() < 1e-4);
+
+        // Closer to the sun means shallower, so it shadows what's behind it
+        let above = view_proj.project_point3(target + sun_direction());
+        assert!(above.z < centre.z);
+        assert!(above.truncate().length() < 1e-4);
+
+        let across = Vec3::Y.cross(sun_direction()).normalize();
+        let edge = view_proj.project_point3(target + across * SHADOW_EXTENT);
+        assert!((edge.truncate().length() - 1.0).abs() < 1e-4);
+    }
+}
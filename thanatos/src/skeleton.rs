@@ -0,0 +1,140 @@
+use anyhow::{bail, Result};
+use glam::{Mat4, Quat, Vec3};
+use gltf::{Glb, Property};
+
+use crate::transform::Transform;
+
+/// The joints of a skinned mesh, posed by the first animation in its glTF
+/// playing on a loop. The glTF is kept to sample the animation from.
+pub struct Skeleton {
+    glb: Glb,
+    /// Each node's parent, `None` for roots
+    parents: Vec<Option<usize>>,
+    /// Each node's transform when not animated
+    rest: Vec<Transform>,
+    /// The node each joint is, in the order vertices refer to them
+    joints: Vec<usize>,
+    inverse_bind: Vec<Mat4>,
+    duration: f32,
+}
+
+impl Skeleton {
+    /// `None` if the glTF doesn't have a skin
+    pub fn from_glb(glb: Glb) -> Result<Option<Self>> {
+        let Some(skin) = glb.gltf.skins.first() else {
+            return Ok(None);
+        };
+        let nodes = &glb.gltf.nodes;
+
+        let mut parents = vec![None; nodes.len()];
+        for (parent, node) in nodes.iter().enumerate() {
+            for child in &node.children {
+                match parents.get_mut(*child) {
+                    Some(slot) => *slot = Some(parent),
+                    None => bail!("Node {parent} has missing child {child}"),
+                }
+            }
+        }
+
+        // Posing walks up the parents, which would never end in a cycle
+        let cyclic = (0..nodes.len()).any(|node| {
+            std::iter::successors(Some(node), |node| parents[*node])
+                .nth(nodes.len())
+                .is_some()
+        });
+        if cyclic {
+            bail!("Node hierarchy has a cycle");
+        }
+
+        let rest = nodes
+            .iter()
+            .map(|node| match node.matrix {
+                Some(matrix) => {
+                    let (scale, rotation, translation) =
+                        Mat4::from_cols_array(&matrix).to_scale_rotation_translation();
+                    Transform::new(translation, rotation, scale)
+                }
+                None => Transform::new(
+                    node.translation.map(Vec3::from).unwrap_or(Vec3::ZERO),
+                    node.rotation.map(Quat::from_array).unwrap_or(Quat::IDENTITY),
+                    node.scale.map(Vec3::from).unwrap_or(Vec3::ONE),
+                ),
+            })
+            .collect();
+
+        if let Some(joint) = skin.joints.iter().find(|joint| **joint >= nodes.len()) {
+            bail!("Joint {joint} is past {} nodes", nodes.len());
+        }
+        let inverse_bind = skin
+            .get_inverse_bind_matrices(&glb)?
+            .iter()
+            .map(Mat4::from_cols_array)
+            .collect::<Vec<_>>();
+        if inverse_bind.len() < skin.joints.len() {
+            bail!("{} inverse bind matrices for {} joints", inverse_bind.len(), skin.joints.len());
+        }
+        let joints = skin.joints.clone();
+
+        let duration = match glb.gltf.animations.first() {
+            Some(animation) => animation.duration(&glb)?,
+            None => 0.0,
+        };
+
+        let skeleton = Self {
+            glb,
+            parents,
+            rest,
+            joints,
+            inverse_bind,
+            duration,
+        };
+        // Sampling only fails on bad data, which is the same at any time
+        skeleton.joint_matrices(0.0)?;
+        Ok(Some(skeleton))
+    }
+
+    pub fn joints(&self) -> usize {
+        self.joints.len()
+    }
+
+    /// Model space joint matrices `time` seconds into the clip, which loops
+    pub fn joint_matrices(&self, time: f32) -> Result<Vec<Mat4>> {
+        let mut locals = self.rest.clone();
+        if let Some(animation) = self.glb.gltf.animations.first() {
+            let time = if self.duration > 0.0 { time % self.duration } else { 0.0 };
+            for (node, property) in animation.sample(&self.glb, time)? {
+                let Some(local) = locals.get_mut(node) else {
+                    bail!("Animation targets missing node {node}");
+                };
+                match property {
+                    Property::Translation(translation) => local.translation = translation.into(),
+                    Property::Rotation(rotation) => local.rotation = Quat::from_array(rotation),
+                    Property::Scale(scale) => local.scale = scale.into(),
+                }
+            }
+        }
+
+        let mut globals: Vec<Option<Mat4>> = vec![None; locals.len()];
+        Ok(self
+            .joints
+            .iter()
+            .zip(&self.inverse_bind)
+            .map(|(joint, inverse_bind)| {
+                self.global(*joint, &locals, &mut globals) * *inverse_bind
+            })
+            .collect())
+    }
+
+    fn global(&self, node: usize, locals: &[Transform], globals: &mut [Option<Mat4>]) -> Mat4 {
+        if let Some(global) = globals[node] {
+            return global;
+        }
+        let local = locals[node].matrix();
+        let global = match self.parents[node] {
+            Some(parent) => self.global(parent, locals, globals) * local,
+            None => local,
+        };
+        globals[node] = Some(global);
+        global
+    }
+}
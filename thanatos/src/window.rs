@@ -6,9 +6,9 @@ use std::{
 
 use glam::Vec2;
 use winit::{
-    event::{ElementState, MouseButton, WindowEvent},
+    event::{DeviceEvent, ElementState, MouseButton, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    keyboard::{Key, SmolStr},
+    keyboard::{Key, NamedKey, SmolStr},
     platform::pump_events::EventLoopExtPumpEvents,
     window::WindowBuilder,
 };
@@ -18,7 +18,9 @@ use crate::{event::Event, World};
 #[derive(Clone, Default)]
 pub struct Mouse {
     pub position: Vec2,
-    pub delta: Vec2,
+    /// Raw device movement this frame, unaffected by cursor acceleration or
+    /// the cursor hitting the edge of the window
+    pub motion: Vec2,
     down: HashSet<MouseButton>,
 }
 
@@ -29,13 +31,16 @@ impl Mouse {
 
     pub fn tick(world: &World) {
         let mut mouse = world.get_mut::<Mouse>().unwrap();
-        mouse.delta = Vec2::ZERO;
+        mouse.motion = Vec2::ZERO;
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Keybind {
     Interact,
+    ToggleCamera,
+    CameraPreset,
+    Chat,
 }
 
 #[derive(Clone)]
@@ -43,6 +48,8 @@ pub struct Keyboard {
     previous: HashSet<Key>,
     down: HashSet<Key>,
     pub keybinds: HashMap<Keybind, Key>,
+    /// Set while something is taking text input, hiding keys from bindings
+    pub captured: bool,
 }
 
 impl Default for Keyboard {
@@ -51,8 +58,14 @@ impl Default for Keyboard {
             previous: HashSet::new(),
             down: HashSet::new(),
             keybinds: HashMap::new(),
+            captured: false,
         };
-        keyboard.keybinds = HashMap::from([(Keybind::Interact, "f".into_key(&keyboard))]);
+        keyboard.keybinds = HashMap::from([
+            (Keybind::Interact, "f".into_key(&keyboard)),
+            (Keybind::ToggleCamera, "v".into_key(&keyboard)),
+            (Keybind::CameraPreset, "p".into_key(&keyboard)),
+            (Keybind::Chat, Key::Named(NamedKey::Enter)),
+        ]);
         keyboard
     }
 }
@@ -60,16 +73,16 @@ impl Default for Keyboard {
 impl Keyboard {
     pub fn pressed<T: IntoKey>(&self, key: T) -> bool {
         let key = key.into_key(self);
-        self.down.contains(&key) && !self.previous.contains(&key)
+        !self.captured && self.down.contains(&key) && !self.previous.contains(&key)
     }
 
     pub fn released<T: IntoKey>(&self, key: T) -> bool {
         let key = key.into_key(self);
-        !self.down.contains(&key) && self.previous.contains(&key)
+        !self.captured && !self.down.contains(&key) && self.previous.contains(&key)
     }
 
     pub fn is_down<T: IntoKey>(&self, key: T) -> bool {
-        self.down.contains(&key.into_key(self))
+        !self.captured && self.down.contains(&key.into_key(self))
     }
 }
 
@@ -120,6 +133,7 @@ impl Window {
             let mut keyboard = world.get_mut::<Keyboard>().unwrap();
             keyboard.previous = keyboard.down.clone();
             let mut mouse = world.get_mut::<Mouse>().unwrap();
+            let mut rescaled = false;
 
             window
                 .event_loop
@@ -128,6 +142,11 @@ impl Window {
                         WindowEvent::Resized(new_size) => {
                             events.push(Event::Resized(new_size));
                         }
+                        WindowEvent::ScaleFactorChanged { .. } => {
+                            // The new physical size is only applied once the
+                            // callback returns, so it is read back below
+                            rescaled = true;
+                        }
                         WindowEvent::CloseRequested => {
                             events.push(Event::Stop);
                         }
@@ -153,17 +172,24 @@ impl Window {
                         },
                         WindowEvent::CursorMoved { position, .. } => {
                             let position = Vec2::new(position.x as f32, position.y as f32);
-                            mouse.delta = position - mouse.position;
+                            let delta = position - mouse.position;
                             mouse.position = position;
-                            events.push(Event::MouseMove {
-                                position,
-                                delta: mouse.delta,
-                            })
+                            events.push(Event::MouseMove { position, delta })
                         }
                         _ => (),
                     },
+                    winit::event::Event::DeviceEvent {
+                        event: DeviceEvent::MouseMotion { delta },
+                        ..
+                    } => {
+                        mouse.motion += Vec2::new(delta.0 as f32, delta.1 as f32);
+                    }
                     _ => (),
                 });
+
+            if rescaled {
+                events.push(Event::Resized(window.window.inner_size()));
+            }
         }
 
         events.into_iter().for_each(|event| world.submit(event));